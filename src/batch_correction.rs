@@ -0,0 +1,515 @@
+/*
+Batch effect correction for cells pooled from several images, plates or sessions: ComBat's
+empirical Bayes location/scale adjustment (Johnson et al. 2007) and a Harmony-like iterative
+correction in principal component space (Korsunsky et al. 2019), plus kBET- and LISI-style scores
+of how well the batches mix.
+*/
+
+use crate::kmeans::{self, euclidean_distance, Init};
+
+use rand::Rng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+
+use std::error::Error;
+use std::str::FromStr;
+
+// Principal components Harmony corrects in and the mixing scores are measured in
+const EMBEDDING_DIMS: usize = 20;
+// Harmony's defaults: soft clustering temperature, diversity penalty and ridge penalty
+const HARMONY_SIGMA: f64 = 0.1;
+const HARMONY_THETA: f64 = 2.0;
+const HARMONY_LAMBDA: f64 = 1.0;
+const HARMONY_ITERATIONS: usize = 10;
+const HARMONY_CLUSTER_ROUNDS: usize = 10;
+const HARMONY_BLOCKS: usize = 20;
+// Neighbours of each tested cell, and cells tested, for the mixing scores
+const MIXING_NEIGHBOURS: usize = 25;
+const MIXING_SAMPLE: usize = 500;
+// Significance of kBET's per-cell chi-squared test
+const KBET_ALPHA: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatchCorrection {
+    ComBat,
+    Harmony,
+}
+
+impl FromStr for BatchCorrection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "combat" => Ok(BatchCorrection::ComBat),
+            "harmony" => Ok(BatchCorrection::Harmony),
+            _ => Err(format!("Unknown batch correction '{}', expected combat or harmony", s)),
+        }
+    }
+}
+
+// Distinct batch labels in order of first appearance, and every cell's index into them
+pub fn batch_indices(labels: &[String]) -> (Vec<String>, Vec<usize>) {
+    let mut names: Vec<String> = Vec::new();
+    let indices = labels.iter()
+        .map(|label| match names.iter().position(|n| n == label) {
+            Some(b) => b,
+            None => {
+                names.push(label.clone());
+                names.len() - 1
+            }
+        })
+        .collect();
+    (names, indices)
+}
+
+// Cells of each batch; an error unless there are two batches of at least two cells
+fn batch_members(batches: &[usize]) -> Result<Vec<Vec<usize>>, Box<dyn Error>> {
+    let count = batches.iter().max().map_or(0, |&b| b + 1);
+    let mut members = vec![Vec::new(); count];
+    for (i, &b) in batches.iter().enumerate() {
+        members[b].push(i);
+    }
+    if members.len() < 2 {
+        return Err("Batch correction needs cells from at least two batches".into());
+    }
+    if let Some(b) = members.iter().position(|m| m.len() < 2) {
+        return Err(format!("Batch {} has fewer than two cells", b).into());
+    }
+    Ok(members)
+}
+
+fn mean_variance(values: impl Iterator<Item = f64> + Clone) -> (f64, f64) {
+    let n = values.clone().count() as f64;
+    let mean = values.clone().sum::<f64>() / n;
+    let variance = values.map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    (mean, variance)
+}
+
+// ComBat without covariates. Each feature is standardised by its pooled mean and within-batch
+// standard deviation; every batch's shift and scale of the standardised features are shrunk
+// towards the batch's average over features (empirical Bayes) and removed. Features with no
+// spread are left as they are.
+pub fn combat(features: &[Vec<f64>], batches: &[usize]) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+    let members = batch_members(batches)?;
+    let (cells, num_features) = (features.len(), features.first().map_or(0, |f| f.len()));
+
+    // Pooled mean and within-batch standard deviation of every feature
+    let mut grand_mean = vec![0.0; num_features];
+    let mut pooled_std = vec![0.0; num_features];
+    for j in 0..num_features {
+        grand_mean[j] = features.iter().map(|f| f[j]).sum::<f64>() / cells as f64;
+        let mut residual = 0.0;
+        for cells_of_batch in &members {
+            let mean = cells_of_batch.iter().map(|&i| features[i][j]).sum::<f64>() / cells_of_batch.len() as f64;
+            residual += cells_of_batch.iter().map(|&i| (features[i][j] - mean).powi(2)).sum::<f64>();
+        }
+        pooled_std[j] = (residual / cells as f64).sqrt();
+    }
+    let varying: Vec<usize> = (0..num_features).filter(|&j| pooled_std[j] > 0.0).collect();
+    let standardized = |i: usize, j: usize| (features[i][j] - grand_mean[j]) / pooled_std[j];
+
+    let mut corrected = features.to_vec();
+    for cells_of_batch in &members {
+        let n = cells_of_batch.len() as f64;
+        let (gamma_hat, delta_hat): (Vec<f64>, Vec<f64>) = varying.iter()
+            .map(|&j| mean_variance(cells_of_batch.iter().map(|&i| standardized(i, j))))
+            .unzip();
+
+        // Normal prior on the shifts, inverse gamma prior on the scales, by the method of moments
+        let (gamma_bar, tau2) = mean_variance(gamma_hat.iter().copied());
+        let (m, s2) = mean_variance(delta_hat.iter().copied());
+        let (mut gamma_star, mut delta_star) = (gamma_hat.clone(), delta_hat.clone());
+        if s2 > 0.0 && m > 0.0 {
+            let (a, b) = ((2.0 * s2 + m * m) / s2, (m * s2 + m * m * m) / s2);
+            for _ in 0..1000 {
+                let mut change: f64 = 0.0;
+                for (f, &j) in varying.iter().enumerate() {
+                    let gamma = (n * tau2 * gamma_hat[f] + delta_star[f] * gamma_bar) / (n * tau2 + delta_star[f]);
+                    let sum2: f64 = cells_of_batch.iter().map(|&i| (standardized(i, j) - gamma).powi(2)).sum();
+                    let delta = (b + 0.5 * sum2) / (n / 2.0 + a - 1.0);
+                    change = change.max(((gamma - gamma_star[f]) / gamma_star[f]).abs()).max(((delta - delta_star[f]) / delta_star[f]).abs());
+                    (gamma_star[f], delta_star[f]) = (gamma, delta);
+                }
+                if change.is_nan() || change < 1e-4 {
+                    break;
+                }
+            }
+        }
+
+        for (f, &j) in varying.iter().enumerate() {
+            let scale = if delta_star[f] > 0.0 { delta_star[f].sqrt() } else { 1.0 };
+            for &i in cells_of_batch {
+                corrected[i][j] = pooled_std[j] * (standardized(i, j) - gamma_star[f]) / scale + grand_mean[j];
+            }
+        }
+    }
+    Ok(corrected)
+}
+
+// Mean and the top `dims` principal directions (unit vectors) of the rows of `features`, by
+// subspace iteration on the covariance matrix
+pub fn principal_components(features: &[Vec<f64>], dims: usize, rng: &mut StdRng) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let (cells, num_features) = (features.len(), features.first().map_or(0, |f| f.len()));
+    let mean: Vec<f64> = (0..num_features).map(|j| features.iter().map(|f| f[j]).sum::<f64>() / cells.max(1) as f64).collect();
+    let mut covariance = vec![vec![0.0; num_features]; num_features];
+    for f in features {
+        let centred: Vec<f64> = f.iter().zip(&mean).map(|(v, m)| v - m).collect();
+        for (a, row) in covariance.iter_mut().enumerate() {
+            for (b, c) in row.iter_mut().enumerate() {
+                *c += centred[a] * centred[b];
+            }
+        }
+    }
+
+    let dims = dims.min(num_features);
+    let mut basis: Vec<Vec<f64>> = (0..dims).map(|_| (0..num_features).map(|_| rng.random::<f64>() - 0.5).collect()).collect();
+    orthonormalize(&mut basis);
+    for _ in 0..100 {
+        let mut next: Vec<Vec<f64>> = basis.iter()
+            .map(|v| covariance.iter().map(|row| row.iter().zip(v).map(|(c, x)| c * x).sum()).collect())
+            .collect();
+        orthonormalize(&mut next);
+        let settled = next.iter().zip(&basis).all(|(a, b)| dot(a, b).abs() > 1.0 - 1e-10);
+        basis = next;
+        if settled {
+            break;
+        }
+    }
+    (mean, basis)
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+// Gram-Schmidt; vectors that vanish (no variance left) are zeroed rather than normalised
+fn orthonormalize(vectors: &mut [Vec<f64>]) {
+    for i in 0..vectors.len() {
+        for j in 0..i {
+            let projection = dot(&vectors[i], &vectors[j]);
+            let previous = vectors[j].clone();
+            vectors[i].iter_mut().zip(&previous).for_each(|(v, p)| *v -= projection * p);
+        }
+        let length = dot(&vectors[i], &vectors[i]).sqrt();
+        vectors[i].iter_mut().for_each(|v| *v = if length > 1e-12 { *v / length } else { 0.0 });
+    }
+}
+
+// Coordinates of every row along the principal directions
+pub fn project(features: &[Vec<f64>], mean: &[f64], components: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    features.iter()
+        .map(|f| {
+            let centred: Vec<f64> = f.iter().zip(mean).map(|(v, m)| v - m).collect();
+            components.iter().map(|c| dot(&centred, c)).collect()
+        })
+        .collect()
+}
+
+fn unit_rows(rows: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    rows.iter()
+        .map(|r| {
+            let length = dot(r, r).sqrt();
+            r.iter().map(|v| if length > 0.0 { v / length } else { 0.0 }).collect()
+        })
+        .collect()
+}
+
+// Harmony-like correction. Cells are projected onto the top principal components and softly
+// clustered (on the unit sphere) with a penalty on clusters dominated by one batch; within every
+// cluster a ridge regression on batch estimates each batch's offset, and the cells' offsets,
+// weighted by cluster membership, are removed. After a few rounds the total correction in
+// component space is mapped back onto the features.
+pub fn harmony(features: &[Vec<f64>], batches: &[usize], rng: &mut StdRng) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+    let members = batch_members(batches)?;
+    let cells = features.len();
+    let num_batches = members.len();
+    let share: Vec<f64> = members.iter().map(|m| m.len() as f64 / cells as f64).collect();
+
+    let (mean, components) = principal_components(features, EMBEDDING_DIMS.min(cells - 1), rng);
+    let embedding = project(features, &mean, &components);
+    let dims = components.len();
+    let clusters = (cells / 30).clamp(2, 100).min(cells);
+
+    let mut corrected = embedding.clone();
+    let start = kmeans::fit(&unit_rows(&embedding), clusters, Init::KMeansPlusPlus, 1, 1e-4, rng);
+    let mut responsibility = vec![vec![0.0; clusters]; cells];
+    for (i, &label) in start.labels.iter().enumerate() {
+        responsibility[i][label] = 1.0;
+    }
+
+    for _ in 0..HARMONY_ITERATIONS {
+        let unit = unit_rows(&corrected);
+        for _ in 0..HARMONY_CLUSTER_ROUNDS {
+            let sums: Vec<Vec<f64>> = (0..clusters)
+                .map(|k| (0..dims).map(|d| (0..cells).map(|i| responsibility[i][k] * unit[i][d]).sum()).collect())
+                .collect();
+            let centroids = unit_rows(&sums);
+
+            // Observed and expected cells of every batch in every cluster
+            let mut observed = vec![vec![0.0; num_batches]; clusters];
+            let mut expected = vec![vec![0.0; num_batches]; clusters];
+            for (i, r) in responsibility.iter().enumerate() {
+                tally(&mut observed, &mut expected, &share, r, batches[i], 1.0);
+            }
+
+            // Cells are reassigned a small random block at a time, each block seeing the others'
+            // current assignments; moving all cells at once makes the batch penalty oscillate
+            let mut order: Vec<usize> = (0..cells).collect();
+            order.shuffle(rng);
+            for block in order.chunks(cells.div_ceil(HARMONY_BLOCKS)) {
+                for &i in block {
+                    tally(&mut observed, &mut expected, &share, &responsibility[i], batches[i], -1.0);
+                }
+                for &i in block {
+                    let b = batches[i];
+                    let logits: Vec<f64> = (0..clusters)
+                        .map(|k| -2.0 * (1.0 - dot(&unit[i], &centroids[k])) / HARMONY_SIGMA
+                            + HARMONY_THETA * ((expected[k][b] + 1.0) / (observed[k][b] + 1.0)).ln())
+                        .collect();
+                    let max = logits.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                    let weights: Vec<f64> = logits.iter().map(|l| (l - max).exp()).collect();
+                    let total: f64 = weights.iter().sum();
+                    responsibility[i].iter_mut().zip(&weights).for_each(|(r, w)| *r = w / total);
+                }
+                for &i in block {
+                    tally(&mut observed, &mut expected, &share, &responsibility[i], batches[i], 1.0);
+                }
+            }
+        }
+
+        // Ridge regression of the uncorrected embedding on [intercept, one-hot batch] per
+        // cluster; only the batch terms are penalised, so the intercept stays free
+        let mut next = embedding.clone();
+        let memberships: Vec<Vec<f64>> = (0..clusters).map(|k| responsibility.iter().map(|r| r[k]).collect()).collect();
+        for membership in &memberships {
+            let mut system = vec![vec![0.0; num_batches + 1]; num_batches + 1];
+            let mut rhs = vec![vec![0.0; dims]; num_batches + 1];
+            for (i, z) in embedding.iter().enumerate() {
+                let (r, b) = (membership[i], batches[i] + 1);
+                system[0][0] += r;
+                system[0][b] += r;
+                system[b][0] += r;
+                system[b][b] += r;
+                for (d, v) in z.iter().enumerate() {
+                    rhs[0][d] += r * v;
+                    rhs[b][d] += r * v;
+                }
+            }
+            for (b, row) in system.iter_mut().enumerate().skip(1) {
+                row[b] += HARMONY_LAMBDA;
+            }
+            let Some(coefficients) = solve(system, rhs) else { continue };
+            for (i, z) in next.iter_mut().enumerate() {
+                let offset = &coefficients[batches[i] + 1];
+                z.iter_mut().zip(offset).for_each(|(v, o)| *v -= membership[i] * o);
+            }
+        }
+
+        let change = next.iter().zip(&corrected).flat_map(|(a, b)| a.iter().zip(b).map(|(x, y)| (x - y).abs())).fold(0.0, f64::max);
+        corrected = next;
+        if change < 1e-6 {
+            break;
+        }
+    }
+
+    Ok(features.iter()
+        .zip(corrected.iter().zip(&embedding))
+        .map(|(f, (z, original))| {
+            let mut row = f.clone();
+            for (c, component) in components.iter().enumerate() {
+                let shift = z[c] - original[c];
+                row.iter_mut().zip(component).for_each(|(v, p)| *v += shift * p);
+            }
+            row
+        })
+        .collect())
+}
+
+// Adds (sign 1) or removes (sign -1) a cell of batch `b` with cluster memberships `r` to the
+// observed and expected batch counts of every cluster
+fn tally(observed: &mut [Vec<f64>], expected: &mut [Vec<f64>], share: &[f64], r: &[f64], b: usize, sign: f64) {
+    for (k, &r) in r.iter().enumerate() {
+        observed[k][b] += sign * r;
+        expected[k].iter_mut().zip(share).for_each(|(e, p)| *e += sign * r * p);
+    }
+}
+
+// Solves A X = B by Gaussian elimination with partial pivoting; None if A is singular
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<Vec<f64>>) -> Option<Vec<Vec<f64>>> {
+    let n = a.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&x, &y| a[x][col].abs().total_cmp(&a[y][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in 0..n {
+            if row == col {
+                continue;
+            }
+            let factor = a[row][col] / a[col][col];
+            if factor == 0.0 {
+                continue;
+            }
+            let (pivot_a, pivot_b) = (a[col].clone(), b[col].clone());
+            a[row].iter_mut().zip(&pivot_a).for_each(|(v, p)| *v -= factor * p);
+            b[row].iter_mut().zip(&pivot_b).for_each(|(v, p)| *v -= factor * p);
+        }
+    }
+    for (row, coefficients) in b.iter_mut().enumerate() {
+        let diagonal = a[row][row];
+        coefficients.iter_mut().for_each(|v| *v /= diagonal);
+    }
+    Some(b)
+}
+
+// How well the batches mix among each cell's nearest neighbours
+#[derive(Debug, Clone, Copy)]
+pub struct MixingScore {
+    pub kbet_rejection_rate: f64,  // Fraction of cells whose neighbours' batches differ significantly from the overall mix; 0 is well mixed
+    pub mean_lisi: f64,            // Mean inverse Simpson index of the neighbours' batches, from 1 (one batch) to the number of batches
+}
+
+// kBET- and LISI-style scores on a sample of cells. Each sampled cell's nearest neighbours in
+// `embedding` are compared with the overall batch proportions by a chi-squared test (kBET), and
+// their inverse Simpson index is averaged with equal neighbour weights (LISI without its
+// perplexity-based kernel).
+pub fn mixing_score(embedding: &[Vec<f64>], batches: &[usize], rng: &mut StdRng) -> MixingScore {
+    let cells = embedding.len();
+    let num_batches = batches.iter().max().map_or(0, |&b| b + 1);
+    let neighbours = MIXING_NEIGHBOURS.min(cells.saturating_sub(1));
+    if neighbours == 0 || num_batches < 2 {
+        return MixingScore { kbet_rejection_rate: 0.0, mean_lisi: 1.0 };
+    }
+    let mut share = vec![0.0; num_batches];
+    for &b in batches {
+        share[b] += 1.0 / cells as f64;
+    }
+
+    let mut sample: Vec<usize> = (0..cells).collect();
+    sample.shuffle(rng);
+    sample.truncate(MIXING_SAMPLE);
+
+    let (mut rejected, mut lisi) = (0.0, 0.0);
+    for &i in &sample {
+        let mut distances: Vec<(f64, usize)> = (0..cells)
+            .filter(|&j| j != i)
+            .map(|j| (euclidean_distance(&embedding[i], &embedding[j]), j))
+            .collect();
+        distances.select_nth_unstable_by(neighbours - 1, |a, b| a.0.total_cmp(&b.0));
+        let mut counts = vec![0.0; num_batches];
+        for &(_, j) in &distances[..neighbours] {
+            counts[batches[j]] += 1.0;
+        }
+
+        let statistic: f64 = counts.iter().zip(&share)
+            .filter(|&(_, &p)| p > 0.0)
+            .map(|(&observed, &p)| (observed - neighbours as f64 * p).powi(2) / (neighbours as f64 * p))
+            .sum();
+        let p_value = 1.0 - regularized_gamma((num_batches - 1) as f64 / 2.0, statistic / 2.0);
+        if p_value < KBET_ALPHA {
+            rejected += 1.0;
+        }
+        lisi += 1.0 / counts.iter().map(|c| (c / neighbours as f64).powi(2)).sum::<f64>();
+    }
+    MixingScore { kbet_rejection_rate: rejected / sample.len() as f64, mean_lisi: lisi / sample.len() as f64 }
+}
+
+// Lower regularised incomplete gamma function P(a, x), the chi-squared CDF with 2a degrees of
+// freedom at 2x: a series below a + 1 and a continued fraction above (Numerical Recipes)
+fn regularized_gamma(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let log_prefactor = a * x.ln() - x - ln_gamma(a);
+    if x < a + 1.0 {
+        let (mut term, mut sum, mut n) = (1.0 / a, 1.0 / a, a);
+        for _ in 0..500 {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+            if term.abs() < sum.abs() * 1e-15 {
+                break;
+            }
+        }
+        (sum.ln() + log_prefactor).exp().min(1.0)
+    } else {
+        // Lentz's method for the continued fraction of Q(a, x)
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..500 {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < tiny { d = tiny; }
+            c = b + an / c;
+            if c.abs() < tiny { c = tiny; }
+            d = 1.0 / d;
+            let step = d * c;
+            h *= step;
+            if (step - 1.0).abs() < 1e-15 {
+                break;
+            }
+        }
+        (1.0 - (h.ln() + log_prefactor).exp()).max(0.0)
+    }
+}
+
+// ln Γ(x) for x > 0 by the Lanczos approximation
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [76.18009172947146, -86.50532032941677, 24.01409824083091, -1.231739572450155, 0.1208650973866179e-2, -0.5395239384953e-5];
+    let tmp = x + 5.5 - (x + 0.5) * (x + 5.5).ln();
+    let series = COEFFICIENTS.iter().enumerate().fold(1.000000000190015, |s, (j, c)| s + c / (x + 1.0 + j as f64));
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+// Mixing scores before and, if given, after correction, both measured on the principal
+// components of the uncorrected features so they are comparable
+pub fn mixing_scores(before: &[Vec<f64>], after: Option<&[Vec<f64>]>, batches: &[usize], rng: &mut StdRng) -> (MixingScore, Option<MixingScore>) {
+    let (mean, components) = principal_components(before, EMBEDDING_DIMS, rng);
+    let before = mixing_score(&project(before, &mean, &components), batches, rng);
+    let after = after.map(|after| mixing_score(&project(after, &mean, &components), batches, rng));
+    (before, after)
+}
+
+// Writes the mixing scores before and, if corrected, after batch correction
+pub fn write_diagnostics(path: &str, batches: &[String], cells: usize, before: &MixingScore, after: Option<&MixingScore>) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["stage", "cells", "batches", "kbet_rejection_rate", "mean_lisi"])?;
+    let rows = std::iter::once(("before", before)).chain(after.map(|a| ("after", a)));
+    for (stage, score) in rows {
+        writer.write_record([stage, &cells.to_string(), &batches.len().to_string(), &score.kbet_rejection_rate.to_string(), &score.mean_lisi.to_string()])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn combat_removes_an_additive_batch_shift() {
+        let mut noise = StdRng::seed_from_u64(1);
+        let batches: Vec<usize> = (0..400).map(|i| i % 2).collect();
+        let features: Vec<Vec<f64>> = batches.iter()
+            .map(|&b| (0..4).map(|j| j as f64 + 3.0 * b as f64 + noise.random_range(-1.0..1.0)).collect())
+            .collect();
+
+        let corrected = combat(&features, &batches).unwrap();
+        for j in 0..4 {
+            let mean = |batch: usize| {
+                let values: Vec<f64> = (0..corrected.len()).filter(|&i| batches[i] == batch).map(|i| corrected[i][j]).collect();
+                values.iter().sum::<f64>() / values.len() as f64
+            };
+            // The shift of 3 is gone up to the noise left by shrinking towards the average shift
+            assert!((mean(0) - mean(1)).abs() < 0.2, "feature {} still differs by {}", j, mean(1) - mean(0));
+        }
+    }
+}
@@ -0,0 +1,208 @@
+/*
+Physical pixel calibration. Features are measured in pixels; a Calibration converts them to
+microns (or leaves them in pixels) and names the unit in the column suffix.
+*/
+
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::str::FromStr;
+
+use tiff::decoder::ifd::Value;
+use tiff::tags::Tag;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Units {
+    Pixels,
+    Microns,
+}
+
+impl FromStr for Units {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "px" | "pixels" => Ok(Units::Pixels),
+            "um" | "µm" | "microns" => Ok(Units::Microns),
+            _ => Err(format!("Unknown units '{}', expected pixels or microns", s)),
+        }
+    }
+}
+
+// What a feature measures, which decides how it is converted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantity {
+    Dimensionless,
+    X,       // A position or extent along x
+    Y,       // A position or extent along y
+    Length,  // A length in an arbitrary direction
+    Area,
+}
+
+// µm per pixel along x and y, and µm between z slices when known
+pub type PixelSize = (f64, f64, Option<f64>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub x_um_per_pixel: f64,
+    pub y_um_per_pixel: f64,
+    pub z_um_per_slice: Option<f64>,
+    pub units: Units,  // Units features are reported in
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration { x_um_per_pixel: 1.0, y_um_per_pixel: 1.0, z_um_per_slice: None, units: Units::Pixels }
+    }
+}
+
+impl Calibration {
+    // Converts a value measured in pixels into the reporting units.
+    pub fn convert(&self, quantity: Quantity, pixels: f64) -> f64 {
+        if self.units == Units::Pixels {
+            return pixels;
+        }
+        match quantity {
+            Quantity::Dimensionless => pixels,
+            Quantity::X => pixels * self.x_um_per_pixel,
+            Quantity::Y => pixels * self.y_um_per_pixel,
+            // Exact for square pixels; for rectangular ones the geometric mean is the best
+            // single factor for lengths whose direction varies
+            Quantity::Length => pixels * (self.x_um_per_pixel * self.y_um_per_pixel).sqrt(),
+            Quantity::Area => pixels * self.x_um_per_pixel * self.y_um_per_pixel,
+        }
+    }
+
+    // Column name suffix naming the unit of a quantity, e.g. "_um2".
+    pub fn suffix(&self, quantity: Quantity) -> &'static str {
+        match (quantity, self.units) {
+            (Quantity::Dimensionless, _) => "",
+            (Quantity::Area, Units::Pixels) => "_px2",
+            (Quantity::Area, Units::Microns) => "_um2",
+            (_, Units::Pixels) => "_px",
+            (_, Units::Microns) => "_um",
+        }
+    }
+}
+
+// Pixel sizes above this are print/screen DPI settings left by image editors, not a microscope
+// calibration (72 dpi is 353 µm per pixel).
+const MAX_PLAUSIBLE_UM_PER_PIXEL: f64 = 50.0;
+
+// Reads the pixel size in µm as (x, y, z) from image metadata: OME-XML or ImageJ descriptions
+// and resolution tags in TIFFs, the pHYs chunk in PNGs. Returns None if the image doesn't
+// record a plausible one.
+pub fn pixel_size_from_metadata(path: &str) -> Result<Option<PixelSize>, Box<dyn Error>> {
+    let size = raw_pixel_size_from_metadata(path)?;
+    Ok(size.filter(|&(x, y, _)| x > 0.0 && y > 0.0 && x <= MAX_PLAUSIBLE_UM_PER_PIXEL && y <= MAX_PLAUSIBLE_UM_PER_PIXEL))
+}
+
+fn raw_pixel_size_from_metadata(path: &str) -> Result<Option<PixelSize>, Box<dyn Error>> {
+    let lower = path.to_lowercase();
+    if lower.ends_with(".tif") || lower.ends_with(".tiff") {
+        tiff_pixel_size(path)
+    } else if lower.ends_with(".png") {
+        let reader = png::Decoder::new(BufReader::new(File::open(path)?)).read_info()?;
+        Ok(reader.info().pixel_dims.and_then(|dims| {
+            // Only pixels per meter is a physical unit
+            (dims.unit == png::Unit::Meter && dims.xppu > 0 && dims.yppu > 0)
+                .then(|| (1e6 / dims.xppu as f64, 1e6 / dims.yppu as f64, None))
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+fn tiff_pixel_size(path: &str) -> Result<Option<PixelSize>, Box<dyn Error>> {
+    let mut decoder = tiff::decoder::Decoder::new(BufReader::new(File::open(path)?))?;
+    let description = match decoder.find_tag(Tag::ImageDescription)? {
+        Some(Value::Ascii(text)) => text,
+        _ => String::new(),
+    };
+
+    // OME-TIFF: <Pixels PhysicalSizeX="0.325" PhysicalSizeXUnit="nm" ...>, in µm unless the unit
+    // attribute says otherwise. A unit that isn't a length leaves the pixel size unknown.
+    if let Some(x) = ome_physical_size(&description, "X") {
+        let Some(x) = x else { return Ok(None) };
+        let y = match ome_physical_size(&description, "Y") {
+            Some(Some(y)) => y,
+            Some(None) => return Ok(None),
+            None => x,
+        };
+        let z = ome_physical_size(&description, "Z").flatten();
+        return Ok(Some((x, y, z)));
+    }
+
+    let rational = |value: Option<Value>| match value {
+        Some(Value::Rational(n, d)) if n > 0 && d > 0 => Some(n as f64 / d as f64),
+        Some(Value::RationalBig(n, d)) if n > 0 && d > 0 => Some(n as f64 / d as f64),
+        _ => None,
+    };
+    let (Some(x_res), Some(y_res)) = (rational(decoder.find_tag(Tag::XResolution)?), rational(decoder.find_tag(Tag::YResolution)?)) else {
+        return Ok(None);
+    };
+
+    // ImageJ stores pixels per `unit` with the unit in the description
+    let imagej_unit = description.lines().find_map(|line| line.strip_prefix("unit="));
+    let um_per_unit = match imagej_unit {
+        Some("micron") | Some("um") | Some("µm") | Some("\\u00B5m") => Some(1.0),
+        Some("nm") => Some(1e-3),
+        Some("mm") => Some(1e3),
+        _ => match decoder.find_tag_unsigned::<u16>(Tag::ResolutionUnit)? {
+            Some(2) => Some(25400.0),  // Inch
+            Some(3) => Some(10000.0),  // Centimeter
+            _ => None,
+        },
+    };
+    let z = description.lines()
+        .find_map(|line| line.strip_prefix("spacing="))
+        .and_then(|s| s.trim().parse::<f64>().ok());
+
+    Ok(um_per_unit.map(|um| (um / x_res, um / y_res, z.map(|z| z * um))))
+}
+
+// The OME PhysicalSize{axis} attribute in µm: None if absent, Some(None) if its value or
+// PhysicalSize{axis}Unit can't be read.
+fn ome_physical_size(xml: &str, axis: &str) -> Option<Option<f64>> {
+    let value = xml_attribute(xml, &format!("PhysicalSize{}", axis))?;
+    let um_per_unit = match xml_attribute(xml, &format!("PhysicalSize{}Unit", axis)) {
+        None => Some(1.0),
+        Some(unit) => um_per_length_unit(unit),
+    };
+    Some(value.parse::<f64>().ok().zip(um_per_unit).map(|(v, um)| v * um))
+}
+
+// Micrometres in one of the OME length units
+fn um_per_length_unit(unit: &str) -> Option<f64> {
+    match unit {
+        "µm" | "μm" | "&#181;m" | "&#xB5;m" | "um" | "micron" => Some(1.0),
+        "pm" => Some(1e-6),
+        "Å" | "&#197;" => Some(1e-4),
+        "nm" => Some(1e-3),
+        "mm" => Some(1e3),
+        "cm" => Some(1e4),
+        "m" => Some(1e6),
+        _ => None,
+    }
+}
+
+fn xml_attribute<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("{}=\"", name))? + name.len() + 2;
+    let end = xml[start..].find('"')? + start;
+    Some(&xml[start..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ome_sizes_are_converted_to_microns() {
+        let xml = r#"<Pixels PhysicalSizeX="325" PhysicalSizeXUnit="nm" PhysicalSizeY="0.0004" PhysicalSizeYUnit="mm" PhysicalSizeZ="1.5">"#;
+        assert!((ome_physical_size(xml, "X").unwrap().unwrap() - 0.325).abs() < 1e-12);
+        assert!((ome_physical_size(xml, "Y").unwrap().unwrap() - 0.4).abs() < 1e-12);
+        assert_eq!(ome_physical_size(xml, "Z"), Some(Some(1.5)));
+        assert_eq!(ome_physical_size(r#"PhysicalSizeX="1" PhysicalSizeXUnit="pixel""#, "X"), Some(None));
+        assert_eq!(ome_physical_size(xml, "T"), None);
+    }
+}
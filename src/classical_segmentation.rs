@@ -0,0 +1,464 @@
+/*
+Classical segmentation for images that were never put through Cellpose: smooth one channel,
+threshold it, fill holes, drop small specks and split touching nuclei with a watershed seeded at
+the peaks of the distance transform. The result is a label mask, outlined like any other.
+*/
+
+use crate::segmentation::{label_components, label_mask_to_segmentation, LabelMask, Segmentation};
+
+use ndarray::Array2;
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::str::FromStr;
+
+// Bins of the intensity histogram the global thresholds are computed from
+const HISTOGRAM_BINS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThresholdMethod {
+    Otsu,      // Maximises the between-class variance
+    Li,        // Minimises the cross entropy between the image and its binarisation
+    Triangle,  // Furthest histogram bin from the line joining the peak to the end of the longer tail
+    Adaptive,  // Each pixel against the mean of the block around it
+}
+
+impl FromStr for ThresholdMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "otsu" => Ok(ThresholdMethod::Otsu),
+            "li" => Ok(ThresholdMethod::Li),
+            "triangle" => Ok(ThresholdMethod::Triangle),
+            "adaptive" => Ok(ThresholdMethod::Adaptive),
+            _ => Err(format!("Unknown threshold method '{}', expected otsu, li, triangle or adaptive", s)),
+        }
+    }
+}
+
+// Whether cells are brighter (fluorescence) or darker (brightfield) than the background
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Foreground {
+    Bright,
+    Dark,
+}
+
+impl FromStr for Foreground {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bright" => Ok(Foreground::Bright),
+            "dark" => Ok(Foreground::Dark),
+            _ => Err(format!("Unknown foreground '{}', expected bright or dark", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentationParameters {
+    pub method: ThresholdMethod,
+    pub foreground: Foreground,
+    pub sigma: f64,           // Gaussian smoothing in pixels, 0 for none
+    pub block_size: usize,    // Side of the adaptive threshold's block in pixels
+    pub offset: f64,          // Subtracted from the adaptive threshold's local mean
+    pub min_size: usize,      // Objects with fewer pixels are dropped
+    pub seed_distance: usize, // Least distance between watershed seeds in pixels, 0 to not split objects
+}
+
+// Segments `plane` into cells labelled 1, 2, ... in raster order of their first pixel
+pub fn segment(plane: &Array2<f64>, parameters: &SegmentationParameters) -> Segmentation {
+    let mut smoothed = gaussian_blur(plane, parameters.sigma);
+    // Thresholds look for bright objects; dark ones are flipped first
+    if parameters.foreground == Foreground::Dark {
+        let max = smoothed.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        smoothed.mapv_inplace(|v| max - v);
+    }
+
+    let mut binary = match parameters.method {
+        ThresholdMethod::Adaptive => adaptive_threshold(&smoothed, parameters.block_size, parameters.offset),
+        global => {
+            let threshold = match global {
+                ThresholdMethod::Otsu => otsu_threshold(&smoothed),
+                ThresholdMethod::Li => li_threshold(&smoothed),
+                _ => triangle_threshold(&smoothed),
+            };
+            smoothed.mapv(|v| v > threshold)
+        }
+    };
+    fill_holes(&mut binary);
+
+    let rows: Vec<Vec<bool>> = binary.rows().into_iter().map(|row| row.to_vec()).collect();
+    let mut labels = label_components(&rows);
+    remove_small(&mut labels, parameters.min_size);
+    if parameters.seed_distance > 0 {
+        labels = watershed(&labels, parameters.seed_distance);
+        remove_small(&mut labels, parameters.min_size);
+    }
+    relabel(&mut labels);
+    label_mask_to_segmentation(&labels)
+}
+
+// Separable Gaussian blur, replicating the edge pixels. The kernel reaches 3 sigma either side.
+pub fn gaussian_blur(plane: &Array2<f64>, sigma: f64) -> Array2<f64> {
+    if sigma <= 0.0 {
+        return plane.clone();
+    }
+    let radius = (3.0 * sigma).ceil() as isize;
+    let kernel: Vec<f64> = (-radius..=radius).map(|i| (-(i * i) as f64 / (2.0 * sigma * sigma)).exp()).collect();
+    let total: f64 = kernel.iter().sum();
+    let kernel: Vec<f64> = kernel.iter().map(|k| k / total).collect();
+
+    let (height, width) = plane.dim();
+    let clamp = |i: isize, n: usize| i.clamp(0, n as isize - 1) as usize;
+    let horizontal: Array2<f64> = Array2::from_shape_fn((height, width), |(y, x)| {
+        kernel.iter().enumerate().map(|(k, w)| w * plane[[y, clamp(x as isize + k as isize - radius, width)]]).sum::<f64>()
+    });
+    Array2::from_shape_fn((height, width), |(y, x)| {
+        kernel.iter().enumerate().map(|(k, w)| w * horizontal[[clamp(y as isize + k as isize - radius, height), x]]).sum::<f64>()
+    })
+}
+
+// Histogram of `plane` over its own range, with the intensity at the centre of each bin
+fn histogram(plane: &Array2<f64>) -> (Vec<f64>, Vec<f64>) {
+    let lo = plane.iter().cloned().fold(f64::INFINITY, f64::min);
+    let hi = plane.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let width = if hi > lo { (hi - lo) / HISTOGRAM_BINS as f64 } else { 1.0 };
+    let mut counts = vec![0.0; HISTOGRAM_BINS];
+    for &v in plane {
+        counts[(((v - lo) / width) as usize).min(HISTOGRAM_BINS - 1)] += 1.0;
+    }
+    let centres = (0..HISTOGRAM_BINS).map(|b| lo + (b as f64 + 0.5) * width).collect();
+    (counts, centres)
+}
+
+// Otsu's threshold: the bin boundary that maximises the variance between the two classes
+pub fn otsu_threshold(plane: &Array2<f64>) -> f64 {
+    let (counts, centres) = histogram(plane);
+    let total: f64 = counts.iter().sum();
+    let sum: f64 = counts.iter().zip(&centres).map(|(n, c)| n * c).sum();
+
+    let (mut below, mut below_sum) = (0.0, 0.0);
+    let (mut best, mut threshold) = (f64::NEG_INFINITY, centres[0]);
+    for b in 0..HISTOGRAM_BINS - 1 {
+        below += counts[b];
+        below_sum += counts[b] * centres[b];
+        let above = total - below;
+        if below == 0.0 || above == 0.0 {
+            continue;
+        }
+        let difference = below_sum / below - (sum - below_sum) / above;
+        let between = below * above * difference * difference;
+        if between > best {
+            best = between;
+            threshold = centres[b];
+        }
+    }
+    threshold
+}
+
+// Li's iterative minimum cross entropy threshold, starting from the mean. Intensities are shifted
+// to be positive since the iteration takes logarithms.
+pub fn li_threshold(plane: &Array2<f64>) -> f64 {
+    let lo = plane.iter().cloned().fold(f64::INFINITY, f64::min);
+    let values: Vec<f64> = plane.iter().map(|v| v - lo).collect();
+    let tolerance = values.iter().cloned().fold(0.0, f64::max) / (2.0 * HISTOGRAM_BINS as f64);
+
+    let mut threshold = values.iter().sum::<f64>() / values.len().max(1) as f64;
+    for _ in 0..1000 {
+        let (mut fore, mut fore_n, mut back, mut back_n) = (0.0, 0.0, 0.0, 0.0);
+        for &v in &values {
+            if v > threshold {
+                fore += v;
+                fore_n += 1.0;
+            } else {
+                back += v;
+                back_n += 1.0;
+            }
+        }
+        if fore_n == 0.0 || back_n == 0.0 {
+            break;
+        }
+        let (mean_fore, mean_back) = (fore / fore_n, back / back_n);
+        // A background of zeros has no logarithm; nudge it up like a near-empty bin would
+        let mean_back = mean_back.max(tolerance.max(f64::EPSILON));
+        let next = (mean_fore - mean_back) / (mean_fore.ln() - mean_back.ln());
+        let converged = (next - threshold).abs() < tolerance;
+        threshold = next;
+        if converged {
+            break;
+        }
+    }
+    threshold + lo
+}
+
+// Zack's triangle threshold, which suits a single background peak with a long tail of objects
+pub fn triangle_threshold(plane: &Array2<f64>) -> f64 {
+    let (counts, centres) = histogram(plane);
+    let peak = (0..HISTOGRAM_BINS).max_by(|&a, &b| counts[a].total_cmp(&counts[b]).then(b.cmp(&a))).unwrap_or(0);
+    let first = counts.iter().position(|&n| n > 0.0).unwrap_or(0);
+    let last = counts.iter().rposition(|&n| n > 0.0).unwrap_or(HISTOGRAM_BINS - 1);
+    // The line runs from the peak to the end of whichever tail is longer
+    let end = if last - peak >= peak - first { last } else { first };
+    if end == peak {
+        return centres[peak];
+    }
+
+    let (x0, y0, x1, y1) = (peak as f64, counts[peak], end as f64, counts[end]);
+    let length = ((x1 - x0).powi(2) + (y1 - y0).powi(2)).sqrt();
+    let range = if end > peak { peak..=end } else { end..=peak };
+    let furthest = range
+        .max_by(|&a, &b| {
+            let distance = |b: usize| ((y1 - y0) * b as f64 - (x1 - x0) * counts[b] + x1 * y0 - y1 * x0).abs() / length;
+            distance(a).total_cmp(&distance(b))
+        })
+        .unwrap_or(peak);
+    centres[furthest]
+}
+
+// Foreground wherever a pixel is above the mean of the `block_size` square around it less
+// `offset`. Blocks are clipped at the image edges.
+pub fn adaptive_threshold(plane: &Array2<f64>, block_size: usize, offset: f64) -> Array2<bool> {
+    let (height, width) = plane.dim();
+    // Summed-area table with a zero first row and column
+    let mut integral = Array2::<f64>::zeros((height + 1, width + 1));
+    for y in 0..height {
+        for x in 0..width {
+            integral[[y + 1, x + 1]] = plane[[y, x]] + integral[[y, x + 1]] + integral[[y + 1, x]] - integral[[y, x]];
+        }
+    }
+
+    let half = block_size / 2;
+    Array2::from_shape_fn((height, width), |(y, x)| {
+        let (top, bottom) = (y.saturating_sub(half), (y + half + 1).min(height));
+        let (left, right) = (x.saturating_sub(half), (x + half + 1).min(width));
+        let sum = integral[[bottom, right]] - integral[[top, right]] - integral[[bottom, left]] + integral[[top, left]];
+        let mean = sum / ((bottom - top) * (right - left)) as f64;
+        plane[[y, x]] > mean - offset
+    })
+}
+
+// Fills background regions that can't be reached from the image edge through 4-connected
+// background pixels.
+pub fn fill_holes(binary: &mut Array2<bool>) {
+    let (height, width) = binary.dim();
+    let mut outside = Array2::from_elem((height, width), false);
+    let mut stack: Vec<(usize, usize)> = (0..height)
+        .flat_map(|y| [(y, 0), (y, width.saturating_sub(1))])
+        .chain((0..width).flat_map(|x| [(0, x), (height.saturating_sub(1), x)]))
+        .filter(|&p| !binary[p])
+        .collect();
+    while let Some((y, x)) = stack.pop() {
+        if outside[[y, x]] {
+            continue;
+        }
+        outside[[y, x]] = true;
+        let neighbours = [(y.wrapping_sub(1), x), (y + 1, x), (y, x.wrapping_sub(1)), (y, x + 1)];
+        for (ny, nx) in neighbours {
+            if ny < height && nx < width && !binary[[ny, nx]] && !outside[[ny, nx]] {
+                stack.push((ny, nx));
+            }
+        }
+    }
+    binary.zip_mut_with(&outside, |b, &o| *b = !o);
+}
+
+// Clears every label with fewer than `min_size` pixels
+fn remove_small(labels: &mut LabelMask, min_size: usize) {
+    let mut sizes: HashMap<u32, usize> = HashMap::new();
+    for &label in labels.iter().flatten() {
+        *sizes.entry(label).or_insert(0) += 1;
+    }
+    for label in labels.iter_mut().flatten() {
+        if sizes[label] < min_size {
+            *label = 0;
+        }
+    }
+}
+
+// Renumbers labels 1, 2, ... in raster order of their first pixel
+fn relabel(labels: &mut LabelMask) {
+    let mut new_labels: HashMap<u32, u32> = HashMap::new();
+    for label in labels.iter_mut().flatten() {
+        if *label != 0 {
+            let next = new_labels.len() as u32 + 1;
+            *label = *new_labels.entry(*label).or_insert(next);
+        }
+    }
+}
+
+// Exact Euclidean distance from every foreground pixel to the nearest background pixel, by
+// Felzenszwalb and Huttenlocher's separable lower envelope of parabolas. Pixels beyond the image
+// edge don't count as background.
+pub fn distance_transform(foreground: &Array2<bool>) -> Array2<f64> {
+    let (height, width) = foreground.dim();
+    let infinity = ((height * height + width * width) as f64) * 2.0;
+    let mut squared = foreground.mapv(|f| if f { infinity } else { 0.0 });
+    for mut column in squared.columns_mut() {
+        let transformed = squared_distance_1d(&column.to_vec());
+        column.assign(&ndarray::Array1::from(transformed));
+    }
+    for mut row in squared.rows_mut() {
+        let transformed = squared_distance_1d(&row.to_vec());
+        row.assign(&ndarray::Array1::from(transformed));
+    }
+    squared.mapv(f64::sqrt)
+}
+
+// One-dimensional squared distance transform of the sampled function `f`: min over q of
+// f(q) + (p - q)^2 at every p
+pub fn squared_distance_1d(f: &[f64]) -> Vec<f64> {
+    let n = f.len();
+    let mut result = vec![0.0; n];
+    if n == 0 {
+        return result;
+    }
+    // Parabola vertices of the lower envelope and the boundaries between them
+    let mut vertices = vec![0usize; n];
+    let mut boundaries = vec![0.0; n + 1];
+    let mut k = 0;
+    boundaries[0] = f64::NEG_INFINITY;
+    boundaries[1] = f64::INFINITY;
+    let intersection = |q: usize, v: usize| {
+        ((f[q] + (q * q) as f64) - (f[v] + (v * v) as f64)) / (2.0 * q as f64 - 2.0 * v as f64)
+    };
+    for q in 1..n {
+        let mut s = intersection(q, vertices[k]);
+        while s <= boundaries[k] {
+            k -= 1;
+            s = intersection(q, vertices[k]);
+        }
+        k += 1;
+        vertices[k] = q;
+        boundaries[k] = s;
+        boundaries[k + 1] = f64::INFINITY;
+    }
+    k = 0;
+    for (q, value) in result.iter_mut().enumerate() {
+        while boundaries[k + 1] < q as f64 {
+            k += 1;
+        }
+        let d = q as f64 - vertices[k] as f64;
+        *value = d * d + f[vertices[k]];
+    }
+    result
+}
+
+// Heap entry of the watershed flood, highest distance first and then first pushed first
+struct Flood {
+    distance: f64,
+    order: usize,
+    pixel: (usize, usize),
+}
+
+impl PartialEq for Flood {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Flood {}
+
+impl PartialOrd for Flood {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Flood {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.total_cmp(&other.distance).then(other.order.cmp(&self.order))
+    }
+}
+
+// Splits touching objects along the valleys of their distance transform. Seeds are the pixels
+// no lower than any pixel of the same object within `seed_distance`; touching seed pixels
+// make one seed. Every object keeps at least one seed, at its highest point.
+pub fn watershed(objects: &LabelMask, seed_distance: usize) -> LabelMask {
+    let height = objects.len();
+    let width = objects.first().map_or(0, |row| row.len());
+    let foreground = Array2::from_shape_fn((height, width), |(y, x)| objects[y][x] != 0);
+    let distance = distance_transform(&foreground);
+
+    let r = seed_distance as isize;
+    let peaks: Vec<Vec<bool>> = (0..height)
+        .map(|y| {
+            (0..width)
+                .map(|x| {
+                    let label = objects[y][x];
+                    label != 0 && (-r..=r).all(|dy| (-r..=r).all(|dx| {
+                        let (ny, nx) = (y as isize + dy, x as isize + dx);
+                        if ny < 0 || nx < 0 || ny >= height as isize || nx >= width as isize {
+                            return true;
+                        }
+                        let (ny, nx) = (ny as usize, nx as usize);
+                        objects[ny][nx] != label || distance[[ny, nx]] <= distance[[y, x]]
+                    }))
+                })
+                .collect()
+        })
+        .collect();
+    let mut labels = label_components(&peaks);
+
+    let mut heap = BinaryHeap::new();
+    let mut order = 0;
+    for y in 0..height {
+        for x in 0..width {
+            if labels[y][x] != 0 {
+                heap.push(Flood { distance: distance[[y, x]], order, pixel: (y, x) });
+                order += 1;
+            }
+        }
+    }
+    // Flood downhill from the seeds, each pixel taking the label that reaches it first and never
+    // crossing from one object into another
+    while let Some(Flood { pixel: (y, x), .. }) = heap.pop() {
+        let label = labels[y][x];
+        for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+            for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                if labels[ny][nx] == 0 && objects[ny][nx] == objects[y][x] {
+                    labels[ny][nx] = label;
+                    heap.push(Flood { distance: distance[[ny, nx]], order, pixel: (ny, nx) });
+                    order += 1;
+                }
+            }
+        }
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn otsu_splits_a_bimodal_histogram() {
+        // Dim background around 10 and bright nuclei around 200
+        let plane = Array2::from_shape_fn((20, 20), |(y, x)| {
+            let jitter = ((x * 7 + y * 13) % 11) as f64;
+            if x < 12 { 5.0 + jitter } else { 195.0 + jitter }
+        });
+        let threshold = otsu_threshold(&plane);
+        // The centre of the brightest background bin, which is at most one bin below 15
+        assert!(threshold > 14.0 && threshold < 195.0, "threshold {}", threshold);
+    }
+
+    #[test]
+    fn watershed_splits_two_touching_discs() {
+        let centres = [(8.0, 10.0), (19.0, 10.0)];
+        let binary: Vec<Vec<bool>> = (0..21)
+            .map(|y| (0..28).map(|x| centres.iter().any(|&(cx, cy)| (x as f64 - cx).hypot(y as f64 - cy) <= 6.0)).collect())
+            .collect();
+        let objects = label_components(&binary);
+        assert!(objects.iter().flatten().all(|&l| l <= 1));
+
+        let labels = watershed(&objects, 4);
+        let (left, right) = (labels[10][8], labels[10][19]);
+        assert!(left != 0 && right != 0 && left != right);
+        let mut distinct: Vec<u32> = labels.iter().flatten().copied().filter(|&l| l != 0).collect();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), 2);
+    }
+}
@@ -0,0 +1,101 @@
+/*
+Subcellular compartments. Nuclei from a separate segmentation are matched to the cells that
+contain them; each cell then splits into its nucleus, its cytoplasm (the rest of the cell) and a
+membrane ring just inside its outline.
+*/
+
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Default)]
+pub struct Compartments {
+    pub nucleus: Option<usize>,           // Index of the matched nucleus
+    pub nucleus_pixels: Vec<(i32, i32)>,  // The nucleus clipped to the cell
+    pub cytoplasm_pixels: Vec<(i32, i32)>,
+    pub membrane_pixels: Vec<(i32, i32)>,
+}
+
+// Matches every nucleus to the cell it overlaps most, if at least `min_overlap` of the nucleus's
+// pixels lie in that cell. A cell that several nuclei match keeps the one with the largest
+// overlap. Returns the matched nucleus of each cell.
+pub fn match_nuclei(cells: &[Vec<(i32, i32)>], nuclei: &[Vec<(i32, i32)>], min_overlap: f64) -> Vec<Option<usize>> {
+    let mut owner: HashMap<(i32, i32), usize> = HashMap::new();
+    for (c, pixels) in cells.iter().enumerate() {
+        for &p in pixels {
+            owner.insert(p, c);
+        }
+    }
+
+    // (overlap, nucleus) of the best nucleus found so far for each cell
+    let mut best: Vec<Option<(usize, usize)>> = vec![None; cells.len()];
+    for (n, pixels) in nuclei.iter().enumerate() {
+        let mut counts: HashMap<usize, usize> = HashMap::new();
+        for p in pixels {
+            if let Some(&c) = owner.get(p) {
+                *counts.entry(c).or_insert(0) += 1;
+            }
+        }
+        // Ties go to the lower cell index so matching doesn't depend on hash order
+        let Some((cell, overlap)) = counts.into_iter().max_by_key(|&(c, count)| (count, std::cmp::Reverse(c))) else { continue };
+        if (overlap as f64) < min_overlap * pixels.len() as f64 {
+            continue;
+        }
+        if best[cell].is_none_or(|(current, _)| overlap > current) {
+            best[cell] = Some((overlap, n));
+        }
+    }
+
+    best.into_iter().map(|b| b.map(|(_, n)| n)).collect()
+}
+
+// Splits a cell into compartments. The membrane is the pixels within `membrane_width` pixels of
+// the outside of the cell, measured in 8-connected steps, and overlaps the cytoplasm and nucleus.
+pub fn compartments(cell: &[(i32, i32)], nucleus: Option<(usize, &[(i32, i32)])>, membrane_width: usize) -> Compartments {
+    let cell_set: HashSet<(i32, i32)> = cell.iter().copied().collect();
+    let nucleus_set: HashSet<(i32, i32)> = nucleus.map_or_else(HashSet::new, |(_, pixels)| {
+        pixels.iter().copied().filter(|p| cell_set.contains(p)).collect()
+    });
+
+    // Erode the cell `membrane_width` times; what the erosion removes is the membrane
+    let mut interior = cell_set.clone();
+    for _ in 0..membrane_width {
+        let eroded: HashSet<(i32, i32)> = interior.iter()
+            .copied()
+            .filter(|&(x, y)| (-1..=1).all(|dy| (-1..=1).all(|dx| interior.contains(&(x + dx, y + dy)))))
+            .collect();
+        interior = eroded;
+    }
+
+    Compartments {
+        nucleus: nucleus.map(|(n, _)| n),
+        nucleus_pixels: cell.iter().copied().filter(|p| nucleus_set.contains(p)).collect(),
+        cytoplasm_pixels: cell.iter().copied().filter(|p| !nucleus_set.contains(p)).collect(),
+        membrane_pixels: cell.iter().copied().filter(|p| !interior.contains(p)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(x0: i32, y0: i32, side: i32) -> Vec<(i32, i32)> {
+        (y0..y0 + side).flat_map(|y| (x0..x0 + side).map(move |x| (x, y))).collect()
+    }
+
+    #[test]
+    fn a_cell_splits_around_its_nucleus() {
+        let cells = vec![square(0, 0, 10), square(20, 0, 10)];
+        // The first nucleus sits in the first cell; the second barely touches the second cell
+        let nuclei = vec![square(3, 3, 4), square(28, 8, 4)];
+        let matches = match_nuclei(&cells, &nuclei, 0.5);
+        assert_eq!(matches, vec![Some(0), None]);
+
+        let split = compartments(&cells[0], Some((0, &nuclei[0])), 2);
+        assert_eq!(split.nucleus, Some(0));
+        assert_eq!(split.nucleus_pixels.len(), 16);
+        assert_eq!(split.cytoplasm_pixels.len(), 84);
+        assert_eq!(split.nucleus_pixels.len() + split.cytoplasm_pixels.len(), cells[0].len());
+        // The outer two rings of the 10 x 10 cell, clear of the nucleus
+        assert_eq!(split.membrane_pixels.len(), 100 - 36);
+        assert!(split.membrane_pixels.iter().all(|p| !split.nucleus_pixels.contains(p)));
+    }
+}
@@ -0,0 +1,472 @@
+/*
+Command line options for a DECODE run. Every option has a default so `cargo run` alone
+reproduces the original behaviour on the bundled example image.
+*/
+
+use crate::batch_correction::BatchCorrection;
+use crate::calibration::{PixelSize, Units};
+use crate::classical_segmentation::{Foreground, ThresholdMethod};
+use crate::extract_features::{FillRule, IntensityRegion};
+use crate::illumination::Background;
+use crate::image_normalization::ImageNormalization;
+use crate::kmeans::Init;
+use crate::neighbours::Adjacency;
+use crate::normalization::{Method, NormalizationSpec};
+use crate::stains::Stains;
+use crate::texture::GradientOperator;
+
+use std::error::Error;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+const IMAGE_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/9.png");
+const SEGMENTATION_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/9_outlines.txt");
+const CLUSTERED_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/clustered.png");
+
+const USAGE: &str = "Usage: kmeans [options]
+  --image <path>       image to measure (default: src/9.png)
+  --panel <path>       marker panel CSV with channel (index from 0) and marker columns; features
+                       of those channels are named after their markers
+  --markers <names>    comma-separated marker names of channels 0, 1, 2, ..., instead of --panel
+  --segmentation <path>  cells of the image: a Cellpose _outlines.txt or _seg.npy, or an
+                       integer label mask PNG/TIFF (default: src/9_outlines.txt); alias --outlines
+  --flat-field <path>  flat-field reference image, one channel or one per image channel; each
+                       channel is divided by it scaled to average 1
+  --dark-frame <path>  dark-frame reference image subtracted from the image and the flat field
+  --flat-field-batch <paths>  comma-separated images from the same acquisition; a flat field is
+                       estimated from them and the image instead of read from --flat-field
+  --save-flat-field <path>  where to write the estimated flat field as a float TIFF
+  --background <method>  background subtraction after flat-field correction: none (default),
+                       rolling-ball or top-hat
+  --background-radius <px>  ball or disk radius, larger than the largest cell (default: 50)
+  --normalize <method> normalise the image to --normalization-reference before measuring:
+                       reinhard (L*a*b* colour statistics), macenko (H&E stains) or histogram
+                       (every channel's intensity distribution)
+  --normalization-reference <path>  reference image --normalize matches
+  --normalization-table <path>  where --normalize writes the parameters it matched
+                       (default: normalization.csv)
+  --stains <s>         add optical density channels of each stain by colour deconvolution of an
+                       RGB image: he, h-dab, he-dab, macenko (H&E vectors estimated from the
+                       image) or a CSV with stain, r, g and b columns
+  --threshold <method> segment the image instead of reading --segmentation: otsu, li, triangle
+                       or adaptive
+  --segment-channel <c>  image channel --threshold segments (default: gray)
+  --foreground <f>     whether cells are bright (default) or dark against the background
+  --smoothing <sigma>  Gaussian smoothing before thresholding, in pixels (default: 1)
+  --block-size <px>    side of the block an adaptive threshold averages over, odd (default: 51)
+  --threshold-offset <x>  subtracted from an adaptive threshold's local mean (default: 0)
+  --min-size <px>      smallest object --threshold keeps, in pixels (default: 30)
+  --seed-distance <px> least distance between the watershed seeds that split touching cells;
+                       0 leaves them joined (default: 5)
+  --nuclei <path>      nuclear segmentation, in any --segmentation format; adds the shape,
+                       intensity, moments and (for --texture-channels) texture of each cell's
+                       nucleus, cytoplasm and membrane, and nuclear/cytoplasmic ratios
+  --min-nucleus-overlap <f>  fraction of a nucleus that must lie in a cell to match it (default: 0.5)
+  --membrane-width <px>  width of the membrane ring inside the cell outline (default: 2)
+  --output <path>      where to save the clustered image (default: src/clustered.png)
+  --feature-table <path>  where to write the per-cell feature CSV (default: features.csv)
+  --intensity-region <r>  pixels intensity features use: interior (default) or boundary
+  --fill-rule <rule>   polygon fill rule for cell interiors: even-odd (default) or nonzero
+  --pixel-size <um>    µm per pixel, as one value or x,y[,z]; read from image metadata if omitted
+  --units <u>          units for lengths and areas: microns (default when the pixel size is
+                       known) or pixels
+  --tissue-mask <path> image whose nonzero pixels are tissue; Voronoi regions are clipped to it
+                       instead of the image rectangle
+  --adjacency <graph>  which cells are neighbours: delaunay (default) or voronoi, which only
+                       joins cells whose clipped Voronoi regions touch
+  --neighbour-radius <r>  radius for counting nearby cells, in the reported units (default: 50)
+  --neighbour-average <names>  comma-separated feature columns to also average over each cell's
+                       neighbours, or all
+  --edge-list <path>   where to write the neighbour graph as an edge list CSV
+  --texture-channels <c>  comma-separated image channels for texture features, e.g. red or c2;
+                       gray is the mean of all channels but alpha (default: gray)
+  --glcm-distances <d> comma-separated co-occurrence distances in pixels (default: 1)
+  --glcm-angles <a>    comma-separated co-occurrence angles in degrees (default: 0,45,90,135)
+  --glcm-levels <n>    gray levels intensities are quantised to (default: 32)
+  --lbp-points <n>     neighbours sampled for local binary patterns (default: 8)
+  --lbp-radius <r>     radius of the local binary pattern circle in pixels (default: 1)
+  --gradient-operator <op>  sobel (default) or scharr
+  --hog-bins <n>       orientation bins of the gradient histogram (default: 9)
+  --moment-channel <c> image channel intensity-weighted moments use (default: gray)
+  --zernike-order <n>  highest order of the Zernike moments (default: 8)
+  --normalization <spec>  how features are scaled before clustering: a chain like arcsinh+z-score
+                       of min-max, z-score, robust, log1p, arcsinh[:cofactor] and quantile,
+                       then optional feature=chain overrides where * matches anything, e.g.
+                       z-score,channel_*=arcsinh:5+z-score (default: min-max)
+                       quantile maps every image's values onto the average distribution of
+                       this image and the --fit-tables
+  --clip-quantiles <lo,hi>  clip every feature to these percentiles of the fitted cells first
+  --fit-tables <paths> comma-separated feature tables of other images; normalisation is fitted
+                       over their cells and this image's instead of this image's alone
+  --normalization-params <path>  apply normalisation parameters saved by an earlier run instead
+                       of fitting them
+  --save-normalization-params <path>  where to write the fitted normalisation parameters
+  --batch-tables <paths>  comma-separated feature tables of other images, each optionally
+                       labelled batch=path, whose cells are clustered together with this image's;
+                       a table's batch is its label, else its batch column, else its file name;
+                       each table is normalised over its own cells unless --fit-tables or
+                       --normalization-params give one normalisation for every image
+  --batch <label>      batch of this image's cells (default: the image file name)
+  --batch-correction <method>  remove batch effects from the pooled features before clustering:
+                       combat or harmony
+  --batch-diagnostics <path>  where to write batch mixing scores before and after correction
+                       (default: batch_diagnostics.csv)
+  --pooled-clusters <path>  where to write the cluster of every pooled cell
+  --k <n>              number of clusters (default: 10)
+  --seed <n>           RNG seed; a random one is chosen and printed if omitted
+  --init <method>      centroid seeding: kmeans++ (default) or random
+  --n-init <n>         k-means restarts; the lowest-inertia run is kept (default: 10)
+  --tol <x>            convergence tolerance relative to the mean feature variance (default: 1e-4)
+  --select-k <a..b>    sweep k over a..b, write the scores table and cluster with the recommended k
+  --selection-table <path>  where --select-k writes its table (default: k_selection.csv)
+  --gap-refs <n>       uniform reference data sets per k for the gap statistic (default: 10)
+  --ripley <path>      where to write Ripley's K, L and pair correlation of all cells and each cluster
+  --ripley-max-radius <r>  largest radius, in the reported units (default: a quarter of the
+                       image's shorter side)
+  --ripley-steps <n>   number of radii (default: 20)
+  --enrichment <path>  where to write the neighbourhood enrichment z-scores between clusters
+  --permutations <n>   label permutations for the enrichment test (default: 1000)";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub image_path: String,
+    pub panel_path: Option<String>,
+    pub markers: Vec<String>,
+    pub segmentation_path: String,
+    pub flat_field_path: Option<String>,
+    pub dark_frame_path: Option<String>,
+    pub flat_field_batch: Vec<String>,
+    pub save_flat_field_path: Option<String>,
+    pub background: Background,
+    pub background_radius: f64,
+    pub normalize: Option<ImageNormalization>,
+    pub normalization_reference_path: Option<String>,
+    pub normalization_table_path: String,
+    pub stains: Option<Stains>,
+    pub threshold: Option<ThresholdMethod>,
+    pub segment_channel: String,
+    pub foreground: Foreground,
+    pub smoothing: f64,
+    pub block_size: usize,
+    pub threshold_offset: f64,
+    pub min_size: usize,
+    pub seed_distance: usize,
+    pub nuclei_path: Option<String>,
+    pub min_nucleus_overlap: f64,
+    pub membrane_width: usize,
+    pub output_path: String,
+    pub feature_table_path: String,
+    pub intensity_region: IntensityRegion,
+    pub fill_rule: FillRule,
+    pub pixel_size: Option<PixelSize>,
+    pub units: Option<Units>,
+    pub tissue_mask_path: Option<String>,
+    pub adjacency: Adjacency,
+    pub neighbour_radius: f64,
+    pub neighbour_average: Vec<String>,
+    pub edge_list_path: Option<String>,
+    pub texture_channels: Vec<String>,
+    pub glcm_distances: Vec<u32>,
+    pub glcm_angles: Vec<f64>,
+    pub glcm_levels: usize,
+    pub lbp_points: usize,
+    pub lbp_radius: f64,
+    pub gradient_operator: GradientOperator,
+    pub hog_bins: usize,
+    pub moment_channel: String,
+    pub zernike_order: usize,
+    pub normalization: NormalizationSpec,
+    pub clip_quantiles: Option<(f64, f64)>,
+    pub fit_tables: Vec<String>,
+    pub normalization_params_path: Option<String>,
+    pub save_normalization_params_path: Option<String>,
+    pub batch_tables: Vec<String>,
+    pub batch: Option<String>,
+    pub batch_correction: Option<BatchCorrection>,
+    pub batch_diagnostics_path: String,
+    pub pooled_clusters_path: Option<String>,
+    pub k: usize,
+    pub seed: u64,
+    pub init: Init,
+    pub n_init: usize,
+    pub tol: f64,
+    pub select_k: Option<RangeInclusive<usize>>,
+    pub selection_table_path: String,
+    pub gap_refs: usize,
+    pub ripley_path: Option<String>,
+    pub ripley_max_radius: Option<f64>,
+    pub ripley_steps: usize,
+    pub enrichment_path: Option<String>,
+    pub permutations: usize,
+}
+
+impl Config {
+    pub fn from_args() -> Result<Config, Box<dyn Error>> {
+        let mut config = Config {
+            image_path: IMAGE_PATH.to_string(),
+            panel_path: None,
+            markers: Vec::new(),
+            segmentation_path: SEGMENTATION_PATH.to_string(),
+            flat_field_path: None,
+            dark_frame_path: None,
+            flat_field_batch: Vec::new(),
+            save_flat_field_path: None,
+            background: Background::None,
+            background_radius: 50.0,
+            normalize: None,
+            normalization_reference_path: None,
+            normalization_table_path: "normalization.csv".to_string(),
+            stains: None,
+            threshold: None,
+            segment_channel: "gray".to_string(),
+            foreground: Foreground::Bright,
+            smoothing: 1.0,
+            block_size: 51,
+            threshold_offset: 0.0,
+            min_size: 30,
+            seed_distance: 5,
+            nuclei_path: None,
+            min_nucleus_overlap: 0.5,
+            membrane_width: 2,
+            output_path: CLUSTERED_PATH.to_string(),
+            feature_table_path: "features.csv".to_string(),
+            intensity_region: IntensityRegion::Interior,
+            fill_rule: FillRule::EvenOdd,
+            pixel_size: None,
+            units: None,
+            tissue_mask_path: None,
+            adjacency: Adjacency::Delaunay,
+            neighbour_radius: 50.0,
+            neighbour_average: Vec::new(),
+            edge_list_path: None,
+            texture_channels: vec!["gray".to_string()],
+            glcm_distances: vec![1],
+            glcm_angles: vec![0.0, 45.0, 90.0, 135.0],
+            glcm_levels: 32,
+            lbp_points: 8,
+            lbp_radius: 1.0,
+            gradient_operator: GradientOperator::Sobel,
+            hog_bins: 9,
+            moment_channel: "gray".to_string(),
+            zernike_order: 8,
+            normalization: NormalizationSpec { default: vec![Method::MinMax], rules: Vec::new() },
+            clip_quantiles: None,
+            fit_tables: Vec::new(),
+            normalization_params_path: None,
+            save_normalization_params_path: None,
+            batch_tables: Vec::new(),
+            batch: None,
+            batch_correction: None,
+            batch_diagnostics_path: "batch_diagnostics.csv".to_string(),
+            pooled_clusters_path: None,
+            k: 10,
+            seed: rand::random(),
+            init: Init::KMeansPlusPlus,
+            n_init: 10,
+            tol: 1e-4,
+            select_k: None,
+            selection_table_path: "k_selection.csv".to_string(),
+            gap_refs: 10,
+            ripley_path: None,
+            ripley_max_radius: None,
+            ripley_steps: 20,
+            enrichment_path: None,
+            permutations: 1000,
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                return Err(USAGE.into());
+            }
+            let value = args.next().ok_or_else(|| format!("Missing value for {}\n{}", arg, USAGE))?;
+            match arg.as_str() {
+                "--image" => config.image_path = value,
+                "--panel" => config.panel_path = Some(value),
+                "--markers" => config.markers = parse_list(&value)?,
+                "--segmentation" | "--outlines" => config.segmentation_path = value,
+                "--flat-field" => config.flat_field_path = Some(value),
+                "--dark-frame" => config.dark_frame_path = Some(value),
+                "--flat-field-batch" => config.flat_field_batch = parse_list(&value)?,
+                "--save-flat-field" => config.save_flat_field_path = Some(value),
+                "--background" => config.background = value.parse()?,
+                "--background-radius" => config.background_radius = parse_positive(&value)?,
+                "--normalize" => config.normalize = Some(value.parse()?),
+                "--normalization-reference" => config.normalization_reference_path = Some(value),
+                "--normalization-table" => config.normalization_table_path = value,
+                "--stains" => config.stains = Some(value.parse()?),
+                "--threshold" => config.threshold = Some(value.parse()?),
+                "--segment-channel" => config.segment_channel = value,
+                "--foreground" => config.foreground = value.parse()?,
+                "--smoothing" => config.smoothing = parse_positive(&value)?,
+                "--block-size" => config.block_size = parse_block_size(&value)?,
+                "--threshold-offset" => config.threshold_offset = value.parse()?,
+                "--min-size" => config.min_size = value.parse()?,
+                "--seed-distance" => config.seed_distance = value.parse()?,
+                "--nuclei" => config.nuclei_path = Some(value),
+                "--min-nucleus-overlap" => config.min_nucleus_overlap = parse_fraction(&value)?,
+                "--membrane-width" => config.membrane_width = parse_count(&value)?,
+                "--output" => config.output_path = value,
+                "--feature-table" => config.feature_table_path = value,
+                "--intensity-region" => config.intensity_region = value.parse()?,
+                "--fill-rule" => config.fill_rule = value.parse()?,
+                "--pixel-size" => config.pixel_size = Some(parse_pixel_size(&value)?),
+                "--units" => config.units = Some(value.parse()?),
+                "--tissue-mask" => config.tissue_mask_path = Some(value),
+                "--adjacency" => config.adjacency = value.parse()?,
+                "--neighbour-radius" => config.neighbour_radius = parse_positive(&value)?,
+                "--neighbour-average" => config.neighbour_average = value.split(',').map(|name| name.trim().to_string()).collect(),
+                "--edge-list" => config.edge_list_path = Some(value),
+                "--texture-channels" => config.texture_channels = parse_list(&value)?,
+                "--glcm-distances" => config.glcm_distances = parse_distances(&value)?,
+                "--glcm-angles" => config.glcm_angles = parse_list(&value)?,
+                "--glcm-levels" => config.glcm_levels = parse_levels(&value)?,
+                "--lbp-points" => config.lbp_points = parse_count(&value)?,
+                "--lbp-radius" => config.lbp_radius = parse_positive(&value)?,
+                "--gradient-operator" => config.gradient_operator = value.parse()?,
+                "--hog-bins" => config.hog_bins = parse_count(&value)?,
+                "--moment-channel" => config.moment_channel = value,
+                "--zernike-order" => config.zernike_order = value.parse()?,
+                "--normalization" => config.normalization = value.parse()?,
+                "--clip-quantiles" => config.clip_quantiles = Some(parse_percentile_pair(&value)?),
+                "--fit-tables" => config.fit_tables = parse_list(&value)?,
+                "--normalization-params" => config.normalization_params_path = Some(value),
+                "--save-normalization-params" => config.save_normalization_params_path = Some(value),
+                "--batch-tables" => config.batch_tables = parse_list(&value)?,
+                "--batch" => config.batch = Some(value),
+                "--batch-correction" => config.batch_correction = Some(value.parse()?),
+                "--batch-diagnostics" => config.batch_diagnostics_path = value,
+                "--pooled-clusters" => config.pooled_clusters_path = Some(value),
+                "--k" => config.k = value.parse()?,
+                "--seed" => config.seed = value.parse()?,
+                "--init" => config.init = value.parse()?,
+                "--n-init" => config.n_init = value.parse()?,
+                "--tol" => config.tol = value.parse()?,
+                "--select-k" => config.select_k = Some(parse_range(&value)?),
+                "--selection-table" => config.selection_table_path = value,
+                "--gap-refs" => config.gap_refs = value.parse()?,
+                "--ripley" => config.ripley_path = Some(value),
+                "--ripley-max-radius" => config.ripley_max_radius = Some(parse_positive(&value)?),
+                "--ripley-steps" => config.ripley_steps = parse_count(&value)?,
+                "--enrichment" => config.enrichment_path = Some(value),
+                "--permutations" => config.permutations = value.parse()?,
+                _ => return Err(format!("Unknown option {}\n{}", arg, USAGE).into()),
+            }
+        }
+
+        if config.k == 0 {
+            return Err(format!("--k needs at least one cluster\n{}", USAGE).into());
+        }
+        if config.flat_field_path.is_some() && !config.flat_field_batch.is_empty() {
+            return Err(format!("Use either --flat-field or --flat-field-batch, not both\n{}", USAGE).into());
+        }
+        if config.normalization_params_path.is_some() && !config.fit_tables.is_empty() {
+            return Err(format!("--normalization-params applies saved parameters; it can't be combined with --fit-tables\n{}", USAGE).into());
+        }
+        if config.normalize.is_some() && config.normalization_reference_path.is_none() {
+            return Err(format!("--normalize needs a --normalization-reference image\n{}", USAGE).into());
+        }
+        if config.batch_correction.is_some() && config.batch_tables.is_empty() {
+            return Err(format!("--batch-correction needs the --batch-tables of other images\n{}", USAGE).into());
+        }
+        Ok(config)
+    }
+}
+
+// Parses comma-separated values.
+fn parse_list<T>(value: &str) -> Result<Vec<T>, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: Into<Box<dyn Error>>,
+{
+    value.split(',').map(|s| s.trim().parse().map_err(Into::into)).collect()
+}
+
+// Parses a number of gray levels. Co-occurrence matrices grow with its square, so 256 is the most.
+fn parse_levels(value: &str) -> Result<usize, Box<dyn Error>> {
+    let levels: usize = value.parse()?;
+    if !(2..=256).contains(&levels) {
+        return Err(format!("Expected between 2 and 256 gray levels, got {}", levels).into());
+    }
+    Ok(levels)
+}
+
+// Parses comma-separated GLCM distances. A distance of 0 would pair every pixel with itself.
+fn parse_distances(value: &str) -> Result<Vec<u32>, Box<dyn Error>> {
+    let distances: Vec<u32> = parse_list(value)?;
+    if distances.contains(&0) {
+        return Err(format!("GLCM distances must be at least 1 pixel, got {}", value).into());
+    }
+    Ok(distances)
+}
+
+// Parses a count that must be at least 1.
+fn parse_count(value: &str) -> Result<usize, Box<dyn Error>> {
+    let count: usize = value.parse()?;
+    if count == 0 {
+        return Err(format!("Expected at least 1, got {}", value).into());
+    }
+    Ok(count)
+}
+
+// Parses an adaptive threshold block size, odd so the block is centred on its pixel.
+fn parse_block_size(value: &str) -> Result<usize, Box<dyn Error>> {
+    let size: usize = value.parse()?;
+    if size < 3 || size.is_multiple_of(2) {
+        return Err(format!("The block size must be odd and at least 3, got {}", value).into());
+    }
+    Ok(size)
+}
+
+// Parses a fraction between 0 and 1.
+fn parse_fraction(value: &str) -> Result<f64, Box<dyn Error>> {
+    let x: f64 = value.parse()?;
+    if !(0.0..=1.0).contains(&x) {
+        return Err(format!("Expected a fraction between 0 and 1, got {}", value).into());
+    }
+    Ok(x)
+}
+
+// Parses a length that must be greater than zero.
+fn parse_positive(value: &str) -> Result<f64, Box<dyn Error>> {
+    let x: f64 = value.parse()?;
+    if !(x > 0.0 && x.is_finite()) {
+        return Err(format!("Expected a positive number, got {}", value).into());
+    }
+    Ok(x)
+}
+
+// Parses a pair of percentiles "low,high" with 0 <= low < high <= 100.
+fn parse_percentile_pair(value: &str) -> Result<(f64, f64), Box<dyn Error>> {
+    let values: Vec<f64> = parse_list(value)?;
+    match values[..] {
+        [low, high] if 0.0 <= low && low < high && high <= 100.0 => Ok((low, high)),
+        _ => Err(format!("Expected two percentiles low,high between 0 and 100, got {}", value).into()),
+    }
+}
+
+// Parses an inclusive range written as "2..8" or "2-8".
+fn parse_range(value: &str) -> Result<RangeInclusive<usize>, Box<dyn Error>> {
+    let (start, end) = value.split_once("..")
+        .or_else(|| value.split_once('-'))
+        .ok_or_else(|| format!("Expected a range like 2..8, got '{}'", value))?;
+    let (start, end): (usize, usize) = (start.trim().parse()?, end.trim_start_matches('=').trim().parse()?);
+    if start > end {
+        return Err(format!("Empty range '{}'", value).into());
+    }
+    Ok(start..=end)
+}
+
+// Parses "0.325" (square pixels) or "0.325,0.325[,1.5]". Sizes must be positive.
+fn parse_pixel_size(value: &str) -> Result<PixelSize, Box<dyn Error>> {
+    let sizes: Vec<f64> = value.split(',').map(|s| s.trim().parse()).collect::<Result<_, _>>()?;
+    if sizes.iter().any(|&s| !(s > 0.0 && s.is_finite())) {
+        return Err(format!("Pixel sizes must be positive, got '{}'", value).into());
+    }
+    match sizes[..] {
+        [xy] => Ok((xy, xy, None)),
+        [x, y] => Ok((x, y, None)),
+        [x, y, z] => Ok((x, y, Some(z))),
+        _ => Err(format!("Expected a pixel size like 0.325 or 0.325,0.325,1.5, got '{}'", value).into()),
+    }
+}
@@ -4,14 +4,19 @@
 Extract features into a 2D vector of N cells each with roughly 20 features 
 */
 
-use crate::kmeans::{self, load_image_as_matrix, Point};
+use crate::calibration::Quantity;
+use crate::multichannel::MultiChannelImage;
 
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::error::Error;
-use std::path::absolute;
+use std::str::FromStr;
 
-pub fn read_outlines(filename: &str) -> Result<Vec<Vec<(i32, i32)>>, Box<dyn Error>> {
+// A cell outline as (x, y) pixel coordinates, in the order Cellpose wrote them.
+pub type Outline = Vec<(i32, i32)>;
+
+pub fn read_outlines(filename: &str) -> Result<Vec<Outline>, Box<dyn Error>> {
     let file = File::open(filename)?;
     let reader = BufReader::new(file);
     let mut data = Vec::new();
@@ -25,7 +30,7 @@ pub fn read_outlines(filename: &str) -> Result<Vec<Vec<(i32, i32)>>, Box<dyn Err
             .map(|s| s.parse::<i32>())
             .collect::<Result<Vec<_>, _>>()?;
         
-        if numbers.len() % 2 != 0 {
+        if !numbers.len().is_multiple_of(2) {
             return Err("Odd number of coordinates in line".into());
         }
         
@@ -41,53 +46,50 @@ pub fn cross(a: (i32, i32), b: (i32, i32), c: (i32, i32)) -> i32 {
     (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0)
 }
 
-pub fn convex_hull(mut points: Vec<Vec<(i32, i32)>>) -> Vec<Vec<(i32, i32)>> {
-    let mut hulls: Vec<Vec<(i32, i32)>> = Vec::new();
-
-    for cell in &mut points {
-        cell.sort();
-        // Build lower hull
-        let mut lower: Vec<(i32, i32)> = vec![cell[0], cell[1]];
-        for i in 2..cell.len() {
-                if cross(cell[i - 2], cell[i - 1], cell[i]) <= 0 {
-                    lower.pop();
-                }
+// Convex hull of a cell's points (Andrew's monotone chain), counter-clockwise without repeating
+// the first point. Cells with fewer than three distinct points are returned as they are.
+pub fn convex_hull(points: &[(i32, i32)]) -> Vec<(i32, i32)> {
+    let mut cell = points.to_vec();
+    cell.sort();
+    cell.dedup();
+    if cell.len() < 3 {
+        return cell;
+    }
 
-                lower.push(cell[i]);
+    // Build lower hull
+    let mut lower: Vec<(i32, i32)> = Vec::new();
+    for &p in &cell {
+        while lower.len() >= 2 && cross(lower[lower.len() - 2], lower[lower.len() - 1], p) <= 0 {
+            lower.pop();
         }
+        lower.push(p);
+    }
 
-        // Build upper hull
-        let mut upper: Vec<(i32, i32)> = vec![cell[cell.len() - 1], cell[cell.len() - 2]];
-        for i in (0..cell.len() - 2).rev() {
-            if  cross(upper[upper.len() - 2], upper[upper.len() - 1], cell[i]) <= 0 {
-                upper.pop();
-            }
-            upper.push(cell[i]);
+    // Build upper hull
+    let mut upper: Vec<(i32, i32)> = Vec::new();
+    for &p in cell.iter().rev() {
+        while upper.len() >= 2 && cross(upper[upper.len() - 2], upper[upper.len() - 1], p) <= 0 {
+            upper.pop();
         }
-
-        lower.pop();
-        upper.pop();
-        let mut hull = lower;
-        hull.extend(upper);
-        hulls.push(hull);
+        upper.push(p);
     }
-    
 
-    hulls
+    lower.pop();
+    upper.pop();
+    let mut hull = lower;
+    hull.extend(upper);
+    hull
 }
 
-pub fn convex_area(points: &[(i32, i32)]) -> f64 {
+// Area enclosed by a polygon (shoelace formula), in square pixels.
+pub fn polygon_area(points: &[(i32, i32)]) -> f64 {
+    let n = points.len();
     let mut area = 0.0;
-
-    for i in 0..points.len() {
-        if i == points.len() - 1 {
-            area += (points[i].0 * points[0].1 - points[0].0 * points[i].1) as f64;
-        } else {
-            area += (points[i].0 * points[i+1].1 - points[i+1].0 * points[i].1) as f64;
-        }
+    for i in 0..n {
+        let (a, b) = (points[i], points[(i + 1) % n]);
+        area += (a.0 as f64) * (b.1 as f64) - (b.0 as f64) * (a.1 as f64);
     }
-
-    0.05 * 0.5 * area.abs()
+    0.5 * area.abs()
 }
 
 fn distance_sq(p1: (i32, i32), p2: (i32, i32)) -> i64 {
@@ -96,7 +98,7 @@ fn distance_sq(p1: (i32, i32), p2: (i32, i32)) -> i64 {
     dx * dx + dy * dy
 }
 
-pub fn calculate_centroids(points: &Vec<Vec<(i32, i32)>>) -> Vec<(i32, i32)> {
+pub fn calculate_centroids(points: &[Outline]) -> Vec<(i32, i32)> {
     let mut centroids = Vec::new();
 
     for cell in points {
@@ -114,37 +116,7 @@ pub fn calculate_centroids(points: &Vec<Vec<(i32, i32)>>) -> Vec<(i32, i32)> {
     centroids
 }
 
-pub fn voronoi_areas(centroids: &[(i32, i32)], width: usize, height: usize) -> Vec<f64> {
-    if centroids.is_empty() {
-        return Vec::new();
-    }
-
-    let num_centroids = centroids.len();
-    let mut areas: Vec<f64> = vec![0.0; num_centroids];
-
-    for y_pixel in 0..height {
-        for x_pixel in 0..width {
-            let current_pixel = (x_pixel as i32, y_pixel as i32);
-            let mut min_dist_sq = i64::MAX;
-            let mut closest_centroid_index = 0; // Default to first, will be updated
-
-            // Find the closest centroid to the current_pixel
-            for (idx, centroid_coords) in centroids.iter().enumerate() {
-                let dist_sq = distance_sq(current_pixel, *centroid_coords);
-                if dist_sq < min_dist_sq {
-                    min_dist_sq = dist_sq;
-                    closest_centroid_index = idx;
-                }
-                // If dist_sq == min_dist_sq, the current logic implicitly favors
-                // the centroid with the lower index. This is a common tie-breaking rule.
-            }
-            // Assign the pixel to the closest centroid
-            areas[closest_centroid_index] += 0.1;
-        }
-    }
-    areas
-}
-
+// Length of a closed polygon, in pixels. Works for any polygon, not just convex hulls.
 pub fn convex_perimeter(points: &[(i32, i32)]) -> f64 {
     let mut perimeter = 0.0;
     let n  = points.len();
@@ -158,33 +130,320 @@ pub fn convex_perimeter(points: &[(i32, i32)]) -> f64 {
     perimeter
 }
 
-pub fn load_segmentations_as_matrix(image_path: &str, outline_path: &str) -> Vec<Vec<Point>> {
-    let rgb_matrix: Vec<Vec<Point>> = load_image_as_matrix(image_path);
-    let segmentation_matrix = read_outlines(outline_path).expect("Failed to load segmentations");
-    let mut segmentation_rgb: Vec<Vec<Point>> = vec![vec![Point(0, 0, 0); segmentation_matrix[0].len()]; segmentation_matrix.len()];
+// Shape of one cell. Lengths are in pixels, areas in square pixels and angles in radians.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Morphology {
+    pub area: f64,               // Area enclosed by the outline
+    pub perimeter: f64,          // Length of the outline
+    pub hull_area: f64,
+    pub hull_perimeter: f64,
+    pub solidity: f64,           // area / hull_area
+    pub circularity: f64,        // 4πA/P², 1 for a circle
+    pub major_axis_length: f64,  // Axes of the ellipse with the same second moments as the cell
+    pub minor_axis_length: f64,
+    pub eccentricity: f64,
+    pub orientation: f64,        // Angle of the major axis from the x axis, in (-π/2, π/2]
+    pub feret_min: f64,          // Smallest caliper width
+    pub feret_max: f64,          // Largest caliper width (the hull diameter)
+}
+
+impl Morphology {
+    pub const NAMES: [&'static str; 12] = [
+        "polygon_area", "perimeter", "hull_area", "hull_perimeter", "solidity", "circularity",
+        "major_axis_length", "minor_axis_length", "eccentricity", "orientation", "feret_min", "feret_max",
+    ];
+
+    // What each value measures, in the same order as NAMES
+    pub const QUANTITIES: [Quantity; 12] = [
+        Quantity::Area, Quantity::Length, Quantity::Area, Quantity::Length, Quantity::Dimensionless, Quantity::Dimensionless,
+        Quantity::Length, Quantity::Length, Quantity::Dimensionless, Quantity::Dimensionless, Quantity::Length, Quantity::Length,
+    ];
+
+    // Values in the same order as NAMES
+    pub fn values(&self) -> [f64; 12] {
+        [
+            self.area, self.perimeter, self.hull_area, self.hull_perimeter, self.solidity, self.circularity,
+            self.major_axis_length, self.minor_axis_length, self.eccentricity, self.orientation, self.feret_min, self.feret_max,
+        ]
+    }
+}
+
+// Shape features of a cell from its outline and its rasterized pixels (for the ellipse fit).
+pub fn morphology(outline: &[(i32, i32)], pixels: &[(i32, i32)]) -> Morphology {
+    let hull = convex_hull(outline);
+    let area = polygon_area(outline);
+    let perimeter = convex_perimeter(outline);
+    shape(area, perimeter, &hull, pixels)
+}
+
+// Shape features of a region that has no single outline, like a cytoplasm or membrane ring.
+// Every pixel is taken as a unit square: the area is the number of pixels, the perimeter the
+// length of the square edges between the region and the rest of the image (holes included), and
+// the hull is the hull of the squares' corners.
+pub fn region_morphology(pixels: &[(i32, i32)]) -> Morphology {
+    let region: HashSet<(i32, i32)> = pixels.iter().copied().collect();
+    let exposed_edges = region.iter()
+        .map(|&(x, y)| [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)].iter().filter(|p| !region.contains(p)).count())
+        .sum::<usize>();
+    let corners: Vec<(i32, i32)> = region.iter()
+        .flat_map(|&(x, y)| [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)])
+        .collect();
+    shape(region.len() as f64, exposed_edges as f64, &convex_hull(&corners), pixels)
+}
+
+fn shape(area: f64, perimeter: f64, hull: &[(i32, i32)], pixels: &[(i32, i32)]) -> Morphology {
+    let hull_area = polygon_area(hull);
+    let hull_perimeter = convex_perimeter(hull);
+
+    // Ellipse fit from the central second moments of the cell's pixels
+    let (mut major_axis_length, mut minor_axis_length, mut eccentricity, mut orientation) = (0.0, 0.0, 0.0, 0.0);
+    if !pixels.is_empty() {
+        let n = pixels.len() as f64;
+        let mx = pixels.iter().map(|p| p.0 as f64).sum::<f64>() / n;
+        let my = pixels.iter().map(|p| p.1 as f64).sum::<f64>() / n;
+        // Each pixel is a unit square, which adds 1/12 to the variance along each axis
+        let mu20 = pixels.iter().map(|p| (p.0 as f64 - mx).powi(2)).sum::<f64>() / n + 1.0 / 12.0;
+        let mu02 = pixels.iter().map(|p| (p.1 as f64 - my).powi(2)).sum::<f64>() / n + 1.0 / 12.0;
+        let mu11 = pixels.iter().map(|p| (p.0 as f64 - mx) * (p.1 as f64 - my)).sum::<f64>() / n;
+
+        let common = ((mu20 - mu02).powi(2) + 4.0 * mu11 * mu11).sqrt();
+        let l1 = (mu20 + mu02 + common) / 2.0;
+        let l2 = ((mu20 + mu02 - common) / 2.0).max(0.0);
+        major_axis_length = 4.0 * l1.sqrt();
+        minor_axis_length = 4.0 * l2.sqrt();
+        eccentricity = if l1 > 0.0 { (1.0 - l2 / l1).sqrt() } else { 0.0 };
+        orientation = 0.5 * (2.0 * mu11).atan2(mu20 - mu02);
+    }
+
+    let (feret_min, feret_max) = feret_diameters(hull);
+
+    Morphology {
+        area,
+        perimeter,
+        hull_area,
+        hull_perimeter,
+        solidity: if hull_area > 0.0 { area / hull_area } else { 0.0 },
+        circularity: if perimeter > 0.0 { 4.0 * std::f64::consts::PI * area / (perimeter * perimeter) } else { 0.0 },
+        major_axis_length,
+        minor_axis_length,
+        eccentricity,
+        orientation,
+        feret_min,
+        feret_max,
+    }
+}
+
+// Minimum and maximum Feret diameters of a convex hull by rotating calipers: for every hull
+// edge, the farthest vertex from it gives the caliper width perpendicular to that edge, and
+// the antipodal vertex pairs met along the way include the diameter.
+pub fn feret_diameters(hull: &[(i32, i32)]) -> (f64, f64) {
+    let n = hull.len();
+    let dist = |a: (i32, i32), b: (i32, i32)| (distance_sq(a, b) as f64).sqrt();
+    match n {
+        0 | 1 => return (0.0, 0.0),
+        2 => return (0.0, dist(hull[0], hull[1])),
+        _ => {}
+    }
+
+    let twice_area = |a: (i32, i32), b: (i32, i32), c: (i32, i32)| (cross(a, b, c) as i64).abs();
+    let mut min_width = f64::INFINITY;
+    let mut max_diameter: f64 = 0.0;
+    let mut j = 1;
+    for i in 0..n {
+        let (a, b) = (hull[i], hull[(i + 1) % n]);
+        while twice_area(a, b, hull[(j + 1) % n]) > twice_area(a, b, hull[j]) {
+            j = (j + 1) % n;
+        }
+        let edge = dist(a, b);
+        if edge > 0.0 {
+            min_width = min_width.min(twice_area(a, b, hull[j]) as f64 / edge);
+        }
+        max_diameter = max_diameter.max(dist(a, hull[j])).max(dist(b, hull[j]));
+    }
+
+    (if min_width.is_finite() { min_width } else { 0.0 }, max_diameter)
+}
+
+// How polygon interiors are decided when an outline crosses itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FillRule {
+    EvenOdd,
+    NonZero,
+}
+
+impl FromStr for FillRule {
+    type Err = String;
 
-    for (i, row) in segmentation_matrix.iter().enumerate() {
-        for pixel in row {
-            segmentation_rgb[i].push(rgb_matrix[pixel.1 as usize][pixel.0 as usize].clone());
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "even-odd" | "evenodd" => Ok(FillRule::EvenOdd),
+            "nonzero" | "non-zero" => Ok(FillRule::NonZero),
+            _ => Err(format!("Unknown fill rule '{}', expected even-odd or nonzero", s)),
         }
     }
+}
 
-    segmentation_rgb
+// Scan-fills a cell outline and returns every pixel of the cell: the pixels whose centers lie
+// inside the polygon plus the outline pixels themselves, sorted by row then column.
+pub fn rasterize_polygon(outline: &[(i32, i32)], rule: FillRule) -> Vec<(i32, i32)> {
+    let mut pixels: Vec<(i32, i32)> = outline.to_vec();
+    let n = outline.len();
+    if n >= 3 {
+        let y_min = outline.iter().map(|p| p.1).min().unwrap_or(0);
+        let y_max = outline.iter().map(|p| p.1).max().unwrap_or(0);
+
+        for y in y_min..=y_max {
+            let yc = y as f64;
+            // (x, winding direction) of every edge crossing this scanline. Edges are half-open
+            // in y so shared vertices are only counted once.
+            let mut crossings: Vec<(f64, i32)> = Vec::new();
+            for i in 0..n {
+                let (x0, y0) = (outline[i].0 as f64, outline[i].1 as f64);
+                let (x1, y1) = (outline[(i + 1) % n].0 as f64, outline[(i + 1) % n].1 as f64);
+                if (y0 <= yc && yc < y1) || (y1 <= yc && yc < y0) {
+                    let x = x0 + (yc - y0) * (x1 - x0) / (y1 - y0);
+                    crossings.push((x, if y1 > y0 { 1 } else { -1 }));
+                }
+            }
+            crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+            let mut winding = 0;
+            for (j, &(x, direction)) in crossings.iter().enumerate() {
+                winding += direction;
+                let inside = match rule {
+                    FillRule::EvenOdd => (j + 1) % 2 == 1,
+                    FillRule::NonZero => winding != 0,
+                };
+                if inside && j + 1 < crossings.len() {
+                    let next_x = crossings[j + 1].0;
+                    for px in (x.ceil() as i32)..=(next_x.floor() as i32) {
+                        pixels.push((px, y));
+                    }
+                }
+            }
+        }
+    }
+
+    pixels.sort_by_key(|&(x, y)| (y, x));
+    pixels.dedup();
+    pixels
 }
 
-pub fn channel_mean(channels: &Vec<Vec<kmeans::Point>>) -> Vec<(f64, f64, f64)> {
-    let mut means: Vec<(f64, f64, f64)> = vec![(0.0, 0.0, 0.0); channels.len()];
-    for (i, row) in channels.iter().enumerate() {
-        for pixel in row {
-            means[i].0 += pixel.0 as f64;
-            means[i].1 += pixel.1 as f64;
-            means[i].2 += pixel.2 as f64;
+// Which pixels of a cell the intensity features are computed over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntensityRegion {
+    Interior,  // Every pixel of the rasterized cell
+    Boundary,  // Only the outline pixels
+}
+
+impl FromStr for IntensityRegion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interior" => Ok(IntensityRegion::Interior),
+            "boundary" => Ok(IntensityRegion::Boundary),
+            _ => Err(format!("Unknown intensity region '{}', expected interior or boundary", s)),
         }
+    }
+}
+
+// Intensities of the given pixels of every cell, as [cell][channel][pixel]. Pixels outside the
+// image are skipped.
+pub fn cell_intensities(image: &MultiChannelImage, cell_pixels: &[Vec<(i32, i32)>]) -> Vec<Vec<Vec<f64>>> {
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    let num_channels = image.channel_names.len();
+
+    cell_pixels.iter()
+        .map(|pixels| {
+            let inside: Vec<&(i32, i32)> = pixels.iter().filter(|p| p.0 >= 0 && p.1 >= 0 && p.0 < width && p.1 < height).collect();
+            (0..num_channels)
+                .map(|c| {
+                    let channel = image.channel(c);
+                    inside.iter().map(|&&(x, y)| channel[[y as usize, x as usize]] as f64).collect()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Distribution of one channel's intensities over the pixels of one cell.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IntensityProfile {
+    pub mean: f64,
+    pub std: f64,
+    pub median: f64,
+    pub p05: f64,
+    pub p25: f64,
+    pub p75: f64,
+    pub p95: f64,
+    pub min: f64,
+    pub max: f64,
+    pub integrated: f64,  // Sum over all pixels
+    pub skewness: f64,
+    pub kurtosis: f64,    // Excess kurtosis, 0 for a normal distribution
+}
+
+impl IntensityProfile {
+    pub const NAMES: [&'static str; 12] = [
+        "mean", "std", "median", "p05", "p25", "p75", "p95", "min", "max", "integrated", "skewness", "kurtosis",
+    ];
+
+    // Values in the same order as NAMES
+    pub fn values(&self) -> [f64; 12] {
+        [
+            self.mean, self.std, self.median, self.p05, self.p25, self.p75, self.p95,
+            self.min, self.max, self.integrated, self.skewness, self.kurtosis,
+        ]
+    }
+}
 
-        means[i] = (means[i].0 / row.len() as f64, means[i].1 / row.len() as f64, means[i].2 / row.len() as f64);
+// Summary statistics of a set of pixel intensities. An empty set gives all zeros.
+pub fn intensity_profile(values: &[f64]) -> IntensityProfile {
+    if values.is_empty() {
+        return IntensityProfile::default();
+    }
+    let n = values.len() as f64;
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+
+    let integrated: f64 = values.iter().sum();
+    let mean = integrated / n;
+    let central_moment = |k: i32| values.iter().map(|v| (v - mean).powi(k)).sum::<f64>() / n;
+    let (m2, m3, m4) = (central_moment(2), central_moment(3), central_moment(4));
+    let (skewness, kurtosis) = if m2 > 0.0 { (m3 / m2.powf(1.5), m4 / (m2 * m2) - 3.0) } else { (0.0, 0.0) };
+
+    IntensityProfile {
+        mean,
+        std: m2.sqrt(),
+        median: percentile(&sorted, 50.0),
+        p05: percentile(&sorted, 5.0),
+        p25: percentile(&sorted, 25.0),
+        p75: percentile(&sorted, 75.0),
+        p95: percentile(&sorted, 95.0),
+        min: sorted[0],
+        max: sorted[sorted.len() - 1],
+        integrated,
+        skewness,
+        kurtosis,
     }
+}
 
-    means
+// `q`th percentile of sorted values, linearly interpolating between the closest ranks.
+pub fn percentile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = q / 100.0 * (sorted.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lo] + (rank - lo as f64) * (sorted[hi] - sorted[lo])
+}
+
+// Intensity profile of every channel of every cell.
+pub fn channel_profiles(intensities: &[Vec<Vec<f64>>]) -> Vec<Vec<IntensityProfile>> {
+    intensities.iter()
+        .map(|channels| channels.iter().map(|values| intensity_profile(values)).collect())
+        .collect()
 }
 
 /*pub fn main() {
@@ -199,4 +458,21 @@ pub fn channel_mean(channels: &Vec<Vec<kmeans::Point>>) -> Vec<(f64, f64, f64)>
     }
 
     println!("{:?}", channel_mean(&features));
-}*/
\ No newline at end of file
+}*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rings_are_measured_on_their_pixels() {
+        // A 5x5 square with its centre pixel missing
+        let ring: Vec<(i32, i32)> = (0..5).flat_map(|y| (0..5).map(move |x| (x, y))).filter(|&p| p != (2, 2)).collect();
+        let shape = region_morphology(&ring);
+        assert_eq!(shape.area, 24.0);
+        // 20 edges around the outside and 4 around the hole
+        assert_eq!(shape.perimeter, 24.0);
+        assert_eq!(shape.hull_area, 25.0);
+        assert_eq!(shape.solidity, 24.0 / 25.0);
+        assert_eq!(shape.feret_min, 5.0);
+    }
+}
@@ -0,0 +1,141 @@
+/*
+Per-cell feature table: named feature columns keyed by a stable cell ID, and its CSV export.
+*/
+
+use crate::calibration::{Calibration, Quantity};
+
+use std::error::Error;
+
+#[derive(Debug, Clone, Default)]
+pub struct FeatureTable {
+    pub cell_ids: Vec<usize>,
+    pub names: Vec<String>,
+    pub columns: Vec<Vec<f64>>,  // columns[j][i] is feature `names[j]` of cell `cell_ids[i]`
+}
+
+impl FeatureTable {
+    pub fn new(cell_ids: Vec<usize>) -> FeatureTable {
+        FeatureTable { cell_ids, names: Vec::new(), columns: Vec::new() }
+    }
+
+    pub fn push(&mut self, name: &str, values: Vec<f64>) {
+        assert_eq!(values.len(), self.cell_ids.len(), "feature '{}' has the wrong number of cells", name);
+        self.names.push(name.to_string());
+        self.columns.push(values);
+    }
+
+    // Adds a feature measured in pixels, converted to the calibration's units and named after them
+    pub fn push_calibrated(&mut self, name: &str, quantity: Quantity, values: Vec<f64>, calibration: &Calibration) {
+        let values = values.into_iter().map(|v| calibration.convert(quantity, v)).collect();
+        self.push(&format!("{}{}", name, calibration.suffix(quantity)), values);
+    }
+
+    pub fn num_cells(&self) -> usize {
+        self.cell_ids.len()
+    }
+
+    // One row per cell, one column per feature
+    pub fn rows(&self) -> Vec<Vec<f64>> {
+        (0..self.num_cells())
+            .map(|i| self.columns.iter().map(|column| column[i]).collect())
+            .collect()
+    }
+}
+
+// Writes one row per cell: its ID, its batch if given, every raw feature, every normalized
+// (and batch-corrected) feature (suffixed `_normalized`) and the assigned cluster.
+pub fn write_feature_table(path: &str, table: &FeatureTable, batch: Option<&str>, normalized: &[Vec<f64>], labels: &[usize]) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_path(path)?;
+
+    let mut header = vec!["cell_id".to_string()];
+    header.extend(batch.map(|_| "batch".to_string()));
+    header.extend(table.names.iter().cloned());
+    header.extend(table.names.iter().map(|name| format!("{}_normalized", name)));
+    header.push("cluster".to_string());
+    writer.write_record(&header)?;
+
+    let raw = table.rows();
+    for (i, &id) in table.cell_ids.iter().enumerate() {
+        let mut record = vec![id.to_string()];
+        record.extend(batch.map(str::to_string));
+        record.extend(raw[i].iter().map(|v| v.to_string()));
+        record.extend(normalized[i].iter().map(|v| v.to_string()));
+        record.push(labels[i].to_string());
+        writer.write_record(&record)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+// Reads the named raw feature columns of a table written by `write_feature_table`, e.g. from
+// another image of the same batch.
+pub fn read_feature_columns(path: &str, names: &[String]) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let positions: Vec<usize> = names.iter()
+        .map(|name| headers.iter().position(|h| h == name).ok_or_else(|| format!("{} has no feature {}", path, name)))
+        .collect::<Result<_, _>>()?;
+
+    let mut columns = vec![Vec::new(); names.len()];
+    for (line, record) in reader.records().enumerate() {
+        let record = record?;
+        for (column, &p) in columns.iter_mut().zip(&positions) {
+            column.push(record[p].parse().map_err(|_| format!("{} line {}: '{}' is not a number", path, line + 2, &record[p]))?);
+        }
+    }
+    Ok(columns)
+}
+
+// Writes the cluster of every cell pooled from several images, with the table it came from and
+// its batch.
+pub fn write_pooled_clusters(path: &str, sources: &[String], cell_ids: &[String], batches: &[String], labels: &[usize]) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["source", "cell_id", "batch", "cluster"])?;
+    for i in 0..labels.len() {
+        writer.write_record([&sources[i], &cell_ids[i], &batches[i], &labels[i].to_string()])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Reads a text column such as `cell_id` or `batch` of a feature table, if it has one.
+pub fn read_text_column(path: &str, name: &str) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let Some(position) = reader.headers()?.iter().position(|h| h == name) else { return Ok(None) };
+    let mut values = Vec::new();
+    for record in reader.records() {
+        values.push(record?[position].to_string());
+    }
+    Ok(Some(values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_columns_read_back_by_name() {
+        let mut table = FeatureTable::new(vec![4, 9, 12]);
+        table.push("area", vec![10.0, 20.5, 30.0]);
+        table.push("eccentricity", vec![0.1, 0.2, 0.3]);
+        let normalized = vec![vec![0.0, 0.0], vec![0.5, 0.5], vec![1.0, 1.0]];
+
+        let path = std::env::temp_dir().join(format!("features-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_feature_table(path, &table, Some("plate1"), &normalized, &[1, 0, 1]).unwrap();
+        let names = ["eccentricity", "area_normalized", "area"].map(String::from);
+        let columns = read_feature_columns(path, &names);
+        let missing = read_feature_columns(path, &["perimeter".to_string()]);
+        let ids = read_text_column(path, "cell_id");
+        let batches = read_text_column(path, "batch");
+        let absent = read_text_column(path, "source");
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(columns.unwrap(), vec![vec![0.1, 0.2, 0.3], vec![0.0, 0.5, 1.0], vec![10.0, 20.5, 30.0]]);
+        assert!(missing.is_err());
+        assert_eq!(ids.unwrap(), Some(vec!["4".to_string(), "9".to_string(), "12".to_string()]));
+        assert_eq!(batches.unwrap(), Some(vec!["plate1".to_string(); 3]));
+        assert_eq!(absent.unwrap(), None);
+    }
+}
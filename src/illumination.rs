@@ -0,0 +1,325 @@
+/*
+Illumination correction before measuring: dark-frame and flat-field correction from reference
+images or from a flat field estimated over a batch of images, then rolling-ball or top-hat
+background subtraction. Without it vignetting dims the cells near the image edge.
+*/
+
+use crate::classical_segmentation::{gaussian_blur, squared_distance_1d};
+use crate::multichannel::{MultiChannelImage, PixelType};
+
+use ndarray::{Array1, Array2, Array3, Axis};
+use tiff::encoder::{colortype, TiffEncoder};
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::File;
+use std::str::FromStr;
+
+// Side, in blocks, of the shorter edge of the grid an estimated flat field is smoothed on
+const FLAT_FIELD_GRID: usize = 64;
+// Gaussian smoothing of that grid in blocks, leaving only slow changes like vignetting
+const FLAT_FIELD_SIGMA: f64 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Background {
+    None,
+    RollingBall,  // Intensity surface under a ball rolled beneath it, as in ImageJ
+    TopHat,       // Grayscale opening with a flat disk
+}
+
+impl FromStr for Background {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Background::None),
+            "rolling-ball" => Ok(Background::RollingBall),
+            "top-hat" => Ok(Background::TopHat),
+            _ => Err(format!("Unknown background method '{}', expected none, rolling-ball or top-hat", s)),
+        }
+    }
+}
+
+// Channels corrections apply to: all but alpha
+fn corrected_channels(image: &MultiChannelImage) -> Vec<usize> {
+    (0..image.channel_names.len()).filter(|&c| !image.is_alpha(c)).collect()
+}
+
+// The channel of a reference image that corrects channel `c` of `image`: the same channel, or
+// the only one of a single-channel reference.
+fn reference_channel(reference: &MultiChannelImage, image: &MultiChannelImage, c: usize, what: &str) -> Result<Array2<f64>, Box<dyn Error>> {
+    if (reference.width(), reference.height()) != (image.width(), image.height()) {
+        return Err(format!("The {} is {}x{} but the image is {}x{}", what,
+            reference.width(), reference.height(), image.width(), image.height()).into());
+    }
+    let channels = reference.channel_names.len();
+    let index = if channels == image.channel_names.len() {
+        c
+    } else if channels == 1 {
+        0
+    } else {
+        return Err(format!("The {} has {} channels but the image has {}", what, channels, image.channel_names.len()).into());
+    };
+    Ok(reference.channel(index).mapv(|v| v as f64))
+}
+
+// Subtracts the dark frame and divides by the flat field scaled to average 1, so
+// corrected = (raw - dark) / (flat - dark) * mean(flat - dark). Either reference may be left out.
+pub fn correct_flat_field(image: &mut MultiChannelImage, flat: Option<&MultiChannelImage>, dark: Option<&MultiChannelImage>) -> Result<(), Box<dyn Error>> {
+    for c in corrected_channels(image) {
+        let mut plane = image.channel(c).mapv(|v| v as f64);
+        let dark_plane = match dark {
+            Some(dark) => Some(reference_channel(dark, image, c, "dark frame")?),
+            None => None,
+        };
+        if let Some(dark_plane) = &dark_plane {
+            plane -= dark_plane;
+        }
+        if let Some(flat) = flat {
+            let mut gain = reference_channel(flat, image, c, "flat field")?;
+            if let Some(dark_plane) = &dark_plane {
+                gain -= dark_plane;
+            }
+            let mean = gain.mean().unwrap_or(0.0);
+            if mean <= 0.0 {
+                return Err(format!("Channel {} of the flat field is no brighter than the dark frame", c).into());
+            }
+            // Pixels the flat field says are (nearly) unlit are left as they are
+            plane.zip_mut_with(&gain, |v, &g| if g > 0.0 { *v *= mean / g });
+        }
+        image.set_channel(c, &plane);
+    }
+    Ok(())
+}
+
+// BaSiC-style flat field of a batch of images of the same size and channels: each image minus the
+// dark frame is divided by its mean, the pixelwise median over the batch is taken so cells that
+// only some images have at a pixel don't show, and the median is smoothed and scaled to average
+// 1. Unlike BaSiC there is no sparse/low-rank decomposition; the batch just needs to be large
+// enough that most pixels are background in most images. The result reads like a flat-field
+// reference: that profile times the batch's mean intensity, plus the dark frame.
+pub fn estimate_flat_field(images: &[MultiChannelImage], dark: Option<&MultiChannelImage>) -> Result<MultiChannelImage, Box<dyn Error>> {
+    let first = images.first().ok_or("No images to estimate a flat field from")?;
+    let (channels, height, width) = first.data.dim();
+    for image in images {
+        if image.data.dim() != (channels, height, width) {
+            return Err(format!("Flat field batch images differ in size or channels: {:?} and {:?}", first.data.dim(), image.data.dim()).into());
+        }
+    }
+
+    let mut flat = Array3::<f32>::ones((channels, height, width));
+    for c in corrected_channels(first) {
+        let dark_plane = match dark {
+            Some(dark) => reference_channel(dark, first, c, "dark frame")?,
+            None => Array2::zeros((height, width)),
+        };
+        let mut brightness = 0.0;
+        let normalized: Vec<Array2<f64>> = images.iter()
+            .map(|image| {
+                let plane = image.channel(c).mapv(|v| v as f64) - &dark_plane;
+                let mean = plane.mean().unwrap_or(0.0);
+                brightness += mean / images.len() as f64;
+                if mean > 0.0 { plane / mean } else { plane }
+            })
+            .collect();
+
+        let mut samples = vec![0.0; normalized.len()];
+        let median = Array2::from_shape_fn((height, width), |(y, x)| {
+            for (sample, plane) in samples.iter_mut().zip(&normalized) {
+                *sample = plane[[y, x]];
+            }
+            samples.sort_by(f64::total_cmp);
+            let mid = samples.len() / 2;
+            if samples.len() % 2 == 1 { samples[mid] } else { (samples[mid - 1] + samples[mid]) / 2.0 }
+        });
+
+        let smooth = smooth_profile(&median);
+        let mean = smooth.mean().unwrap_or(0.0);
+        if mean <= 0.0 {
+            return Err(format!("Channel {} of the flat field batch is empty", first.channel_names[c]).into());
+        }
+        let reference = smooth * (brightness / mean) + &dark_plane;
+        flat.index_axis_mut(Axis(0), c).assign(&reference.mapv(|v| v as f32));
+    }
+
+    Ok(MultiChannelImage { data: flat, channel_names: first.channel_names.clone(), pixel_type: PixelType::F32, derived_channels: 0, alpha: first.alpha })
+}
+
+// Block-averages `plane` onto a coarse grid, blurs it there and interpolates it back up bilinearly,
+// which is much cheaper than blurring a large image with a wide kernel.
+fn smooth_profile(plane: &Array2<f64>) -> Array2<f64> {
+    let (height, width) = plane.dim();
+    let block = height.min(width).div_ceil(FLAT_FIELD_GRID).max(1);
+    let (rows, cols) = (height.div_ceil(block), width.div_ceil(block));
+
+    let mut grid = Array2::<f64>::zeros((rows, cols));
+    let mut counts = Array2::<f64>::zeros((rows, cols));
+    for ((y, x), &v) in plane.indexed_iter() {
+        grid[[y / block, x / block]] += v;
+        counts[[y / block, x / block]] += 1.0;
+    }
+    grid /= &counts;
+    let grid = gaussian_blur(&grid, FLAT_FIELD_SIGMA);
+
+    // Block centres sit at (i + 0.5) * block - 0.5 in pixel coordinates
+    let coordinate = |p: usize, n: usize| {
+        let g = ((p as f64 + 0.5) / block as f64 - 0.5).clamp(0.0, (n - 1) as f64);
+        let i = (g.floor() as usize).min(n.saturating_sub(2));
+        (i, (i + 1).min(n - 1), g - i as f64)
+    };
+    Array2::from_shape_fn((height, width), |(y, x)| {
+        let (y0, y1, fy) = coordinate(y, rows);
+        let (x0, x1, fx) = coordinate(x, cols);
+        let top = grid[[y0, x0]] * (1.0 - fx) + grid[[y0, x1]] * fx;
+        let bottom = grid[[y1, x0]] * (1.0 - fx) + grid[[y1, x1]] * fx;
+        top * (1.0 - fy) + bottom * fy
+    })
+}
+
+// Writes the channels of a floating point image as pages of a 32-bit float TIFF, which
+// --flat-field can read back.
+pub fn write_float_tiff(path: &str, image: &MultiChannelImage) -> Result<(), Box<dyn Error>> {
+    let mut encoder = TiffEncoder::new(File::create(path)?)?;
+    for c in 0..image.channel_names.len() {
+        let samples: Vec<f32> = image.channel(c).iter().copied().collect();
+        encoder.write_image::<colortype::Gray32Float>(image.width() as u32, image.height() as u32, &samples)?;
+    }
+    Ok(())
+}
+
+// Subtracts the background estimated with a ball or disk of `radius` pixels from every channel
+// but alpha. Structures narrower than the radius, like cells, are kept.
+pub fn subtract_background(image: &mut MultiChannelImage, method: Background, radius: f64) {
+    if method == Background::None {
+        return;
+    }
+    for c in corrected_channels(image) {
+        let plane = image.channel(c).mapv(|v| v as f64);
+        let background = match method {
+            Background::RollingBall => rolling_ball_background(&plane, radius),
+            _ => flat_opening(&plane, radius.max(0.0) as usize),
+        };
+        image.set_channel(c, &(plane - background));
+    }
+}
+
+// Background under a ball of `radius` rolled beneath the intensity surface. The ball is replaced
+// by the paraboloid with the same curvature at its top (ImageJ's "sliding paraboloid"), which
+// makes the opening separable: erosion by h(d) = d^2 / 2r is a lower envelope of parabolas.
+pub fn rolling_ball_background(plane: &Array2<f64>, radius: f64) -> Array2<f64> {
+    let scale = 2.0 * radius.max(f64::EPSILON);
+    // min over q of f(q) + |p - q|^2 / scale, one axis at a time
+    let erode = |f: &Array2<f64>| {
+        let mut result = f * scale;
+        for axis in [Axis(0), Axis(1)] {
+            for mut lane in result.lanes_mut(axis) {
+                let transformed = squared_distance_1d(&lane.to_vec());
+                lane.assign(&Array1::from(transformed));
+            }
+        }
+        result / scale
+    };
+    // Dilation by the same paraboloid is erosion of the negated surface
+    -erode(&-erode(plane))
+}
+
+// Grayscale opening with a flat disk of `radius` pixels: the largest surface no disk-shaped
+// structure of the image rises above. Each row of the disk is a sliding minimum or maximum.
+pub fn flat_opening(plane: &Array2<f64>, radius: usize) -> Array2<f64> {
+    let eroded = disk_filter(plane, radius, f64::min);
+    disk_filter(&eroded, radius, f64::max)
+}
+
+// Minimum or maximum (`pick`) over the disk around every pixel, clipped at the image edge
+fn disk_filter(plane: &Array2<f64>, radius: usize, pick: fn(f64, f64) -> f64) -> Array2<f64> {
+    let (height, width) = plane.dim();
+    let r = radius as isize;
+    // Half width of the disk's row dy
+    let half_width = |dy: isize| (((r * r - dy * dy) as f64).sqrt().floor()) as usize;
+
+    // Each row filtered horizontally with every half width the disk uses
+    let mut row_filtered: Vec<Option<Array2<f64>>> = vec![None; radius + 1];
+    for dy in -r..=r {
+        let half = half_width(dy);
+        if row_filtered[half].is_none() {
+            let mut filtered = plane.clone();
+            for mut row in filtered.rows_mut() {
+                let values = sliding_extreme(&row.to_vec(), half, pick);
+                row.assign(&Array1::from(values));
+            }
+            row_filtered[half] = Some(filtered);
+        }
+    }
+
+    Array2::from_shape_fn((height, width), |(y, x)| {
+        (-r..=r)
+            .filter_map(|dy| {
+                let ny = y as isize + dy;
+                if ny < 0 || ny >= height as isize {
+                    return None;
+                }
+                row_filtered[half_width(dy)].as_ref().map(|filtered| filtered[[ny as usize, x]])
+            })
+            .reduce(pick)
+            .unwrap_or(plane[[y, x]])
+    })
+}
+
+// Minimum or maximum over the window of `half` values either side of every position, in linear
+// time with a monotonic queue of candidate indices
+fn sliding_extreme(values: &[f64], half: usize, pick: fn(f64, f64) -> f64) -> Vec<f64> {
+    let n = values.len();
+    let better = |a: f64, b: f64| pick(a, b) == a;
+    let mut queue: VecDeque<usize> = VecDeque::new();
+    let mut result = Vec::with_capacity(n);
+    let mut next = 0;
+    for i in 0..n {
+        // Take in everything up to the window's right edge
+        while next < n && next <= i + half {
+            while queue.back().is_some_and(|&b| better(values[next], values[b])) {
+                queue.pop_back();
+            }
+            queue.push_back(next);
+            next += 1;
+        }
+        while queue.front().is_some_and(|&f| f + half < i) {
+            queue.pop_front();
+        }
+        result.push(values[queue[0]]);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn float_image(value: impl Fn(usize, usize) -> f32) -> MultiChannelImage {
+        MultiChannelImage {
+            data: Array3::from_shape_fn((1, 30, 40), |(_, y, x)| value(x, y)),
+            channel_names: vec!["gray".to_string()],
+            pixel_type: PixelType::F32,
+            derived_channels: 0,
+            alpha: None,
+        }
+    }
+
+    #[test]
+    fn rolling_ball_removes_a_sloping_background() {
+        let mut image = float_image(|x, y| 50.0 + 0.5 * x as f32 + 0.25 * y as f32);
+        subtract_background(&mut image, Background::RollingBall, 10.0);
+        // The ball can't reach under the edge pixels, so only the interior is flattened exactly
+        let corrected = image.channel(0);
+        assert!(corrected.iter().all(|&v| v >= -1e-3));
+        assert!(corrected.slice(ndarray::s![10..20, 10..30]).iter().all(|v| v.abs() < 1e-3));
+    }
+
+    #[test]
+    fn flat_field_of_the_image_itself_leaves_a_constant() {
+        let mut image = float_image(|x, y| 20.0 + ((x * 7 + y * 3) % 11) as f32);
+        let flat = image.clone();
+        correct_flat_field(&mut image, Some(&flat), None).unwrap();
+        let first = image.channel(0)[[0, 0]];
+        assert!(image.channel(0).iter().all(|v| (v - first).abs() < 1e-3));
+    }
+}
@@ -0,0 +1,271 @@
+/*
+Image-level normalisation to a reference image, so differences in staining or exposure between
+slides don't shift every intensity feature: Reinhard colour transfer in CIE L*a*b*, Macenko stain
+normalisation and per-channel histogram matching. Each reports the parameters it matched.
+*/
+
+use crate::extract_features::percentile;
+use crate::multichannel::MultiChannelImage;
+use crate::stains::{colour_channels, deconvolve, macenko, optical_density, white_level};
+
+use ndarray::{Array2, Array3, Axis};
+
+use std::error::Error;
+use std::str::FromStr;
+
+// Percentiles of each channel the histogram matching reports
+const REPORTED_PERCENTILES: [f64; 7] = [1.0, 5.0, 25.0, 50.0, 75.0, 95.0, 99.0];
+// Percentile of each stain's density Macenko normalisation matches, as a robust maximum
+const MACENKO_MAX_PERCENTILE: f64 = 99.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageNormalization {
+    Reinhard,   // Mean and standard deviation of L*, a* and b*
+    Macenko,    // Stain vectors and maximum densities
+    Histogram,  // Whole intensity distribution of every channel
+}
+
+impl FromStr for ImageNormalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reinhard" => Ok(ImageNormalization::Reinhard),
+            "macenko" => Ok(ImageNormalization::Macenko),
+            "histogram" => Ok(ImageNormalization::Histogram),
+            _ => Err(format!("Unknown image normalisation '{}', expected reinhard, macenko or histogram", s)),
+        }
+    }
+}
+
+// One matched quantity: its value in the image before normalisation and in the reference
+#[derive(Debug, Clone)]
+pub struct NormalizationParameter {
+    pub parameter: String,
+    pub channel: String,
+    pub source: f64,
+    pub reference: f64,
+}
+
+// Normalises `image` to `reference` in place and returns the parameters it matched
+pub fn normalize_image(image: &mut MultiChannelImage, reference: &MultiChannelImage, method: ImageNormalization) -> Result<Vec<NormalizationParameter>, Box<dyn Error>> {
+    match method {
+        ImageNormalization::Reinhard => reinhard(image, reference),
+        ImageNormalization::Macenko => macenko_normalize(image, reference),
+        ImageNormalization::Histogram => match_histograms(image, reference),
+    }
+}
+
+// sRGB (components in [0, 1]) to CIE L*a*b* under D65
+fn rgb_to_lab(rgb: [f64; 3]) -> [f64; 3] {
+    let linear = rgb.map(|v| if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) });
+    let [r, g, b] = linear;
+    let xyz = [
+        (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047,
+        0.2126729 * r + 0.7151522 * g + 0.0721750 * b,
+        (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883,
+    ];
+    let f = xyz.map(|t| if t > 216.0 / 24389.0 { t.cbrt() } else { (24389.0 / 27.0 * t + 16.0) / 116.0 });
+    [116.0 * f[1] - 16.0, 500.0 * (f[0] - f[1]), 200.0 * (f[1] - f[2])]
+}
+
+// CIE L*a*b* under D65 back to sRGB, not clipped
+fn lab_to_rgb(lab: [f64; 3]) -> [f64; 3] {
+    let fy = (lab[0] + 16.0) / 116.0;
+    let f = [fy + lab[1] / 500.0, fy, fy - lab[2] / 200.0];
+    let t = f.map(|f| if f.powi(3) > 216.0 / 24389.0 { f.powi(3) } else { (116.0 * f - 16.0) * 27.0 / 24389.0 });
+    let (x, y, z) = (t[0] * 0.95047, t[1], t[2] * 1.08883);
+    let linear = [
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.9692660 * x + 1.8760108 * y + 0.0415560 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    ];
+    linear.map(|v| if v <= 0.0031308 { 12.92 * v } else { 1.055 * v.max(0.0).powf(1.0 / 2.4) - 0.055 })
+}
+
+// L*a*b* of every pixel, (channel, y, x), from the colour channels scaled by the white level
+fn lab_planes(image: &MultiChannelImage) -> Result<Array3<f64>, Box<dyn Error>> {
+    let colour = colour_channels(image)?;
+    let white = white_level(image)?;
+    let mut lab = Array3::<f64>::zeros((3, image.height(), image.width()));
+    for ((y, x), _) in image.channel(colour[0]).indexed_iter() {
+        let rgb: [f64; 3] = std::array::from_fn(|i| image.channel(colour[i])[[y, x]] as f64 / white);
+        for (i, v) in rgb_to_lab(rgb).into_iter().enumerate() {
+            lab[[i, y, x]] = v;
+        }
+    }
+    Ok(lab)
+}
+
+fn mean_std(plane: ndarray::ArrayView2<f64>) -> (f64, f64) {
+    let mean = plane.mean().unwrap_or(0.0);
+    let variance = plane.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / plane.len().max(1) as f64;
+    (mean, variance.sqrt())
+}
+
+// Reinhard et al. (2001) colour transfer: every L*a*b* channel is shifted and scaled to the
+// reference's mean and standard deviation.
+fn reinhard(image: &mut MultiChannelImage, reference: &MultiChannelImage) -> Result<Vec<NormalizationParameter>, Box<dyn Error>> {
+    let mut lab = lab_planes(image)?;
+    let reference_lab = lab_planes(reference)?;
+
+    let mut parameters = Vec::new();
+    for (i, name) in ["L", "a", "b"].iter().enumerate() {
+        let (mean, std) = mean_std(lab.index_axis(Axis(0), i));
+        let (reference_mean, reference_std) = mean_std(reference_lab.index_axis(Axis(0), i));
+        let scale = if std > 0.0 { reference_std / std } else { 1.0 };
+        lab.index_axis_mut(Axis(0), i).mapv_inplace(|v| (v - mean) * scale + reference_mean);
+        for (parameter, source, target) in [("mean", mean, reference_mean), ("std", std, reference_std)] {
+            parameters.push(NormalizationParameter { parameter: parameter.to_string(), channel: name.to_string(), source, reference: target });
+        }
+    }
+
+    let colour = colour_channels(image)?;
+    let white = white_level(image)?;
+    let (height, width) = (image.height(), image.width());
+    let mut rgb = Array3::<f64>::zeros((3, height, width));
+    for y in 0..height {
+        for x in 0..width {
+            let values = lab_to_rgb([lab[[0, y, x]], lab[[1, y, x]], lab[[2, y, x]]]);
+            for (i, v) in values.into_iter().enumerate() {
+                rgb[[i, y, x]] = v.clamp(0.0, 1.0) * white;
+            }
+        }
+    }
+    for (i, &c) in colour.iter().enumerate() {
+        image.set_channel(c, &rgb.index_axis(Axis(0), i).to_owned());
+    }
+    Ok(parameters)
+}
+
+// Macenko et al. (2009) normalisation: the image is unmixed with its own hematoxylin and eosin
+// vectors, each stain's densities are scaled so their robust maximum matches the reference's,
+// and the image is rebuilt with the reference's vectors.
+fn macenko_normalize(image: &mut MultiChannelImage, reference: &MultiChannelImage) -> Result<Vec<NormalizationParameter>, Box<dyn Error>> {
+    let od = optical_density(image)?;
+    let reference_od = optical_density(reference)?;
+    let matrix = macenko(&od)?;
+    let reference_matrix = macenko(&reference_od)?;
+    let densities = deconvolve(&od, &matrix)?;
+    let reference_densities = deconvolve(&reference_od, &reference_matrix)?;
+
+    let robust_max = |plane: &Array2<f64>| {
+        let mut values: Vec<f64> = plane.iter().copied().collect();
+        values.sort_by(f64::total_cmp);
+        percentile(&values, MACENKO_MAX_PERCENTILE)
+    };
+
+    let mut parameters = Vec::new();
+    let (height, width) = (image.height(), image.width());
+    let mut target_od = Array3::<f64>::zeros((3, height, width));
+    for (s, name) in matrix.names.iter().enumerate() {
+        let (max, reference_max) = (robust_max(&densities[s]), robust_max(&reference_densities[s]));
+        let scale = if max > 0.0 { reference_max / max } else { 1.0 };
+        for i in 0..3 {
+            target_od.index_axis_mut(Axis(0), i).scaled_add(scale * reference_matrix.vectors[s][i], &densities[s]);
+        }
+        for (i, component) in ["r", "g", "b"].iter().enumerate() {
+            parameters.push(NormalizationParameter {
+                parameter: format!("vector_{}", component),
+                channel: name.clone(),
+                source: matrix.vectors[s][i],
+                reference: reference_matrix.vectors[s][i],
+            });
+        }
+        parameters.push(NormalizationParameter { parameter: "max_density".to_string(), channel: name.clone(), source: max, reference: reference_max });
+    }
+
+    let colour = colour_channels(image)?;
+    let white = white_level(image)?;
+    for (i, &c) in colour.iter().enumerate() {
+        let plane = target_od.index_axis(Axis(0), i).mapv(|d| white * 10f64.powf(-d));
+        image.set_channel(c, &plane);
+    }
+    Ok(parameters)
+}
+
+// Maps every channel's intensities through its cumulative distribution onto the reference
+// channel's, so both have the same histogram. Channels are paired by position; alpha and derived
+// channels are left alone.
+fn match_histograms(image: &mut MultiChannelImage, reference: &MultiChannelImage) -> Result<Vec<NormalizationParameter>, Box<dyn Error>> {
+    let measured = image.channel_names.len() - image.derived_channels;
+    let reference_measured = reference.channel_names.len() - reference.derived_channels;
+    if measured != reference_measured {
+        return Err(format!("The image has {} channels but the reference has {}", measured, reference_measured).into());
+    }
+
+    let mut parameters = Vec::new();
+    let channels: Vec<usize> = (0..measured).filter(|&c| !image.is_alpha(c)).collect();
+    for c in channels {
+        let plane = image.channel(c).mapv(|v| v as f64);
+        let (values, quantiles) = cumulative_distribution(plane.iter().copied());
+        let (reference_values, reference_quantiles) = cumulative_distribution(reference.channel(c).iter().map(|&v| v as f64));
+
+        let mut sorted: Vec<f64> = plane.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        let mut reference_sorted: Vec<f64> = reference.channel(c).iter().map(|&v| v as f64).collect();
+        reference_sorted.sort_by(f64::total_cmp);
+        for p in REPORTED_PERCENTILES {
+            parameters.push(NormalizationParameter {
+                parameter: format!("p{}", p),
+                channel: image.channel_names[c].clone(),
+                source: percentile(&sorted, p),
+                reference: percentile(&reference_sorted, p),
+            });
+        }
+
+        let matched = plane.mapv(|v| {
+            // Every pixel value is one of `values`
+            let i = values.partition_point(|&u| u < v).min(values.len() - 1);
+            interpolate(&reference_quantiles, &reference_values, quantiles[i])
+        });
+        image.set_channel(c, &matched);
+    }
+    Ok(parameters)
+}
+
+// Distinct values in increasing order with the fraction of samples at or below each, placed
+// halfway through the value's own step so a constant image maps to the reference median
+fn cumulative_distribution(samples: impl Iterator<Item = f64>) -> (Vec<f64>, Vec<f64>) {
+    let mut sorted: Vec<f64> = samples.collect();
+    sorted.sort_by(f64::total_cmp);
+    let n = sorted.len() as f64;
+    let (mut values, mut quantiles) = (Vec::new(), Vec::new());
+    let mut start = 0;
+    while start < sorted.len() {
+        let end = start + sorted[start..].partition_point(|&v| v == sorted[start]);
+        values.push(sorted[start]);
+        quantiles.push((start + end) as f64 / 2.0 / n);
+        start = end;
+    }
+    (values, quantiles)
+}
+
+// Piecewise linear y(x) through increasing `xs`, held constant beyond the ends
+fn interpolate(xs: &[f64], ys: &[f64], x: f64) -> f64 {
+    let i = xs.partition_point(|&v| v < x);
+    if i == 0 {
+        return ys[0];
+    }
+    if i == xs.len() {
+        return ys[xs.len() - 1];
+    }
+    let t = (x - xs[i - 1]) / (xs[i] - xs[i - 1]);
+    ys[i - 1] + t * (ys[i] - ys[i - 1])
+}
+
+// Writes the matched parameters in long format, one row per parameter and channel
+pub fn write_normalization_table(path: &str, method: ImageNormalization, parameters: &[NormalizationParameter]) -> Result<(), Box<dyn Error>> {
+    let method = match method {
+        ImageNormalization::Reinhard => "reinhard",
+        ImageNormalization::Macenko => "macenko",
+        ImageNormalization::Histogram => "histogram",
+    };
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["method", "parameter", "channel", "source", "reference"])?;
+    for p in parameters {
+        writer.write_record([method, &p.parameter, &p.channel, &p.source.to_string(), &p.reference.to_string()])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
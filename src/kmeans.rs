@@ -1,133 +1,241 @@
-use image::{ImageReader, RgbImage, Rgb};
+use image::{RgbImage, Rgb};
 use rand::Rng;
+use rand::rngs::StdRng;
 
-const MAX_ITERATIONS: i32 = 1000;
+use std::str::FromStr;
 
-#[derive(Debug, Clone)]
-pub struct Point(pub u8, pub u8, pub u8);
+const MAX_ITERATIONS: i32 = 1000;
 
-impl Point {
-    pub fn sum(&self) -> i32 {
-        (self.0 as i32) + (self.1 as i32) + (self.2 as i32)
-    }
+pub fn euclidean_distance(p: &[f64], q: &[f64]) -> f64 {
+    p.iter()
+        .zip(q.iter())
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f64>()
+        .sqrt()
 }
 
-impl From<[u8; 3]> for Point {
-    fn from(arr: [u8; 3]) -> Self {
-        Point(arr[0], arr[1], arr[2])
-    }
-}
-struct Image {
-    x: usize,
-    y: usize,
-    img: Vec<Vec<Point>>,
+// How the starting centroids are chosen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Init {
+    // k-means++: each new centroid is a cell drawn with probability proportional to its
+    // squared distance from the nearest centroid chosen so far.
+    KMeansPlusPlus,
+    // `k` distinct cells drawn uniformly at random.
+    RandomSubset,
 }
 
-pub fn load_image_as_matrix(path: &str) -> Vec<Vec<Point>> {
-    let img = ImageReader::open(path)
-        .expect("Failed to open image")
-        .decode()
-        .expect("Failed to decode image")
-        .to_rgb8();
-
-    let (width, height) = img.dimensions();
-    let mut matrix = vec![vec![Point(0, 0, 0); width as usize]; height as usize];
+impl FromStr for Init {
+    type Err = String;
 
-    //let pix: &image::Rgb<u8>;
-
-    for (x, y, pixel) in img.enumerate_pixels() {
-        matrix[y as usize][x as usize] = Point::from(pixel.0);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kmeans++" | "k-means++" => Ok(Init::KMeansPlusPlus),
+            "random" => Ok(Init::RandomSubset),
+            _ => Err(format!("Unknown initialization '{}', expected kmeans++ or random", s)),
+        }
     }
-
-    matrix
 }
 
-
-fn euclidean_distance(p: &Vec<f64>, q: &Vec<f64>) -> f64 {
-    p.iter()
-        .zip(q.iter())
-        .map(|(a, b)| (a - b).powi(2))
-        .sum::<f64>()
-        .sqrt()
-}
-
-pub fn random_point(v: &Vec<Vec<f64>>) -> (usize, usize) {
-    let mut rng = rand::thread_rng();
-    let y = rng.gen_range(0..v.len());
-    let x = rng.gen_range(0..v[0].len());
-    (x, y)
+// Picks exactly `k` starting centroids (fewer only if there are fewer than `k` cells).
+// All randomness comes from `rng`, so the same seed always gives the same centroids.
+pub fn initialize_centroids(features: &[Vec<f64>], k: usize, init: Init, rng: &mut StdRng) -> Vec<Vec<f64>> {
+    let k = k.min(features.len());
+    match init {
+        Init::RandomSubset => rand::seq::index::sample(rng, features.len(), k)
+            .iter()
+            .map(|i| features[i].clone())
+            .collect(),
+        Init::KMeansPlusPlus => kmeans_plus_plus(features, k, rng),
+    }
 }
 
-pub fn initialize_centroids(v: &Vec<Vec<f64>>) -> Vec<(usize, usize)> {
-    let mut centroids = Vec::new();
+fn kmeans_plus_plus(features: &[Vec<f64>], k: usize, rng: &mut StdRng) -> Vec<Vec<f64>> {
+    let mut centroids: Vec<Vec<f64>> = Vec::with_capacity(k);
+    if k == 0 {
+        return centroids;
+    }
+    centroids.push(features[rng.random_range(0..features.len())].clone());
+
+    // Squared distance from every cell to its nearest chosen centroid
+    let mut min_dist_sq: Vec<f64> = features.iter()
+        .map(|cell| euclidean_distance(cell, &centroids[0]).powi(2))
+        .collect();
+
+    while centroids.len() < k {
+        let total: f64 = min_dist_sq.iter().sum();
+        let next = if total > 0.0 {
+            let mut target = rng.random::<f64>() * total;
+            let mut chosen = min_dist_sq.iter().rposition(|&d| d > 0.0).unwrap_or(0);
+            for (i, &d) in min_dist_sq.iter().enumerate() {
+                if d > 0.0 && target < d {
+                    chosen = i;
+                    break;
+                }
+                target -= d;
+            }
+            chosen
+        } else {
+            // Every remaining cell coincides with a centroid; any choice is as good as another
+            rng.random_range(0..features.len())
+        };
 
-    for i in 0..v.len() {
-        centroids.push(random_point(v));
+        let centroid = features[next].clone();
+        for (d, cell) in min_dist_sq.iter_mut().zip(features) {
+            *d = d.min(euclidean_distance(cell, &centroid).powi(2));
+        }
+        centroids.push(centroid);
     }
 
     centroids
 }
 
-pub fn get_labels(img: &Vec<Vec<f64>>, centroids: &[(usize, usize)]) -> (Vec<Vec<usize>>, Vec<Vec<(usize, usize)>>) {
-    let mut labels = vec![vec![0; img[0].len()]; img.len()];
-    let mut by_label: Vec<Vec<(usize, usize)>> = vec![Vec::new(); centroids.len()];
-
-    for (y, row) in img.iter().enumerate() {
-        for (x, pixel) in row.iter().enumerate() {
-            let mut min_dist = usize::MAX;
+// Assigns every cell (row of `features`) to its nearest centroid in feature space.
+pub fn get_labels(features: &[Vec<f64>], centroids: &[Vec<f64>]) -> Vec<usize> {
+    features.iter()
+        .map(|cell| {
+            let mut min_dist = f64::INFINITY;
             let mut label = 0;
-            for (i, &(cx, cy)) in centroids.iter().enumerate() {
-                let dy = y as isize - cy as isize;
-                let dx = x as isize - cx as isize;
-                let dist = ((dy * dy + dx * dx) as usize).isqrt();
+            for (i, centroid) in centroids.iter().enumerate() {
+                let dist = euclidean_distance(cell, centroid);
                 if dist < min_dist {
                     min_dist = dist;
                     label = i;
                 }
             }
+            label
+        })
+        .collect()
+}
 
-            by_label[label].push((x, y));
-            labels[y][x] = label;
+// Mean feature vector of every cluster. A cluster that lost all of its cells keeps its old centroid.
+pub fn get_centroids(features: &[Vec<f64>], labels: &[usize], old_centroids: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let num_features = features.first().map_or(0, |f| f.len());
+    let mut sums = vec![vec![0.0; num_features]; old_centroids.len()];
+    let mut counts = vec![0usize; old_centroids.len()];
+
+    for (cell, &label) in features.iter().zip(labels) {
+        for (sum, value) in sums[label].iter_mut().zip(cell) {
+            *sum += value;
         }
+        counts[label] += 1;
     }
 
-    (labels, by_label)
+    sums.into_iter()
+        .zip(counts)
+        .enumerate()
+        .map(|(i, (sum, count))| {
+            if count == 0 {
+                old_centroids[i].clone()
+            } else {
+                sum.iter().map(|s| s / count as f64).collect()
+            }
+        })
+        .collect()
 }
 
-impl Image {
-    pub fn filter_image(&mut self) {
-        for row in &mut self.img {
-            for pixel in row {
-                if pixel.sum() < 80 {
-                    *pixel = Point(0, 0, 0);
-                }
-            }
-        }
+// Stops once the centroids have moved less than `tolerance` between iterations, or after
+// MAX_ITERATIONS. Returns whether the run converged (as opposed to hitting the iteration cap).
+pub fn should_stop(old_centroids: &[Vec<f64>], centroids: &[Vec<f64>], iterations: i32, tolerance: f64) -> Option<bool> {
+    debug_assert_eq!(old_centroids.len(), centroids.len());
+    let shift: f64 = old_centroids.iter()
+        .zip(centroids)
+        .map(|(old, new)| euclidean_distance(old, new).powi(2))
+        .sum();
+    if shift <= tolerance {
+        return Some(true);
     }
+    if iterations >= MAX_ITERATIONS { return Some(false) };
+    None
 }
 
-pub fn get_centroids(labels: &Vec<Vec<(usize, usize)>>) -> Vec<(usize, usize)> {
-    let mut centroids: Vec<(usize, usize)> = Vec::new();
+// Within-cluster sum of squared distances from every cell to its cluster centroid.
+pub fn inertia(features: &[Vec<f64>], labels: &[usize], centroids: &[Vec<f64>]) -> f64 {
+    features.iter()
+        .zip(labels)
+        .map(|(cell, &label)| euclidean_distance(cell, &centroids[label]).powi(2))
+        .sum()
+}
 
-    for row in labels.iter() {
-        let mut centroid: (usize, usize)  = (0, 0);
+// Turns a relative tolerance into an absolute one by scaling it with the mean per-feature
+// variance, so the stopping rule doesn't depend on how the features were scaled.
+fn absolute_tolerance(features: &[Vec<f64>], tol: f64) -> f64 {
+    let num_features = features.first().map_or(0, |f| f.len());
+    if num_features == 0 {
+        return 0.0;
+    }
+    let n = features.len() as f64;
+    let mean_variance = (0..num_features)
+        .map(|j| {
+            let mean = features.iter().map(|cell| cell[j]).sum::<f64>() / n;
+            features.iter().map(|cell| (cell[j] - mean).powi(2)).sum::<f64>() / n
+        })
+        .sum::<f64>() / num_features as f64;
+    tol * mean_variance
+}
 
-        for point in row.iter() {
-            centroid.0 += point.0;
-            centroid.1 += point.1;
-            }
+#[derive(Debug, Clone)]
+pub struct KMeansResult {
+    pub labels: Vec<usize>,          // Cluster of every cell
+    pub centroids: Vec<Vec<f64>>,    // Mean feature vector of every cluster
+    pub inertia: f64,                // Within-cluster sum of squares
+    pub iterations: i32,
+    pub converged: bool,             // False if the run stopped at MAX_ITERATIONS
+}
 
-        centroid.0 /= if row.len() != 0 {row.len()} else {1};
-        centroid.1 /= if row.len() != 0 {row.len()} else {1};
-        centroids.push(centroid);
+// Runs k-means `n_init` times from different starting centroids and keeps the run with the
+// lowest inertia. `tol` is relative to the mean feature variance.
+pub fn fit(features: &[Vec<f64>], k: usize, init: Init, n_init: usize, tol: f64, rng: &mut StdRng) -> KMeansResult {
+    assert!(k >= 1, "k-means needs at least one cluster");
+    let tolerance = absolute_tolerance(features, tol);
+    let mut best: Option<KMeansResult> = None;
+
+    for _ in 0..n_init.max(1) {
+        let result = fit_once(features, k, init, tolerance, rng);
+        if best.as_ref().is_none_or(|b| result.inertia < b.inertia) {
+            best = Some(result);
+        }
     }
 
-    centroids
+    best.expect("at least one k-means run")
+}
+
+fn fit_once(features: &[Vec<f64>], k: usize, init: Init, tolerance: f64, rng: &mut StdRng) -> KMeansResult {
+    let mut iterations = 0;
+    let mut centroids = initialize_centroids(features, k, init, rng);
+    let mut labels: Vec<usize> = vec![0; features.len()];
+    let converged = loop {
+        iterations += 1;
+
+        labels = get_labels(features, &centroids);
+
+        let old_centroids = centroids;
+        centroids = get_centroids(features, &labels, &old_centroids);
+        if let Some(converged) = should_stop(&old_centroids, &centroids, iterations, tolerance) {
+            break converged;
+        }
+    };
+
+    // Make the labels agree with the final centroids
+    labels = get_labels(features, &centroids);
+    let inertia = inertia(features, &labels, &centroids);
+
+    KMeansResult { labels, centroids, inertia, iterations, converged }
 }
 
-pub fn should_stop(old_centroids: &Vec<(usize, usize)>, centroids: &Vec<(usize, usize)>, iterations: i32) -> bool {
-    if iterations > MAX_ITERATIONS { return true };
-    old_centroids == centroids
+// Per-pixel cluster map for visualization: every pixel of a cell gets its cell's label,
+// everything else is background (`None`).
+pub fn cell_label_matrix(cell_pixels: &[Vec<(i32, i32)>], labels: &[usize], width: usize, height: usize) -> Vec<Vec<Option<usize>>> {
+    let mut matrix = vec![vec![None; width]; height];
+
+    for (cell, &label) in cell_pixels.iter().zip(labels) {
+        for &(x, y) in cell {
+            if x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height {
+                matrix[y as usize][x as usize] = Some(label);
+            }
+        }
+    }
+
+    matrix
 }
 
 pub fn label_colors(num_colors: usize) -> Vec<Rgb<u8>> {
@@ -171,11 +279,11 @@ pub fn label_colors(num_colors: usize) -> Vec<Rgb<u8>> {
 }
 
 // Saves the clustered image.
-// `final_labels_matrix[y][x]` contains the cluster index for pixel (x,y).
+// `final_labels_matrix[y][x]` contains the cluster index for pixel (x,y), or `None` for background.
 // `img_width` and `img_height` are the dimensions of the image.
 pub fn save_clustered_image(
-    final_labels_matrix: &Vec<Vec<usize>>,
-    cluster_colors: &Vec<Rgb<u8>>,
+    final_labels_matrix: &[Vec<Option<usize>>],
+    cluster_colors: &[Rgb<u8>],
     img_width: u32,
     img_height: u32,
     output_path: &str,
@@ -186,8 +294,10 @@ pub fn save_clustered_image(
         for x_idx in 0..img_width {
             // Check bounds for labels_matrix access
             if (y_idx as usize) < final_labels_matrix.len() && (x_idx as usize) < final_labels_matrix[y_idx as usize].len() {
-                let label_idx = final_labels_matrix[y_idx as usize][x_idx as usize];
-                
+                let Some(label_idx) = final_labels_matrix[y_idx as usize][x_idx as usize] else {
+                    continue; // Background stays black
+                };
+
                 if label_idx < cluster_colors.len() {
                     let color = cluster_colors[label_idx];
                     output_image.put_pixel(x_idx, y_idx, color);
@@ -269,3 +379,30 @@ pub fn save_clustered_image(
         "/Users/sam/dev/kmeans/src/clustered.png" // Your desired output path.
     );
 }*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn the_same_seed_gives_the_same_clustering() {
+        let mut noise = StdRng::seed_from_u64(3);
+        let features: Vec<Vec<f64>> = (0..90)
+            .map(|i| {
+                let centre = [0.0, 5.0, 10.0][i % 3];
+                vec![centre + noise.random_range(-1.0..1.0), centre + noise.random_range(-1.0..1.0)]
+            })
+            .collect();
+
+        let first = fit(&features, 3, Init::KMeansPlusPlus, 4, 1e-4, &mut StdRng::seed_from_u64(42));
+        let second = fit(&features, 3, Init::KMeansPlusPlus, 4, 1e-4, &mut StdRng::seed_from_u64(42));
+        assert_eq!(first.labels, second.labels);
+        assert_eq!(first.centroids, second.centroids);
+        assert_eq!(first.inertia, second.inertia);
+        // The three blobs are found
+        for i in 0..features.len() {
+            assert_eq!(first.labels[i], first.labels[i % 3]);
+        }
+    }
+}
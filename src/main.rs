@@ -1,111 +1,677 @@
-use kmeans::initialize_centroids;
+use calibration::{Calibration, Quantity, Units};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 
+use std::error::Error;
+use std::path::Path;
+
+mod batch_correction;
+mod calibration;
+mod classical_segmentation;
+mod compartments;
+mod config;
 mod extract_features;
+mod feature_table;
+mod illumination;
+mod image_normalization;
 mod kmeans;
+mod model_selection;
+mod moments;
+mod multichannel;
+mod neighbours;
+mod normalization;
+mod npy;
+mod panel;
+mod segmentation;
+mod stains;
+mod spatial_statistics;
+mod texture;
+mod voronoi;
 
-const MAX_ITERATIONS: i32 = 1000;
+// Fits the feature normalisation as configured: on this image's cells, on them and the cells of
+// other tables of the batch, or from saved parameters.
+fn fit_normalization(table: &feature_table::FeatureTable, config: &config::Config) -> Result<Vec<normalization::FeatureNormalizer>, Box<dyn Error>> {
+    let normalizers = match &config.normalization_params_path {
+        Some(path) => normalization::read_parameters(path)?,
+        None => {
+            let mut images = vec![table.columns.clone()];
+            for path in &config.fit_tables {
+                images.push(feature_table::read_feature_columns(path, &table.names)?);
+            }
+            if !config.fit_tables.is_empty() {
+                let cells: usize = images.iter().map(|columns| columns.first().map_or(0, |c| c.len())).sum();
+                println!("Fitted normalisation over {} cells of {} images", cells, images.len());
+            }
+            normalization::fit_normalizers(&table.names, &images, &config.normalization, config.clip_quantiles)
+        }
+    };
+    if let Some(path) = &config.save_normalization_params_path {
+        match normalization::write_parameters(path, &normalizers) {
+            Ok(_) => println!("Normalisation parameters saved as {}", path),
+            Err(e) => eprintln!("Error: Failed to save normalisation parameters to {}: {}", path, e),
+        }
+    }
+    Ok(normalizers)
+}
+
+// Normalised cells of this image followed by those of the --batch-tables, with the batch, source
+// table and cell ID of each.
+struct PooledCells {
+    features: Vec<Vec<f64>>,
+    batches: Vec<String>,
+    sources: Vec<String>,
+    cell_ids: Vec<String>,
+}
 
-fn normalize_features(features: &Vec<Vec<f64>>) -> Vec<Vec<f64>> {
-    if features.is_empty() || features[0].is_empty() {
-        return vec![];
+fn file_stem(path: &str) -> String {
+    Path::new(path).file_stem().map_or_else(|| path.to_string(), |stem| stem.to_string_lossy().into_owned())
+}
+
+// The batch of this image's cells: --batch, else the image file name.
+fn image_batch(config: &config::Config) -> String {
+    config.batch.clone().unwrap_or_else(|| file_stem(&config.image_path))
+}
+
+// Adds the cells of every --batch-tables entry. Normalisation fitted across images or loaded from
+// saved parameters is shared; normalisation fitted on this image alone is fitted afresh on each
+// table's own cells, so every image is scaled by its own statistics. A `label=path` entry puts
+// all its cells in batch `label`; otherwise the table's batch column is used, or its file name
+// if it has none.
+fn pool_cells(table: &feature_table::FeatureTable, features: Vec<Vec<f64>>, normalizers: &[normalization::FeatureNormalizer], config: &config::Config) -> Result<PooledCells, Box<dyn Error>> {
+    let cells = features.len();
+    let mut pooled = PooledCells {
+        features,
+        batches: vec![image_batch(config); cells],
+        sources: vec![config.image_path.clone(); cells],
+        cell_ids: table.cell_ids.iter().map(|id| id.to_string()).collect(),
+    };
+    let per_image = config.normalization_params_path.is_none() && config.fit_tables.is_empty();
+    for entry in &config.batch_tables {
+        let (label, path) = match entry.split_once('=') {
+            Some((label, path)) => (Some(label), path),
+            None => (None, entry.as_str()),
+        };
+        let columns = feature_table::read_feature_columns(path, &table.names)?;
+        let rows = if per_image {
+            let own = normalization::fit_normalizers(&table.names, std::slice::from_ref(&columns), &config.normalization, config.clip_quantiles);
+            normalization::normalize(&table.names, &columns, &own)?
+        } else {
+            normalization::normalize(&table.names, &columns, normalizers)?
+        };
+        let cells = rows.len();
+        let batches = match label {
+            Some(label) => vec![label.to_string(); cells],
+            None => feature_table::read_text_column(path, "batch")?.unwrap_or_else(|| vec![file_stem(path); cells]),
+        };
+        let cell_ids = feature_table::read_text_column(path, "cell_id")?.unwrap_or_else(|| (0..cells).map(|i| i.to_string()).collect());
+        pooled.features.extend(rows);
+        pooled.batches.extend(batches);
+        pooled.sources.extend(std::iter::repeat_n(path.to_string(), cells));
+        pooled.cell_ids.extend(cell_ids);
     }
-    let num_samples = features.len();
-    let num_features = features[0].len();
+    Ok(pooled)
+}
 
-    // Compute min and max for each feature (column)
-    let mut mins = vec![f64::INFINITY; num_features];
-    let mut maxs = vec![f64::NEG_INFINITY; num_features];
+// Removes batch effects from the pooled features if configured, and reports how well the
+// batches mix before and after.
+fn correct_batches(pooled: &mut PooledCells, config: &config::Config, rng: &mut StdRng) -> Result<(), Box<dyn Error>> {
+    let (names, batches) = batch_correction::batch_indices(&pooled.batches);
+    println!("Pooled {} cells of {} batches from {} images", pooled.features.len(), names.len(), config.batch_tables.len() + 1);
+    let corrected = match config.batch_correction {
+        Some(batch_correction::BatchCorrection::ComBat) => Some(batch_correction::combat(&pooled.features, &batches)?),
+        Some(batch_correction::BatchCorrection::Harmony) => Some(batch_correction::harmony(&pooled.features, &batches, rng)?),
+        None => None,
+    };
 
-    for row in features {
-        for (j, &val) in row.iter().enumerate() {
-            if val < mins[j] { mins[j] = val; }
-            if val > maxs[j] { maxs[j] = val; }
+    let (before, after) = batch_correction::mixing_scores(&pooled.features, corrected.as_deref(), &batches, rng);
+    println!("Batch mixing before correction: kBET rejection rate {:.3}, mean LISI {:.3}", before.kbet_rejection_rate, before.mean_lisi);
+    if let Some(after) = &after {
+        println!("Batch mixing after correction: kBET rejection rate {:.3}, mean LISI {:.3}", after.kbet_rejection_rate, after.mean_lisi);
+    }
+    match batch_correction::write_diagnostics(&config.batch_diagnostics_path, &names, pooled.features.len(), &before, after.as_ref()) {
+        Ok(_) => println!("Batch diagnostics saved as {}", config.batch_diagnostics_path),
+        Err(e) => eprintln!("Error: Failed to save batch diagnostics to {}: {}", config.batch_diagnostics_path, e),
+    }
+    if let Some(corrected) = corrected {
+        pooled.features = corrected;
+    }
+    Ok(())
+}
+
+// Pixel size from the command line, else from the image metadata. Features are reported in
+// microns whenever the pixel size is known, unless --units says otherwise.
+fn resolve_calibration(config: &config::Config) -> Calibration {
+    let pixel_size = config.pixel_size.or_else(|| match calibration::pixel_size_from_metadata(&config.image_path) {
+        Ok(size) => size,
+        Err(e) => {
+            eprintln!("Warning: Failed to read the pixel size from {}: {}", config.image_path, e);
+            None
+        }
+    });
+
+    let mut calibration = Calibration::default();
+    match (pixel_size, config.units) {
+        (Some((x, y, z)), units) => {
+            calibration.x_um_per_pixel = x;
+            calibration.y_um_per_pixel = y;
+            calibration.z_um_per_slice = z;
+            calibration.units = units.unwrap_or(Units::Microns);
         }
+        (None, Some(Units::Microns)) => {
+            eprintln!("Warning: microns requested but the pixel size is unknown (pass --pixel-size); reporting pixels");
+        }
+        (None, _) => {}
+    }
+    if calibration.units == Units::Microns {
+        println!("Pixel size: {} x {} µm", calibration.x_um_per_pixel, calibration.y_um_per_pixel);
     }
+    calibration
+}
 
-    // Normalize each value
-    features.iter()
-        .map(|row| {
-            row.iter().enumerate().map(|(j, &val)| {
-                let min = mins[j];
-                let max = maxs[j];
-                if (max - min).abs() < std::f64::EPSILON {
-                    0.0
-                } else {
-                    (val - min) / (max - min)
-                }
-            }).collect()
+// Haralick features of the given pixels of every cell on one channel, for each GLCM distance.
+fn push_haralick(
+    table: &mut feature_table::FeatureTable,
+    prefix: &str,
+    channel: &str,
+    plane: &ndarray::Array2<f64>,
+    range: (f64, f64),
+    cell_pixels: &[Vec<(i32, i32)>],
+    config: &config::Config,
+) {
+    let cell_levels: Vec<Vec<Vec<Option<usize>>>> = cell_pixels.iter()
+        .map(|pixels| texture::quantize_cell(plane, range, pixels, config.glcm_levels))
+        .collect();
+    for &distance in &config.glcm_distances {
+        let textures: Vec<texture::Haralick> = cell_levels.iter()
+            .map(|levels| texture::cell_texture(levels, config.glcm_levels, distance, &config.glcm_angles))
+            .collect();
+        for (t, name) in texture::Haralick::NAMES.iter().enumerate() {
+            let values = textures.iter().map(|haralick| haralick.values()[t]).collect();
+            table.push(&format!("{}_{}_{}_d{}", prefix, name, channel, distance), values);
+        }
+    }
+}
+
+// LBP histograms, gradient statistics and HOG of the given pixels of every cell on one channel.
+fn push_local_texture(
+    table: &mut feature_table::FeatureTable,
+    prefix: &str,
+    channel: &str,
+    plane: &ndarray::Array2<f64>,
+    cell_pixels: &[Vec<(i32, i32)>],
+    config: &config::Config,
+) {
+    let lbp: Vec<Vec<f64>> = cell_pixels.iter()
+        .map(|pixels| texture::lbp_histogram(plane, pixels, config.lbp_points, config.lbp_radius))
+        .collect();
+    for b in 0..config.lbp_points + 2 {
+        table.push(&format!("{}_lbp_{}_{}", prefix, b, channel), lbp.iter().map(|histogram| histogram[b]).collect());
+    }
+
+    let (gx, gy) = texture::gradients(plane, config.gradient_operator);
+    let gradients: Vec<(texture::GradientStats, Vec<f64>)> = cell_pixels.iter()
+        .map(|pixels| texture::gradient_features(&gx, &gy, pixels, config.hog_bins))
+        .collect();
+    for (g, name) in texture::GradientStats::NAMES.iter().enumerate() {
+        let values = gradients.iter().map(|(stats, _)| stats.values()[g]).collect();
+        table.push(&format!("{}_gradient_{}_{}", prefix, name, channel), values);
+    }
+    for b in 0..config.hog_bins {
+        table.push(&format!("{}_hog_{}_{}", prefix, b, channel), gradients.iter().map(|(_, histogram)| histogram[b]).collect());
+    }
+}
+
+// Moments and Zernike moments of the given pixels of every cell, unweighted ("mask") and
+// weighted by the moment channel. `prefix` is prepended to the column names.
+fn push_moments(
+    table: &mut feature_table::FeatureTable,
+    prefix: &str,
+    moment_plane: &ndarray::Array2<f64>,
+    cell_pixels: &[Vec<(i32, i32)>],
+    config: &config::Config,
+) {
+    let (height, width) = (moment_plane.nrows() as i32, moment_plane.ncols() as i32);
+    let weightings: [(&str, Vec<Vec<f64>>); 2] = [
+        ("mask", cell_pixels.iter().map(|pixels| vec![1.0; pixels.len()]).collect()),
+        (&config.moment_channel, cell_pixels.iter()
+            .map(|pixels| pixels.iter()
+                .map(|&(x, y)| {
+                    let inside = x >= 0 && y >= 0 && x < width && y < height;
+                    if inside { moment_plane[[y as usize, x as usize]] } else { 0.0 }
+                })
+                .collect())
+            .collect()),
+    ];
+    for (weighting, weights) in &weightings {
+        let cell_moments: Vec<moments::Moments> = cell_pixels.iter().zip(weights).map(|(pixels, w)| moments::moments(pixels, w)).collect();
+        for (m, name) in moments::Moments::NAMES.iter().enumerate() {
+            let values = cell_moments.iter().map(|moments| moments.values()[m]).collect();
+            table.push(&format!("{}moment_{}_{}", prefix, weighting, name), values);
+        }
+        let zernike: Vec<Vec<f64>> = cell_pixels.iter().zip(weights).map(|(pixels, w)| moments::zernike(pixels, w, config.zernike_order)).collect();
+        for (z, (n, m)) in moments::zernike_indices(config.zernike_order).into_iter().enumerate() {
+            table.push(&format!("{}zernike_{}_{}_{}", prefix, weighting, n, m), zernike.iter().map(|magnitudes| magnitudes[z]).collect());
+        }
+    }
+}
+
+// Intensity profiles of the given pixels of every cell, one column per channel and statistic.
+// Returns the profiles as [cell][channel].
+fn push_intensity_profiles(
+    table: &mut feature_table::FeatureTable,
+    prefix: &str,
+    image: &multichannel::MultiChannelImage,
+    cell_pixels: &[Vec<(i32, i32)>],
+) -> Vec<Vec<extract_features::IntensityProfile>> {
+    let profiles = extract_features::channel_profiles(&extract_features::cell_intensities(image, cell_pixels));
+    // Alpha is opacity, not a measurement
+    for (c, channel) in image.channel_names.iter().enumerate().filter(|&(c, _)| !image.is_alpha(c)) {
+        for (s, stat) in extract_features::IntensityProfile::NAMES.iter().enumerate() {
+            let values = profiles.iter().map(|cell| cell[c].values()[s]).collect();
+            table.push(&format!("{}_{}_{}", prefix, stat, channel), values);
+        }
+    }
+    profiles
+}
+
+// Nucleus, cytoplasm and membrane features of every cell, and nuclear/cytoplasmic ratios.
+fn push_compartment_features(
+    table: &mut feature_table::FeatureTable,
+    image: &multichannel::MultiChannelImage,
+    interiors: &[Vec<(i32, i32)>],
+    nuclei: &segmentation::Segmentation,
+    calibration: &Calibration,
+    config: &config::Config,
+) {
+    let nucleus_interiors: Vec<Vec<(i32, i32)>> = nuclei.outlines.iter()
+        .map(|outline| extract_features::rasterize_polygon(outline, config.fill_rule))
+        .collect();
+    let matches = compartments::match_nuclei(interiors, &nucleus_interiors, config.min_nucleus_overlap);
+    let cells: Vec<compartments::Compartments> = interiors.iter()
+        .zip(&matches)
+        .map(|(pixels, &n)| compartments::compartments(pixels, n.map(|n| (n, nucleus_interiors[n].as_slice())), config.membrane_width))
+        .collect();
+    println!("Matched nuclei to {} of {} cells", matches.iter().flatten().count(), interiors.len());
+
+    table.push("has_nucleus", cells.iter().map(|c| if c.nucleus.is_some() { 1.0 } else { 0.0 }).collect());
+    let nucleus_shapes: Vec<extract_features::Morphology> = cells.iter()
+        .map(|c| match c.nucleus {
+            Some(n) => extract_features::morphology(&nuclei.outlines[n], &c.nucleus_pixels),
+            None => extract_features::Morphology::default(),
         })
-        .collect()
+        .collect();
+    for (m, name) in extract_features::Morphology::NAMES.iter().enumerate() {
+        let values = nucleus_shapes.iter().map(|morphology| morphology.values()[m]).collect();
+        table.push_calibrated(&format!("nucleus_{}", name), extract_features::Morphology::QUANTITIES[m], values, calibration);
+    }
+    let nucleus_pixels: Vec<Vec<(i32, i32)>> = cells.iter().map(|c| c.nucleus_pixels.clone()).collect();
+    let cytoplasm_pixels: Vec<Vec<(i32, i32)>> = cells.iter().map(|c| c.cytoplasm_pixels.clone()).collect();
+    let membrane_pixels: Vec<Vec<(i32, i32)>> = cells.iter().map(|c| c.membrane_pixels.clone()).collect();
+    // The cytoplasm and membrane are rings rather than polygons, so they're measured on their
+    // pixels; their area is the pixel count, not repeated as a polygon area
+    for (compartment, cell_pixels) in [("cytoplasm", &cytoplasm_pixels), ("membrane", &membrane_pixels)] {
+        table.push_calibrated(&format!("{}_area", compartment), Quantity::Area, cell_pixels.iter().map(|pixels| pixels.len() as f64).collect(), calibration);
+        let shapes: Vec<extract_features::Morphology> = cell_pixels.iter().map(|pixels| extract_features::region_morphology(pixels)).collect();
+        for (m, name) in extract_features::Morphology::NAMES.iter().enumerate().filter(|(_, name)| **name != "polygon_area") {
+            let values = shapes.iter().map(|morphology| morphology.values()[m]).collect();
+            table.push_calibrated(&format!("{}_{}", compartment, name), extract_features::Morphology::QUANTITIES[m], values, calibration);
+        }
+    }
+    table.push("nucleus_cell_area_ratio", cells.iter()
+        .zip(interiors)
+        .map(|(c, pixels)| if pixels.is_empty() { 0.0 } else { c.nucleus_pixels.len() as f64 / pixels.len() as f64 })
+        .collect());
+
+    let nucleus = push_intensity_profiles(table, "nucleus_channel", image, &nucleus_pixels);
+    let cytoplasm = push_intensity_profiles(table, "cytoplasm_channel", image, &cytoplasm_pixels);
+    push_intensity_profiles(table, "membrane_channel", image, &membrane_pixels);
+
+    // Nuclear/cytoplasmic ratios, 0 when the cytoplasm has no signal (or the cell no nucleus)
+    let ratio = |n: f64, c: f64| if c > 0.0 { n / c } else { 0.0 };
+    for (c, channel) in image.channel_names.iter().enumerate().filter(|&(c, _)| !image.is_alpha(c)) {
+        table.push(&format!("nc_ratio_mean_{}", channel), nucleus.iter().zip(&cytoplasm).map(|(n, cy)| ratio(n[c].mean, cy[c].mean)).collect());
+        table.push(&format!("nc_ratio_integrated_{}", channel), nucleus.iter().zip(&cytoplasm).map(|(n, cy)| ratio(n[c].integrated, cy[c].integrated)).collect());
+    }
+
+    for channel in &config.texture_channels {
+        let plane = channel_plane(image, channel);
+        let range = image.value_range(channel, &plane);
+        push_haralick(table, "nucleus_texture", channel, &plane, range, &nucleus_pixels, config);
+        push_haralick(table, "cytoplasm_texture", channel, &plane, range, &cytoplasm_pixels, config);
+        push_haralick(table, "membrane_texture", channel, &plane, range, &membrane_pixels, config);
+        push_local_texture(table, "nucleus_texture", channel, &plane, &nucleus_pixels, config);
+        push_local_texture(table, "cytoplasm_texture", channel, &plane, &cytoplasm_pixels, config);
+        push_local_texture(table, "membrane_texture", channel, &plane, &membrane_pixels, config);
+    }
+    let moment_plane = channel_plane(image, &config.moment_channel);
+    push_moments(table, "nucleus_", &moment_plane, &nucleus_pixels, config);
+    push_moments(table, "cytoplasm_", &moment_plane, &cytoplasm_pixels, config);
+    push_moments(table, "membrane_", &moment_plane, &membrane_pixels, config);
+}
+
+// The named channel of the image, exiting if there is none.
+fn channel_plane(image: &multichannel::MultiChannelImage, name: &str) -> ndarray::Array2<f64> {
+    image.plane(name).unwrap_or_else(|| {
+        eprintln!("Error: the image has no channel named {} (channels: {}, or gray)", name, image.channel_names.join(", "));
+        std::process::exit(2);
+    })
+}
+
+// Flat-field and dark-frame correction, then background subtraction, as configured
+fn correct_illumination(image: &mut multichannel::MultiChannelImage, config: &config::Config) {
+    let load = |path: &String| multichannel::MultiChannelImage::load(path).unwrap_or_else(|e| {
+        eprintln!("Error: Failed to load {}: {}", path, e);
+        std::process::exit(2);
+    });
+    let dark = config.dark_frame_path.as_ref().map(load);
+    let flat = if config.flat_field_batch.is_empty() {
+        config.flat_field_path.as_ref().map(load)
+    } else {
+        let mut batch: Vec<_> = config.flat_field_batch.iter().map(load).collect();
+        batch.push(image.clone());
+        let flat = illumination::estimate_flat_field(&batch, dark.as_ref()).unwrap_or_else(|e| {
+            eprintln!("Error: Failed to estimate the flat field: {}", e);
+            std::process::exit(2);
+        });
+        println!("Estimated the flat field from {} images", batch.len());
+        if let Some(path) = &config.save_flat_field_path {
+            match illumination::write_float_tiff(path, &flat) {
+                Ok(_) => println!("Flat field saved as {}", path),
+                Err(e) => eprintln!("Error: Failed to save flat field to {}: {}", path, e),
+            }
+        }
+        Some(flat)
+    };
+
+    if let Err(e) = illumination::correct_flat_field(image, flat.as_ref(), dark.as_ref()) {
+        eprintln!("Error: {}", e);
+        std::process::exit(2);
+    }
+    illumination::subtract_background(image, config.background, config.background_radius);
+}
+
+// Normalises the image to the reference image and writes the matched parameters
+fn normalize_image(image: &mut multichannel::MultiChannelImage, method: image_normalization::ImageNormalization, config: &config::Config) {
+    let path = config.normalization_reference_path.as_deref().unwrap_or_default();
+    let reference = multichannel::MultiChannelImage::load(path).unwrap_or_else(|e| {
+        eprintln!("Error: Failed to load normalisation reference {}: {}", path, e);
+        std::process::exit(2);
+    });
+    let parameters = match image_normalization::normalize_image(image, &reference, method) {
+        Ok(parameters) => parameters,
+        Err(e) => {
+            eprintln!("Error: Image normalisation failed: {}", e);
+            std::process::exit(2);
+        }
+    };
+    match image_normalization::write_normalization_table(&config.normalization_table_path, method, &parameters) {
+        Ok(_) => println!("Normalisation parameters saved as {}", config.normalization_table_path),
+        Err(e) => eprintln!("Error: Failed to save normalisation parameters to {}: {}", config.normalization_table_path, e),
+    }
+}
+
+// Unmixes the image's RGB into stain density channels
+fn add_stain_channels(image: &mut multichannel::MultiChannelImage, stains: &stains::Stains) -> Result<(), Box<dyn Error>> {
+    let od = stains::optical_density(image)?;
+    let matrix = match stains {
+        stains::Stains::Macenko => stains::macenko(&od)?,
+        stains::Stains::File(path) => stains::StainMatrix::read(path)?,
+        builtin => stains::StainMatrix::builtin(builtin).ok_or("No built-in stain vectors")?,
+    };
+    for (name, vector) in matrix.names.iter().zip(&matrix.vectors) {
+        println!("Stain {}: optical density {:.3}, {:.3}, {:.3}", name, vector[0], vector[1], vector[2]);
+    }
+    let densities = stains::deconvolve(&od, &matrix)?;
+    stains::add_stain_channels(image, &densities, &matrix)
 }
 
 fn main() {
-    let outlines = extract_features::read_outlines("/Users/sam/dev/kmeans/src/9_outlines.txt").expect("Failed to read outlines");
-    let mut centroids = extract_features::calculate_centroids(&outlines);
-    //println!("{:?}", features[0][0].0);
+    let config = match config::Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
 
-    let hull = extract_features::convex_hull(outlines.clone());
-    let mut areas = Vec::new();
-    for cell in &outlines {
-        let area = extract_features::convex_area(cell);
-        areas.push(area);
+    let mut image = multichannel::MultiChannelImage::load(&config.image_path).expect("Failed to load image");
+    let panel = match &config.panel_path {
+        Some(path) => Some(panel::MarkerPanel::read(path).expect("Failed to read marker panel")),
+        None => (!config.markers.is_empty()).then(|| panel::MarkerPanel::from_names(&config.markers)),
+    };
+    if let Some(panel) = panel
+        && let Err(e) = panel.apply(&mut image)
+    {
+        eprintln!("Error: {}", e);
+        std::process::exit(2);
     }
-    //println!("{:?}", hull[0]);
+    println!("Image channels: {}", image.channel_names.join(", "));
+    correct_illumination(&mut image, &config);
+    if let Some(method) = config.normalize {
+        normalize_image(&mut image, method, &config);
+    }
+    if let Some(stains) = &config.stains
+        && let Err(e) = add_stain_channels(&mut image, stains)
+    {
+        eprintln!("Error: Colour deconvolution failed: {}", e);
+        std::process::exit(2);
+    }
+
+    let segmentation = match config.threshold {
+        Some(method) => {
+            let parameters = classical_segmentation::SegmentationParameters {
+                method,
+                foreground: config.foreground,
+                sigma: config.smoothing,
+                block_size: config.block_size,
+                offset: config.threshold_offset,
+                min_size: config.min_size,
+                seed_distance: config.seed_distance,
+            };
+            let segmentation = classical_segmentation::segment(&channel_plane(&image, &config.segment_channel), &parameters);
+            println!("Segmented {} cells from {}", segmentation.outlines.len(), config.segment_channel);
+            segmentation
+        }
+        None => segmentation::load_segmentation(&config.segmentation_path).expect("Failed to load segmentation"),
+    };
+    let outlines = segmentation.outlines;
+    let centroids = extract_features::calculate_centroids(&outlines);
+
+    let calibration = resolve_calibration(&config);
+
+    let interiors: Vec<Vec<(i32, i32)>> = outlines.iter()
+        .map(|outline| extract_features::rasterize_polygon(outline, config.fill_rule))
+        .collect();
+    let intensity_pixels = match config.intensity_region {
+        extract_features::IntensityRegion::Interior => &interiors,
+        extract_features::IntensityRegion::Boundary => &outlines,
+    };
 
-    for cell in &outlines {
-        //println!("{:?} {:?}", extract_features::convex_area(cell), extract_features::convex_perimeter(cell));
+    let morphologies: Vec<extract_features::Morphology> = outlines.iter()
+        .zip(&interiors)
+        .map(|(outline, pixels)| extract_features::morphology(outline, pixels))
+        .collect();
+
+    let (width, height) = (image.width(), image.height());
+    let tissue = config.tissue_mask_path.as_ref().map(|path| {
+        let mask = segmentation::read_binary_mask(path).expect("Failed to read tissue mask");
+        segmentation::label_mask_to_segmentation(&segmentation::label_components(&mask)).outlines
+    });
+    let sites: Vec<(f64, f64)> = centroids.iter().map(|c| (c.0 as f64, c.1 as f64)).collect();
+    let voronoi = voronoi::voronoi_regions(&sites, width, height, tissue.as_deref());
+
+    let mut table = feature_table::FeatureTable::new(segmentation.cell_ids);
+    table.push_calibrated("centroid_x", Quantity::X, centroids.iter().map(|c| c.0 as f64).collect(), &calibration);
+    table.push_calibrated("centroid_y", Quantity::Y, centroids.iter().map(|c| c.1 as f64).collect(), &calibration);
+    for (m, name) in extract_features::Morphology::NAMES.iter().enumerate() {
+        let values = morphologies.iter().map(|morphology| morphology.values()[m]).collect();
+        table.push_calibrated(name, extract_features::Morphology::QUANTITIES[m], values, &calibration);
     }
+    push_intensity_profiles(&mut table, "channel", &image, intensity_pixels);
+    for channel in &config.texture_channels {
+        let plane = channel_plane(&image, channel);
+        let range = image.value_range(channel, &plane);
+        push_haralick(&mut table, "texture", channel, &plane, range, &interiors, &config);
 
-    let rgb_matrix = kmeans::load_image_as_matrix("/Users/sam/dev/kmeans/src/9.png");
-    let segmentation_rgb = extract_features::load_segmentations_as_matrix("/Users/sam/dev/kmeans/src/9.png", "/Users/sam/dev/kmeans/src/9_outlines.txt");
-    let channel_means = extract_features::channel_mean(&segmentation_rgb);
-    //println!("{:?}", extract_features::channel_mean(&segmentation_rgb));
+        push_local_texture(&mut table, "texture", channel, &plane, &interiors, &config);
+    }
+    // Moments of the cell's shape, then weighted by the intensity of the chosen channel
+    let moment_plane = channel_plane(&image, &config.moment_channel);
+    push_moments(&mut table, "", &moment_plane, &interiors, &config);
+    if let Some(path) = &config.nuclei_path {
+        let nuclei = segmentation::load_segmentation(path).expect("Failed to load nuclear segmentation");
+        push_compartment_features(&mut table, &image, &interiors, &nuclei, &calibration, &config);
+    }
+    table.push_calibrated("voronoi_area", Quantity::Area, voronoi.iter().map(|v| v.area).collect(), &calibration);
+    table.push_calibrated("voronoi_perimeter", Quantity::Length, voronoi.iter().map(|v| v.perimeter).collect(), &calibration);
+    table.push("voronoi_vertices", voronoi.iter().map(|v| v.vertices as f64).collect());
+    table.push("voronoi_touches_boundary", voronoi.iter().map(|v| if v.touches_boundary { 1.0 } else { 0.0 }).collect());
+
+    let graph = neighbours::neighbour_graph(&sites, &voronoi, config.adjacency);
+    // The radius is given in the reported units
+    let radius = config.neighbour_radius / calibration.convert(Quantity::Length, 1.0);
+    let neighbourhoods = neighbours::neighbourhoods(&sites, &graph, radius);
+    let averaged: Vec<String> = if config.neighbour_average.iter().any(|name| name == "all") {
+        table.names.clone()
+    } else {
+        config.neighbour_average.clone()
+    };
+    for (n, name) in neighbours::Neighbourhood::NAMES.iter().enumerate() {
+        let values = neighbourhoods.iter().map(|neighbourhood| neighbourhood.values()[n]).collect();
+        table.push_calibrated(name, neighbours::Neighbourhood::QUANTITIES[n], values, &calibration);
+    }
+    for name in &averaged {
+        match table.names.iter().position(|existing| existing == name) {
+            Some(j) => {
+                let values = neighbours::neighbour_average(&table.columns[j], &graph);
+                table.push(&format!("neighbour_mean_{}", name), values);
+            }
+            None => eprintln!("Warning: no feature named {} to average over neighbours", name),
+        }
+    }
+    if let Some(path) = &config.edge_list_path {
+        match neighbours::write_edge_list(path, &graph, &table.cell_ids, &sites, &calibration) {
+            Ok(_) => println!("Neighbour graph saved as {}", path),
+            Err(e) => eprintln!("Error: Failed to save neighbour graph to {}: {}", path, e),
+        }
+    }
+    let normalizers = match fit_normalization(&table, &config) {
+        Ok(normalizers) => normalizers,
+        Err(e) => {
+            eprintln!("Error: Failed to normalise features: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let features = match normalization::normalize(&table.names, &table.columns, &normalizers) {
+        Ok(features) => features,
+        Err(e) => {
+            eprintln!("Error: Failed to normalise features: {}", e);
+            std::process::exit(2);
+        }
+    };
 
-    let voronoi = extract_features::voronoi_areas(&centroids, 512, 512);
-    for area in &voronoi {
-        println!("{:?}", area);
+    println!("Seed: {} (pass --seed {} to reproduce this run)", config.seed, config.seed);
+    let mut rng = StdRng::seed_from_u64(config.seed);
+
+    // Cells of the --batch-tables are clustered together with this image's; this image's cells
+    // come first, so its features and labels are the first `table.num_cells()` rows
+    let mut pooled = match pool_cells(&table, features, &normalizers, &config) {
+        Ok(pooled) => pooled,
+        Err(e) => {
+            eprintln!("Error: Failed to pool batch tables: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if !config.batch_tables.is_empty() && let Err(e) = correct_batches(&mut pooled, &config, &mut rng) {
+        eprintln!("Error: Failed to correct batch effects: {}", e);
+        std::process::exit(2);
     }
+    let features = pooled.features;
 
-    let centroid_x: Vec<f64> = centroids.iter().map(|c| c.0 as f64).collect();
-    let centroid_y: Vec<f64> = centroids.iter().map(|c| c.1 as f64).collect();
-    let channel_mean_red: Vec<f64> = channel_means.iter().map(|c| c.0 as f64).collect();
-    let channel_mean_green: Vec<f64> = channel_means.iter().map(|c| c.1 as f64).collect();
-    let channel_mean_blue: Vec<f64> = channel_means.iter().map(|c| c.2 as f64).collect();
-    let voronoi_f64: Vec<f64> = voronoi.iter().map(|a| *a as f64).collect();
-    let features: Vec<Vec<f64>> = vec![centroid_x, centroid_y, areas, channel_mean_red, channel_mean_green, channel_mean_blue, voronoi_f64];
-    let features = (0..features[0].len()).map(|i| features.iter().map(|feature| feature[i]).collect()).collect();
-    let features = normalize_features(&features);
+    if features.is_empty() {
+        eprintln!("Error: No cells to cluster");
+        std::process::exit(2);
+    }
+    let mut k = config.k;
+    if let Some(ks) = &config.select_k {
+        // The silhouette needs at least one cluster with two cells
+        let ks = *ks.start()..=(*ks.end()).min(features.len().saturating_sub(1));
+        let scores = model_selection::sweep_k(&features, ks, config.init, config.n_init, config.tol, config.gap_refs, &mut rng);
+        match model_selection::write_selection_table(&scores, &config.selection_table_path) {
+            Ok(_) => println!("k selection table saved as {}", config.selection_table_path),
+            Err(e) => eprintln!("Error: Failed to save k selection table to {}: {}", config.selection_table_path, e),
+        }
+        match model_selection::recommend_k(&scores) {
+            Some(rec) => {
+                println!(
+                    "Recommended k = {} (elbow: {}, silhouette: {}, gap: {}, Davies-Bouldin: {})",
+                    rec.k, rec.elbow, rec.silhouette, rec.gap, rec.davies_bouldin
+                );
+                k = rec.k;
+            }
+            None => eprintln!("Warning: no k in the requested range fits {} cells; using k = {}", features.len(), k),
+        }
+    }
+    if k > features.len() {
+        eprintln!("Warning: k = {} is larger than the number of cells ({}); using k = {}", k, features.len(), features.len());
+        k = features.len();
+    }
 
-    let mut iterations = 0;
-    let k = 10;
-    let mut centroids = kmeans::initialize_centroids(&features);
-    let mut old_centroids: Vec<(usize, usize)> = Vec::new();
-    let mut final_labels_matrix: Vec<Vec<usize>> = vec![vec![0; features[0].len()]; features.len()];
-    while !kmeans::should_stop(&old_centroids, &centroids, iterations) {
-        iterations += 1;
+    let result = kmeans::fit(&features, k, config.init, config.n_init, config.tol, &mut rng);
+    if let Some(path) = &config.pooled_clusters_path {
+        match feature_table::write_pooled_clusters(path, &pooled.sources, &pooled.cell_ids, &pooled.batches, &result.labels) {
+            Ok(_) => println!("Pooled clusters saved as {}", path),
+            Err(e) => eprintln!("Error: Failed to save pooled clusters to {}: {}", path, e),
+        }
+    }
+    let labels = result.labels[..table.num_cells()].to_vec();
 
-        let (labels_from_this_iteration, by_labels) = kmeans::get_labels(&features, &centroids);
-        final_labels_matrix = labels_from_this_iteration;
-        //println!("{:?}", labels[0][0]);
+    println!(
+        "K-means clustering finished after {} iterations ({}), best of {} runs. Inertia: {:.6}",
+        result.iterations,
+        if result.converged { "converged" } else { "did not converge" },
+        config.n_init,
+        result.inertia
+    );
 
-        old_centroids = centroids;
-        centroids = kmeans::get_centroids(&by_labels);
+    // Pooled tables record their batch so they can themselves be pooled later
+    let batch = (config.batch.is_some() || !config.batch_tables.is_empty()).then(|| image_batch(&config));
+    match feature_table::write_feature_table(&config.feature_table_path, &table, batch.as_deref(), &features[..table.num_cells()], &labels) {
+        Ok(_) => println!("Feature table saved as {}", config.feature_table_path),
+        Err(e) => eprintln!("Error: Failed to save feature table to {}: {}", config.feature_table_path, e),
     }
 
-    println!("K-means clustering finished after {} iterations.", iterations);
-    println!("Final centroids: {:?}", centroids);
+    if let Some(path) = &config.ripley_path {
+        let max_radius = match config.ripley_max_radius {
+            Some(r) => r / calibration.convert(Quantity::Length, 1.0),
+            None => width.min(height) as f64 / 4.0,
+        };
+        let curves = spatial_statistics::ripley_by_cluster(&sites, &labels, k, width as f64, height as f64, max_radius, config.ripley_steps);
+        match spatial_statistics::write_ripley_table(path, &curves, &calibration) {
+            Ok(_) => println!("Ripley's functions saved as {}", path),
+            Err(e) => eprintln!("Error: Failed to save Ripley's functions to {}: {}", path, e),
+        }
+    }
+    if let Some(path) = &config.enrichment_path {
+        let enrichment = spatial_statistics::neighbourhood_enrichment(&graph, &labels, k, config.permutations, &mut rng);
+        match spatial_statistics::write_enrichment_table(path, &enrichment) {
+            Ok(_) => println!("Neighbourhood enrichment saved as {}", path),
+            Err(e) => eprintln!("Error: Failed to save neighbourhood enrichment to {}: {}", path, e),
+        }
+    }
 
     // Now, call the visualization functions:
     let colors = kmeans::label_colors(k); // Generate `k` distinct colors for the clusters.
-    
+    let cell_labels_matrix = kmeans::cell_label_matrix(&interiors, &labels, width, height);
+
     kmeans::save_clustered_image(
-        &final_labels_matrix,       // The final cluster assignments for each pixel.
+        &cell_labels_matrix,        // The cluster assignment of every cell pixel.
         &colors,                    // The colors for each cluster.
-        features[0].len() as u32,               // Image width.
-        features.len() as u32,               // Image height.
-        "/Users/sam/dev/kmeans/src/clustered.png" // Your desired output path.
+        width as u32,               // Image width.
+        height as u32,              // Image height.
+        &config.output_path         // Your desired output path.
     );
 
 }
 
-// DECODE: DEep Cell Observation & Discovery Engine
\ No newline at end of file
+// DECODE: DEep Cell Observation & Discovery Engine
@@ -0,0 +1,264 @@
+/*
+Choosing k: sweep a range of cluster counts and score each clustering with the elbow of the
+inertia curve, the mean silhouette, the gap statistic and the Davies-Bouldin index.
+*/
+
+use crate::kmeans::{self, euclidean_distance, Init};
+
+use rand::Rng;
+use rand::rngs::StdRng;
+
+use std::error::Error;
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone)]
+pub struct KScore {
+    pub k: usize,
+    pub inertia: f64,
+    pub silhouette: f64,      // Mean silhouette width, higher is better
+    pub gap: f64,             // Gap statistic against a uniform reference
+    pub gap_sd: f64,          // s_k from Tibshirani et al., the simulation error of `gap`
+    pub davies_bouldin: f64,  // Lower is better
+}
+
+#[derive(Debug, Clone)]
+pub struct KRecommendation {
+    pub elbow: usize,
+    pub silhouette: usize,
+    pub gap: usize,
+    pub davies_bouldin: usize,
+    pub k: usize,  // The k most criteria agree on
+}
+
+// Clusters `features` once for every k in `ks` and scores the result.
+// `n_refs` uniform reference data sets are clustered per k for the gap statistic.
+pub fn sweep_k(features: &[Vec<f64>], ks: RangeInclusive<usize>, init: Init, n_init: usize, tol: f64, n_refs: usize, rng: &mut StdRng) -> Vec<KScore> {
+    let mut scores = Vec::new();
+    let bounds = feature_bounds(features);
+
+    for k in (*ks.start()).max(1)..=(*ks.end()).min(features.len()) {
+        let result = kmeans::fit(features, k, init, n_init, tol, rng);
+
+        // Gap statistic: compare log(W_k) with its expectation under uniform data spanning the
+        // same bounding box.
+        let log_w = result.inertia.max(f64::MIN_POSITIVE).ln();
+        let ref_log_w: Vec<f64> = (0..n_refs.max(1))
+            .map(|_| {
+                let reference = uniform_reference(&bounds, features.len(), rng);
+                kmeans::fit(&reference, k, init, n_init, tol, rng).inertia.max(f64::MIN_POSITIVE).ln()
+            })
+            .collect();
+        let b = ref_log_w.len() as f64;
+        let ref_mean = ref_log_w.iter().sum::<f64>() / b;
+        let ref_sd = (ref_log_w.iter().map(|w| (w - ref_mean).powi(2)).sum::<f64>() / b).sqrt();
+
+        scores.push(KScore {
+            k,
+            inertia: result.inertia,
+            silhouette: mean_silhouette(features, &result.labels, k),
+            gap: ref_mean - log_w,
+            gap_sd: ref_sd * (1.0 + 1.0 / b).sqrt(),
+            davies_bouldin: davies_bouldin(features, &result.labels, &result.centroids),
+        });
+    }
+
+    scores
+}
+
+// Mean silhouette width over all cells. Cells alone in their cluster score 0, as does any
+// clustering with a single cluster.
+pub fn mean_silhouette(features: &[Vec<f64>], labels: &[usize], k: usize) -> f64 {
+    if k < 2 || features.is_empty() {
+        return 0.0;
+    }
+    let mut cluster_sizes = vec![0usize; k];
+    for &label in labels {
+        cluster_sizes[label] += 1;
+    }
+
+    let total: f64 = features.iter()
+        .enumerate()
+        .map(|(i, cell)| {
+            if cluster_sizes[labels[i]] <= 1 {
+                return 0.0;
+            }
+            let mut dist_sums = vec![0.0; k];
+            for (j, other) in features.iter().enumerate() {
+                if i != j {
+                    dist_sums[labels[j]] += euclidean_distance(cell, other);
+                }
+            }
+            let a = dist_sums[labels[i]] / (cluster_sizes[labels[i]] - 1) as f64;
+            let b = (0..k)
+                .filter(|&c| c != labels[i] && cluster_sizes[c] > 0)
+                .map(|c| dist_sums[c] / cluster_sizes[c] as f64)
+                .fold(f64::INFINITY, f64::min);
+            if !b.is_finite() || a.max(b) == 0.0 {
+                0.0
+            } else {
+                (b - a) / a.max(b)
+            }
+        })
+        .sum();
+
+    total / features.len() as f64
+}
+
+// Davies-Bouldin index: mean over clusters of the worst ratio of within-cluster scatter to
+// between-centroid separation. Empty clusters are ignored.
+pub fn davies_bouldin(features: &[Vec<f64>], labels: &[usize], centroids: &[Vec<f64>]) -> f64 {
+    let k = centroids.len();
+    let mut scatter = vec![0.0; k];
+    let mut sizes = vec![0usize; k];
+    for (cell, &label) in features.iter().zip(labels) {
+        scatter[label] += euclidean_distance(cell, &centroids[label]);
+        sizes[label] += 1;
+    }
+    let present: Vec<usize> = (0..k).filter(|&c| sizes[c] > 0).collect();
+    if present.len() < 2 {
+        return 0.0;
+    }
+    for &c in &present {
+        scatter[c] /= sizes[c] as f64;
+    }
+
+    let total: f64 = present.iter()
+        .map(|&i| {
+            present.iter()
+                .filter(|&&j| j != i)
+                .map(|&j| {
+                    let separation = euclidean_distance(&centroids[i], &centroids[j]);
+                    if separation > 0.0 { (scatter[i] + scatter[j]) / separation } else { f64::INFINITY }
+                })
+                .fold(0.0, f64::max)
+        })
+        .sum();
+
+    total / present.len() as f64
+}
+
+// Picks a k per criterion and the one most of them agree on (ties go to the silhouette's pick).
+//  - elbow: the point of the inertia curve furthest below the chord joining its ends
+//  - silhouette: highest mean silhouette
+//  - gap: smallest k with Gap(k) >= Gap(k+1) - s(k+1)
+//  - Davies-Bouldin: lowest index
+pub fn recommend_k(scores: &[KScore]) -> Option<KRecommendation> {
+    let first = scores.first()?;
+    let last = scores.last()?;
+
+    let elbow = if scores.len() < 3 {
+        first.k
+    } else {
+        let dk = (last.k - first.k) as f64;
+        let dw = last.inertia - first.inertia;
+        scores.iter()
+            .map(|s| {
+                let t = (s.k - first.k) as f64 / dk;
+                let chord = first.inertia + t * dw;
+                (s.k, chord - s.inertia)
+            })
+            .fold((first.k, f64::NEG_INFINITY), |best, (k, d)| if d > best.1 { (k, d) } else { best })
+            .0
+    };
+
+    let silhouette = scores.iter()
+        .fold(first, |best, s| if s.silhouette > best.silhouette { s } else { best })
+        .k;
+
+    let gap = scores.windows(2)
+        .find(|w| w[0].gap >= w[1].gap - w[1].gap_sd)
+        .map_or(last.k, |w| w[0].k);
+
+    let davies_bouldin = scores.iter()
+        .filter(|s| s.k >= 2)
+        .fold(None, |best: Option<&KScore>, s| match best {
+            Some(b) if b.davies_bouldin <= s.davies_bouldin => Some(b),
+            _ => Some(s),
+        })
+        .map_or(first.k, |s| s.k);
+
+    let votes = [elbow, silhouette, gap, davies_bouldin];
+    let count = |k: usize| votes.iter().filter(|&&v| v == k).count();
+    let k = votes.iter()
+        .copied()
+        .fold(silhouette, |best, v| if count(v) > count(best) { v } else { best });
+
+    Some(KRecommendation { elbow, silhouette, gap, davies_bouldin, k })
+}
+
+pub fn write_selection_table(scores: &[KScore], path: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["k", "inertia", "silhouette", "gap", "gap_sd", "davies_bouldin"])?;
+    for s in scores {
+        writer.write_record([
+            s.k.to_string(),
+            s.inertia.to_string(),
+            s.silhouette.to_string(),
+            s.gap.to_string(),
+            s.gap_sd.to_string(),
+            s.davies_bouldin.to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// (min, max) of every feature column
+fn feature_bounds(features: &[Vec<f64>]) -> Vec<(f64, f64)> {
+    let num_features = features.first().map_or(0, |f| f.len());
+    (0..num_features)
+        .map(|j| {
+            features.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), cell| (lo.min(cell[j]), hi.max(cell[j])))
+        })
+        .collect()
+}
+
+fn uniform_reference(bounds: &[(f64, f64)], n: usize, rng: &mut StdRng) -> Vec<Vec<f64>> {
+    (0..n)
+        .map(|_| bounds.iter().map(|&(lo, hi)| lo + rng.random::<f64>() * (hi - lo)).collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    // Three tight, well-separated blobs of 30 cells each; cell i belongs to blob i % 3
+    fn blobs() -> Vec<Vec<f64>> {
+        let mut noise = StdRng::seed_from_u64(5);
+        let centres = [(0.0, 0.0), (10.0, 0.0), (5.0, 8.0)];
+        (0..90)
+            .map(|i| {
+                let (x, y) = centres[i % 3];
+                vec![x + noise.random_range(-0.5..0.5), y + noise.random_range(-0.5..0.5)]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_criterion_finds_three_blobs() {
+        let scores = sweep_k(&blobs(), 1..=6, Init::KMeansPlusPlus, 5, 1e-4, 5, &mut StdRng::seed_from_u64(11));
+        let recommendation = recommend_k(&scores).unwrap();
+        assert_eq!(recommendation.k, 3);
+        assert_eq!(recommendation.silhouette, 3);
+        assert_eq!(recommendation.gap, 3);
+        assert_eq!(recommendation.davies_bouldin, 3);
+    }
+
+    #[test]
+    fn separated_clusters_score_well() {
+        let features = blobs();
+        let labels: Vec<usize> = (0..features.len()).map(|i| i % 3).collect();
+        assert!(mean_silhouette(&features, &labels, 3) > 0.9);
+
+        let mut rng = StdRng::seed_from_u64(2);
+        let index = |k: usize, rng: &mut StdRng| {
+            let result = kmeans::fit(&features, k, Init::KMeansPlusPlus, 5, 1e-4, rng);
+            davies_bouldin(&features, &result.labels, &result.centroids)
+        };
+        let true_k = index(3, &mut rng);
+        assert!(true_k < index(2, &mut rng));
+        assert!(true_k < index(4, &mut rng));
+    }
+}
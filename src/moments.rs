@@ -0,0 +1,171 @@
+/*
+Image moments of each cell: raw, central and scale-normalised moments, Hu's seven invariants and
+Zernike moment magnitudes, weighting the cell's pixels either equally (its shape) or by intensity.
+*/
+
+use std::f64::consts::{FRAC_1_SQRT_2, PI};
+
+// Moments up to third order, in the order of NAMES.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Moments {
+    pub raw: [f64; 10],         // m00, m10, m01, m20, m11, m02, m30, m21, m12, m03 about the image origin
+    pub central: [f64; 7],      // mu20, mu11, mu02, mu30, mu21, mu12, mu03 about the centroid
+    pub normalized: [f64; 7],   // eta_pq = mu_pq / mu00^(1 + (p + q) / 2), scale invariant
+    pub hu: [f64; 7],           // Hu's invariants, also rotation invariant (the seventh changes sign under reflection)
+}
+
+// (p, q) of the raw moments, then of the central and normalised ones
+const RAW_ORDERS: [(i32, i32); 10] = [(0, 0), (1, 0), (0, 1), (2, 0), (1, 1), (0, 2), (3, 0), (2, 1), (1, 2), (0, 3)];
+const CENTRAL_ORDERS: [(i32, i32); 7] = [(2, 0), (1, 1), (0, 2), (3, 0), (2, 1), (1, 2), (0, 3)];
+
+impl Moments {
+    pub const NAMES: [&'static str; 31] = [
+        "m00", "m10", "m01", "m20", "m11", "m02", "m30", "m21", "m12", "m03",
+        "mu20", "mu11", "mu02", "mu30", "mu21", "mu12", "mu03",
+        "eta20", "eta11", "eta02", "eta30", "eta21", "eta12", "eta03",
+        "hu1", "hu2", "hu3", "hu4", "hu5", "hu6", "hu7",
+    ];
+
+    // Values in the same order as NAMES
+    pub fn values(&self) -> [f64; 31] {
+        let mut values = [0.0; 31];
+        values[..10].copy_from_slice(&self.raw);
+        values[10..17].copy_from_slice(&self.central);
+        values[17..24].copy_from_slice(&self.normalized);
+        values[24..].copy_from_slice(&self.hu);
+        values
+    }
+}
+
+// Moments of pixels at integer coordinates with the given weights, 1 for the plain mask. Pixels
+// are point masses at their centres. All zeros if the weights sum to 0.
+pub fn moments(pixels: &[(i32, i32)], weights: &[f64]) -> Moments {
+    let raw = RAW_ORDERS.map(|(p, q)| {
+        pixels.iter().zip(weights).map(|(&(x, y), w)| w * (x as f64).powi(p) * (y as f64).powi(q)).sum::<f64>()
+    });
+    let m00 = raw[0];
+    if m00 <= 0.0 {
+        return Moments { raw, ..Moments::default() };
+    }
+
+    let (cx, cy) = (raw[1] / m00, raw[2] / m00);
+    let central = CENTRAL_ORDERS.map(|(p, q)| {
+        pixels.iter().zip(weights).map(|(&(x, y), w)| w * (x as f64 - cx).powi(p) * (y as f64 - cy).powi(q)).sum::<f64>()
+    });
+    let mut normalized = [0.0; 7];
+    for (k, &(p, q)) in CENTRAL_ORDERS.iter().enumerate() {
+        normalized[k] = central[k] / m00.powf(1.0 + (p + q) as f64 / 2.0);
+    }
+
+    let [n20, n11, n02, n30, n21, n12, n03] = normalized;
+    let (a, b) = (n30 + n12, n21 + n03);
+    let (c, d) = (n30 - 3.0 * n12, 3.0 * n21 - n03);
+    let hu = [
+        n20 + n02,
+        (n20 - n02).powi(2) + 4.0 * n11 * n11,
+        c * c + d * d,
+        a * a + b * b,
+        c * a * (a * a - 3.0 * b * b) + d * b * (3.0 * a * a - b * b),
+        (n20 - n02) * (a * a - b * b) + 4.0 * n11 * a * b,
+        d * a * (a * a - 3.0 * b * b) - c * b * (3.0 * a * a - b * b),
+    ];
+
+    Moments { raw, central, normalized, hu }
+}
+
+// (n, m) of every Zernike moment up to `order`: 0 <= m <= n with n - m even
+pub fn zernike_indices(order: usize) -> Vec<(usize, usize)> {
+    (0..=order).flat_map(|n| (n % 2..=n).step_by(2).map(move |m| (n, m))).collect()
+}
+
+// Magnitudes |A_nm| of the Zernike moments up to `order`, in the order of `zernike_indices`. The
+// unit disk is centred on the weighted centroid and just covers the pixel furthest from it, and
+// the weights are scaled to average 1, so the magnitudes don't depend on the cell's size,
+// orientation or brightness.
+pub fn zernike(pixels: &[(i32, i32)], weights: &[f64], order: usize) -> Vec<f64> {
+    let indices = zernike_indices(order);
+    let total: f64 = weights.iter().sum();
+    if pixels.is_empty() || total <= 0.0 {
+        return vec![0.0; indices.len()];
+    }
+
+    let cx = pixels.iter().zip(weights).map(|(&(x, _), w)| x as f64 * w).sum::<f64>() / total;
+    let cy = pixels.iter().zip(weights).map(|(&(_, y), w)| y as f64 * w).sum::<f64>() / total;
+    // Half a pixel diagonal beyond the furthest pixel centre keeps every pixel inside the disk
+    let radius = pixels.iter()
+        .map(|&(x, y)| ((x as f64 - cx).powi(2) + (y as f64 - cy).powi(2)).sqrt())
+        .fold(0.0, f64::max) + FRAC_1_SQRT_2;
+    let pixel_area = 1.0 / (radius * radius);
+    let scale = pixels.len() as f64 / total;
+
+    let polar: Vec<(f64, f64, f64)> = pixels.iter()
+        .zip(weights)
+        .map(|(&(x, y), w)| {
+            // y grows downwards; flip it so angles run counter-clockwise
+            let (dx, dy) = ((x as f64 - cx) / radius, -(y as f64 - cy) / radius);
+            ((dx * dx + dy * dy).sqrt(), dy.atan2(dx), w * scale)
+        })
+        .collect();
+
+    indices.iter()
+        .map(|&(n, m)| {
+            let (mut re, mut im) = (0.0, 0.0);
+            for &(rho, theta, f) in &polar {
+                let radial = zernike_radial(n, m, rho);
+                // Conjugate of V_nm = R_nm(rho) e^(i m theta)
+                re += f * radial * (m as f64 * theta).cos();
+                im -= f * radial * (m as f64 * theta).sin();
+            }
+            (n + 1) as f64 / PI * pixel_area * (re * re + im * im).sqrt()
+        })
+        .collect()
+}
+
+// Zernike radial polynomial R_nm(rho) for n - m even
+fn zernike_radial(n: usize, m: usize, rho: f64) -> f64 {
+    let factorial = |k: usize| (1..=k).map(|i| i as f64).product::<f64>();
+    (0..=(n - m) / 2)
+        .map(|s| {
+            let sign = if s.is_multiple_of(2) { 1.0 } else { -1.0 };
+            sign * factorial(n - s) / (factorial(s) * factorial((n + m) / 2 - s) * factorial((n - m) / 2 - s))
+                * rho.powi((n - 2 * s) as i32)
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &[f64], b: &[f64]) {
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() <= 1e-9 * x.abs().max(1.0), "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn invariants_survive_translation_and_rotation() {
+        // An L-shaped cell, brighter towards the end of its long arm
+        let pixels: Vec<(i32, i32)> = (0..4).flat_map(|y| (0..9).map(move |x| (x, y)))
+            .chain((4..12).flat_map(|y| (0..3).map(move |x| (x, y))))
+            .collect();
+        let weights: Vec<f64> = pixels.iter().map(|&(x, y)| 1.0 + x as f64 + 0.5 * y as f64).collect();
+        let shifted: Vec<(i32, i32)> = pixels.iter().map(|&(x, y)| (x + 17, y - 5)).collect();
+        let rotated: Vec<(i32, i32)> = pixels.iter().map(|&(x, y)| (-y, x)).collect();
+
+        for w in [vec![1.0; pixels.len()], weights] {
+            let original = moments(&pixels, &w);
+            let zernike_original = zernike(&pixels, &w, 6);
+            for moved in [&shifted, &rotated] {
+                assert_close(&moments(moved, &w).hu, &original.hu);
+                assert_close(&zernike(moved, &w, 6), &zernike_original);
+            }
+            assert_close(&moments(&shifted, &w).central, &original.central);
+        }
+    }
+
+    #[test]
+    fn zernike_indices_pair_n_with_m_of_the_same_parity() {
+        assert_eq!(zernike_indices(3), vec![(0, 0), (1, 1), (2, 0), (2, 2), (3, 1), (3, 3)]);
+    }
+}
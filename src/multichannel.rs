@@ -0,0 +1,248 @@
+/*
+Multichannel images. Every channel is kept at its original bit depth (8 and 16-bit integers are
+stored exactly as f32) under a name, so fluorescence stacks with any number of channels can be
+measured the same way as RGB images.
+*/
+
+use image::{DynamicImage, ImageReader};
+use ndarray::{Array2, Array3, ArrayView2, Axis};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+use tiff::ColorType;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+
+// Sample type the image was stored with
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum PixelType {
+    U8,
+    U16,
+    F32,  // Floating point, or any integer type wider than 16 bits
+}
+
+#[derive(Debug, Clone)]
+pub struct MultiChannelImage {
+    pub data: Array3<f32>,           // (channel, y, x)
+    pub channel_names: Vec<String>,
+    pub pixel_type: PixelType,
+    pub derived_channels: usize,     // Trailing channels computed from the others, like stain densities
+    pub alpha: Option<usize>,        // The opacity channel, which is never measured whatever it's called
+}
+
+impl MultiChannelImage {
+    // Loads a grayscale, gray+alpha, RGB or RGBA image of 8, 16 or 32-bit float samples. Every page
+    // of a TIFF becomes one or more channels; pages are named by OME-XML channel names when present.
+    pub fn load(path: &str) -> Result<MultiChannelImage, Box<dyn Error>> {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".tif") || lower.ends_with(".tiff") {
+            load_tiff(path)
+        } else {
+            from_dynamic(ImageReader::open(path)?.decode()?)
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.data.shape()[2]
+    }
+
+    pub fn height(&self) -> usize {
+        self.data.shape()[1]
+    }
+
+    pub fn channel(&self, c: usize) -> ArrayView2<'_, f32> {
+        self.data.index_axis(Axis(0), c)
+    }
+
+    pub fn is_alpha(&self, c: usize) -> bool {
+        self.alpha == Some(c)
+    }
+
+    // The named channel as f64. "gray" is the mean of every channel except alpha and derived ones
+    // unless the image has a channel of that name.
+    pub fn plane(&self, name: &str) -> Option<Array2<f64>> {
+        if let Some(c) = self.channel_names.iter().position(|n| n == name) {
+            return Some(self.channel(c).mapv(|v| v as f64));
+        }
+        if name != "gray" {
+            return None;
+        }
+        let measured = self.channel_names.len() - self.derived_channels;
+        let colour: Vec<usize> = (0..measured).filter(|&c| !self.is_alpha(c)).collect();
+        let mut sum = Array2::<f64>::zeros((self.height(), self.width()));
+        for &c in &colour {
+            sum += &self.channel(c).mapv(|v| v as f64);
+        }
+        Some(sum / colour.len().max(1) as f64)
+    }
+
+    // Stores `plane` as channel `c`, clipped to the values an integer sample type can hold
+    pub fn set_channel(&mut self, c: usize, plane: &Array2<f64>) {
+        let (min, max) = match self.pixel_type {
+            PixelType::U8 => (0.0, 255.0),
+            PixelType::U16 => (0.0, 65535.0),
+            PixelType::F32 => (f64::NEG_INFINITY, f64::INFINITY),
+        };
+        let clipped = plane.mapv(|v| v.clamp(min, max) as f32);
+        self.data.index_axis_mut(Axis(0), c).assign(&clipped);
+    }
+
+    // Intensities `plane`, the channel called `name`, can take: [0, 2^bits) for the channels of
+    // integer images, and the plane's own range for floating point images and derived channels,
+    // which have no fixed scale.
+    pub fn value_range(&self, name: &str, plane: &Array2<f64>) -> (f64, f64) {
+        let measured = self.channel_names.len() - self.derived_channels;
+        let derived = self.channel_names.iter().position(|n| n == name).is_some_and(|c| c >= measured);
+        match self.pixel_type {
+            PixelType::U8 if !derived => (0.0, 256.0),
+            PixelType::U16 if !derived => (0.0, 65536.0),
+            _ => {
+                let lo = plane.iter().cloned().fold(f64::INFINITY, f64::min);
+                let hi = plane.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                if lo.is_finite() && hi > lo { (lo, hi) } else { (0.0, 1.0) }
+            }
+        }
+    }
+}
+
+// Default names of the samples of a pixel with `samples` interleaved values
+fn sample_names(samples: usize) -> Vec<String> {
+    let names: &[&str] = match samples {
+        1 => &["gray"],
+        2 => &["gray", "alpha"],
+        3 => &["red", "green", "blue"],
+        4 => &["red", "green", "blue", "alpha"],
+        _ => &[],
+    };
+    if names.is_empty() {
+        (0..samples).map(|c| format!("c{}", c)).collect()
+    } else {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+}
+
+// (height, width, samples) interleaved samples into (channel, y, x)
+fn planar(samples: Vec<f32>, height: usize, width: usize, channels: usize) -> Result<Array3<f32>, Box<dyn Error>> {
+    let interleaved = Array3::from_shape_vec((height, width, channels), samples)?;
+    Ok(interleaved.permuted_axes([2, 0, 1]).as_standard_layout().to_owned())
+}
+
+fn from_dynamic(img: DynamicImage) -> Result<MultiChannelImage, Box<dyn Error>> {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let channels = img.color().channel_count() as usize;
+    let (samples, pixel_type): (Vec<f32>, PixelType) = match &img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => {
+            (img.as_bytes().iter().map(|&v| v as f32).collect(), PixelType::U8)
+        }
+        DynamicImage::ImageLuma16(i) => (i.as_raw().iter().map(|&v| v as f32).collect(), PixelType::U16),
+        DynamicImage::ImageLumaA16(i) => (i.as_raw().iter().map(|&v| v as f32).collect(), PixelType::U16),
+        DynamicImage::ImageRgb16(i) => (i.as_raw().iter().map(|&v| v as f32).collect(), PixelType::U16),
+        DynamicImage::ImageRgba16(i) => (i.as_raw().iter().map(|&v| v as f32).collect(), PixelType::U16),
+        DynamicImage::ImageRgb32F(i) => (i.as_raw().clone(), PixelType::F32),
+        DynamicImage::ImageRgba32F(i) => (i.as_raw().clone(), PixelType::F32),
+        _ => return Err(format!("Unsupported pixel format {:?}", img.color()).into()),
+    };
+    let alpha = img.color().has_alpha().then_some(channels - 1);
+    Ok(MultiChannelImage { data: planar(samples, height, width, channels)?, channel_names: sample_names(channels), pixel_type, derived_channels: 0, alpha })
+}
+
+fn load_tiff(path: &str) -> Result<MultiChannelImage, Box<dyn Error>> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    let description = decoder.get_tag_ascii_string(Tag::ImageDescription).unwrap_or_default();
+
+    let mut pages: Vec<Array3<f32>> = Vec::new();
+    let mut page_names: Vec<Vec<String>> = Vec::new();
+    let mut pixel_type = PixelType::U8;
+    let mut page_alpha: Vec<bool> = Vec::new();
+    let mut size: Option<(u32, u32)> = None;
+    loop {
+        let (width, height) = decoder.dimensions()?;
+        if size.is_some_and(|s| s != (width, height)) {
+            return Err(format!("TIFF pages of {} have different sizes", path).into());
+        }
+        size = Some((width, height));
+
+        let colour_type = decoder.colortype()?;
+        page_alpha.push(matches!(colour_type, ColorType::GrayA(_) | ColorType::RGBA(_)));
+        let samples = match colour_type {
+            ColorType::Gray(_) => 1,
+            ColorType::GrayA(_) => 2,
+            ColorType::RGB(_) | ColorType::YCbCr(_) => 3,
+            ColorType::RGBA(_) | ColorType::CMYK(_) => 4,
+            other => return Err(format!("Unsupported TIFF color type {:?}", other).into()),
+        };
+        let (values, page_type): (Vec<f32>, PixelType) = match decoder.read_image()? {
+            DecodingResult::U8(v) => (v.into_iter().map(|v| v as f32).collect(), PixelType::U8),
+            DecodingResult::U16(v) => (v.into_iter().map(|v| v as f32).collect(), PixelType::U16),
+            DecodingResult::U32(v) => (v.into_iter().map(|v| v as f32).collect(), PixelType::F32),
+            DecodingResult::U64(v) => (v.into_iter().map(|v| v as f32).collect(), PixelType::F32),
+            DecodingResult::I8(v) => (v.into_iter().map(|v| v as f32).collect(), PixelType::F32),
+            DecodingResult::I16(v) => (v.into_iter().map(|v| v as f32).collect(), PixelType::F32),
+            DecodingResult::I32(v) => (v.into_iter().map(|v| v as f32).collect(), PixelType::F32),
+            DecodingResult::I64(v) => (v.into_iter().map(|v| v as f32).collect(), PixelType::F32),
+            DecodingResult::F32(v) => (v, PixelType::F32),
+            DecodingResult::F64(v) => (v.into_iter().map(|v| v as f32).collect(), PixelType::F32),
+        };
+        // The whole stack is kept at the widest type of any page
+        if page_type > pixel_type {
+            pixel_type = page_type;
+        }
+        pages.push(planar(values, height as usize, width as usize, samples)?);
+        page_names.push(sample_names(samples));
+
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+
+    let views: Vec<_> = pages.iter().map(|page| page.view()).collect();
+    let data = ndarray::concatenate(Axis(0), &views)?;
+    let channels = data.shape()[0];
+
+    let ome_names = ome_channel_names(&description);
+    let channel_names = if ome_names.len() == channels {
+        ome_names
+    } else if pages.len() == 1 {
+        page_names.remove(0)
+    } else {
+        (0..channels).map(|c| format!("c{}", c)).collect()
+    };
+    // A stack of pages is a stack of channels; only a single gray+alpha or RGBA page has an alpha
+    let alpha = (page_alpha == [true]).then_some(channels - 1);
+    Ok(MultiChannelImage { data, channel_names, pixel_type, derived_channels: 0, alpha })
+}
+
+// Name attributes of the <Channel> elements of an OME-XML description
+fn ome_channel_names(xml: &str) -> Vec<String> {
+    xml.split("<Channel").skip(1)
+        .filter_map(|element| {
+            let element = &element[..element.find('>')?];
+            let start = element.find(" Name=\"")? + 7;
+            let end = element[start..].find('"')? + start;
+            Some(element[start..end].to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::panel::MarkerPanel;
+
+    #[test]
+    fn alpha_is_tracked_by_position_not_name() {
+        let rgba = image::RgbaImage::from_fn(2, 2, |x, y| image::Rgba([10, 20, 30 + (x + y) as u8 * 30, 255]));
+        let mut image = from_dynamic(DynamicImage::ImageRgba8(rgba)).unwrap();
+        assert_eq!(image.alpha, Some(3));
+
+        // A marker called alpha is measured; the renamed opacity plane still isn't
+        let names = ["DAPI", "CD3", "alpha", "opacity"].map(String::from);
+        MarkerPanel::from_names(&names).apply(&mut image).unwrap();
+        assert!(!image.is_alpha(2) && image.is_alpha(3));
+        let gray = image.plane("gray").unwrap();
+        assert_eq!(gray[[0, 0]], 20.0);
+        assert_eq!(gray[[1, 1]], 40.0);
+    }
+}
@@ -0,0 +1,206 @@
+/*
+Cell adjacency graph and the neighbourhood features built on it: how crowded or isolated each
+cell is, and feature values averaged over its neighbours.
+*/
+
+use crate::calibration::{Calibration, Quantity};
+use crate::voronoi::{self, VoronoiRegion};
+
+use std::error::Error;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Adjacency {
+    Delaunay,  // Cells joined by an edge of the Delaunay triangulation of the centroids
+    Voronoi,   // Cells whose clipped Voronoi regions share an edge, which drops the long
+               // triangulation edges across the tissue boundary
+}
+
+impl FromStr for Adjacency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delaunay" => Ok(Adjacency::Delaunay),
+            "voronoi" => Ok(Adjacency::Voronoi),
+            _ => Err(format!("Unknown adjacency '{}', expected delaunay or voronoi", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NeighbourGraph {
+    pub edges: Vec<(usize, usize)>,   // Cell indices, smaller first, sorted
+    pub adjacency: Vec<Vec<usize>>,   // adjacency[i] lists the neighbours of cell i
+}
+
+impl NeighbourGraph {
+    pub fn from_edges(num_cells: usize, mut edges: Vec<(usize, usize)>) -> NeighbourGraph {
+        edges.retain(|&(a, b)| a != b);
+        for edge in edges.iter_mut() {
+            *edge = (edge.0.min(edge.1), edge.0.max(edge.1));
+        }
+        edges.sort();
+        edges.dedup();
+
+        let mut adjacency = vec![Vec::new(); num_cells];
+        for &(a, b) in &edges {
+            adjacency[a].push(b);
+            adjacency[b].push(a);
+        }
+        for neighbours in adjacency.iter_mut() {
+            neighbours.sort();
+        }
+        NeighbourGraph { edges, adjacency }
+    }
+}
+
+// Adjacency graph of the cell centroids. Cells with identical centroids share their neighbours.
+pub fn neighbour_graph(sites: &[(f64, f64)], regions: &[VoronoiRegion], adjacency: Adjacency) -> NeighbourGraph {
+    let mut edges: Vec<(usize, usize)> = match adjacency {
+        Adjacency::Voronoi => regions.iter()
+            .enumerate()
+            .flat_map(|(i, region)| region.neighbours.iter().map(move |&j| (i, j)))
+            .collect(),
+        Adjacency::Delaunay => {
+            let (triangles, _) = voronoi::delaunay(sites);
+            if triangles.is_empty() {
+                // All centroids on one line: the triangulation degenerates to a path along it
+                let mut order: Vec<usize> = (0..sites.len()).collect();
+                order.sort_by(|&a, &b| sites[a].0.total_cmp(&sites[b].0).then(sites[a].1.total_cmp(&sites[b].1)));
+                order.dedup_by(|a, b| sites[*a] == sites[*b]);
+                order.windows(2).map(|w| (w[0], w[1])).collect()
+            } else {
+                triangles.iter().flat_map(|t| [(t[0], t[1]), (t[1], t[2]), (t[2], t[0])]).collect()
+            }
+        }
+    };
+
+    // The triangulation keeps one of each set of duplicate centroids; the others take its edges
+    if adjacency == Adjacency::Delaunay {
+        let mut twin_edges = Vec::new();
+        for i in 0..sites.len() {
+            let Some(first) = (0..i).find(|&j| sites[j] == sites[i]) else { continue };
+            for &(a, b) in &edges {
+                if a == first {
+                    twin_edges.push((i, b));
+                } else if b == first {
+                    twin_edges.push((a, i));
+                }
+            }
+        }
+        edges.extend(twin_edges);
+    }
+
+    NeighbourGraph::from_edges(sites.len(), edges)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Neighbourhood {
+    pub neighbour_count: f64,
+    pub neighbour_distance_mean: f64,     // Mean distance to the graph neighbours
+    pub nearest_neighbour_distance: f64,  // Distance to the closest other cell
+    pub neighbours_within_radius: f64,    // Other cells closer than the radius, graph or not
+}
+
+impl Neighbourhood {
+    pub const NAMES: [&str; 4] = ["neighbour_count", "neighbour_distance_mean", "nearest_neighbour_distance", "neighbours_within_radius"];
+    pub const QUANTITIES: [Quantity; 4] = [Quantity::Dimensionless, Quantity::Length, Quantity::Length, Quantity::Dimensionless];
+
+    pub fn values(&self) -> [f64; 4] {
+        [self.neighbour_count, self.neighbour_distance_mean, self.nearest_neighbour_distance, self.neighbours_within_radius]
+    }
+}
+
+// Crowding features of every cell. Distances and the radius are in pixels; a cell without
+// neighbours, or alone in the image, gets 0 for the distances it has nothing to measure against.
+pub fn neighbourhoods(sites: &[(f64, f64)], graph: &NeighbourGraph, radius: f64) -> Vec<Neighbourhood> {
+    let distance = |a: usize, b: usize| ((sites[a].0 - sites[b].0).powi(2) + (sites[a].1 - sites[b].1).powi(2)).sqrt();
+
+    (0..sites.len())
+        .map(|i| {
+            let neighbours = &graph.adjacency[i];
+            let neighbour_distance_mean = if neighbours.is_empty() {
+                0.0
+            } else {
+                neighbours.iter().map(|&j| distance(i, j)).sum::<f64>() / neighbours.len() as f64
+            };
+
+            let others: Vec<f64> = (0..sites.len()).filter(|&j| j != i).map(|j| distance(i, j)).collect();
+            let nearest_neighbour_distance = others.iter().cloned().fold(f64::INFINITY, f64::min);
+
+            Neighbourhood {
+                neighbour_count: neighbours.len() as f64,
+                neighbour_distance_mean,
+                nearest_neighbour_distance: if nearest_neighbour_distance.is_finite() { nearest_neighbour_distance } else { 0.0 },
+                neighbours_within_radius: others.iter().filter(|&&d| d <= radius).count() as f64,
+            }
+        })
+        .collect()
+}
+
+// Mean of `values` over each cell's neighbours. A cell without neighbours keeps its own value,
+// since its neighbourhood is just itself.
+pub fn neighbour_average(values: &[f64], graph: &NeighbourGraph) -> Vec<f64> {
+    graph.adjacency.iter()
+        .enumerate()
+        .map(|(i, neighbours)| {
+            if neighbours.is_empty() {
+                values[i]
+            } else {
+                neighbours.iter().map(|&j| values[j]).sum::<f64>() / neighbours.len() as f64
+            }
+        })
+        .collect()
+}
+
+// Writes the graph as one row per edge: the two cell IDs and the distance between their
+// centroids in the calibration's units.
+pub fn write_edge_list(path: &str, graph: &NeighbourGraph, cell_ids: &[usize], sites: &[(f64, f64)], calibration: &Calibration) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["source".to_string(), "target".to_string(), format!("distance{}", calibration.suffix(Quantity::Length))])?;
+    for &(a, b) in &graph.edges {
+        let distance = ((sites[a].0 - sites[b].0).powi(2) + (sites[a].1 - sites[b].1).powi(2)).sqrt();
+        writer.write_record([cell_ids[a].to_string(), cell_ids[b].to_string(), calibration.convert(Quantity::Length, distance).to_string()])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Corners of a 10-pixel square, in the middle of the quadrants of a 20 x 20 image
+    const GRID: [(f64, f64); 4] = [(5.0, 5.0), (15.0, 5.0), (5.0, 15.0), (15.0, 15.0)];
+
+    #[test]
+    fn grid_neighbours_follow_the_adjacency() {
+        let regions = voronoi::voronoi_regions(&GRID, 20, 20, None);
+
+        // Diagonal regions only meet at the centre point, so Voronoi adjacency is the four sides
+        let voronoi = neighbour_graph(&GRID, &regions, Adjacency::Voronoi);
+        assert_eq!(voronoi.edges, vec![(0, 1), (0, 2), (1, 3), (2, 3)]);
+        // The triangulation of a square adds one of its diagonals
+        let delaunay = neighbour_graph(&GRID, &regions, Adjacency::Delaunay);
+        assert_eq!(delaunay.edges.len(), 5);
+        assert!(voronoi.edges.iter().all(|edge| delaunay.edges.contains(edge)));
+
+        for radius in [10.0, 15.0] {
+            for cell in neighbourhoods(&GRID, &voronoi, radius) {
+                assert_eq!(cell.neighbour_count, 2.0);
+                assert_eq!(cell.nearest_neighbour_distance, 10.0);
+                // The diagonal, 14.1 pixels away, only counts within the larger radius
+                assert_eq!(cell.neighbours_within_radius, if radius < 14.0 { 2.0 } else { 3.0 });
+            }
+        }
+    }
+
+    #[test]
+    fn averages_are_over_neighbours_only() {
+        let graph = NeighbourGraph::from_edges(5, vec![(0, 1), (0, 2), (1, 3), (2, 3)]);
+        let averaged = neighbour_average(&[1.0, 2.0, 3.0, 4.0, 7.0], &graph);
+        // Cell 4 has no neighbours and keeps its own value
+        assert_eq!(averaged, vec![2.5, 2.5, 2.5, 2.5, 7.0]);
+    }
+}
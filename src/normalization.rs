@@ -0,0 +1,384 @@
+/*
+Feature normalisation before clustering. Every feature gets a chain of steps chosen by name:
+min-max, z-score, robust (median/MAD) scaling, log1p or arcsinh transforms and quantile
+normalisation across images, optionally after clipping outliers. Steps are fitted on this image's
+cells or on a whole batch, and the fitted parameters can be saved and applied unchanged to new
+images.
+*/
+
+use crate::extract_features::percentile;
+
+use std::error::Error;
+use std::str::FromStr;
+
+// Scales a median absolute deviation to the standard deviation of normally distributed data
+const MAD_TO_STD: f64 = 1.4826;
+// Quantiles of the reference distribution of a quantile normalisation, evenly spaced from the
+// minimum to the maximum
+const QUANTILE_STEPS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    MinMax,        // (x - min) / (max - min), into [0, 1] on the fitted data
+    ZScore,        // (x - mean) / standard deviation
+    Robust,        // (x - median) / (1.4826 MAD), which outliers barely move
+    Log1p,         // sign(x) ln(1 + |x|)
+    Arcsinh(f64),  // asinh(x / cofactor), the usual transform for cytometry intensities
+    Quantile,      // Each image's distribution mapped onto the images' average distribution
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("arcsinh", cofactor)) => match cofactor.parse::<f64>() {
+                Ok(c) if c > 0.0 => Ok(Method::Arcsinh(c)),
+                _ => Err(format!("Invalid arcsinh cofactor '{}', expected a positive number", cofactor)),
+            },
+            _ => match s {
+                "min-max" => Ok(Method::MinMax),
+                "z-score" => Ok(Method::ZScore),
+                "robust" => Ok(Method::Robust),
+                "log1p" => Ok(Method::Log1p),
+                "arcsinh" => Ok(Method::Arcsinh(5.0)),
+                "quantile" => Ok(Method::Quantile),
+                _ => Err(format!("Unknown normalisation '{}', expected min-max, z-score, robust, log1p, arcsinh[:cofactor] or quantile", s)),
+            },
+        }
+    }
+}
+
+// Which methods each feature gets: `z-score,area*=robust,channel_*=arcsinh+z-score` gives
+// features matching a pattern (`*` matches anything) the chain of the first rule that matches
+// and every other feature the default chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NormalizationSpec {
+    pub default: Vec<Method>,
+    pub rules: Vec<(String, Vec<Method>)>,
+}
+
+impl FromStr for NormalizationSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chain = |c: &str| c.split('+').map(|m| m.trim().parse()).collect::<Result<Vec<Method>, String>>();
+        let mut spec = NormalizationSpec { default: vec![Method::MinMax], rules: Vec::new() };
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.split_once('=') {
+                Some((pattern, methods)) => spec.rules.push((pattern.trim().to_string(), chain(methods)?)),
+                None => spec.default = chain(entry)?,
+            }
+        }
+        Ok(spec)
+    }
+}
+
+impl NormalizationSpec {
+    pub fn methods(&self, feature: &str) -> &[Method] {
+        self.rules.iter()
+            .find(|(pattern, _)| matches_pattern(pattern, feature))
+            .map_or(&self.default, |(_, methods)| methods)
+    }
+}
+
+// Glob match where `*` stands for any run of characters
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == name;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !name.starts_with(first) || !name[first.len()..].ends_with(last) {
+        return false;
+    }
+    // Middle parts in order, each after the one before
+    let mut rest = &name[first.len()..name.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+// One fitted step of a feature's normalisation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Clip { low: f64, high: f64 },
+    MinMax { min: f64, max: f64 },
+    ZScore { mean: f64, std: f64 },
+    Robust { median: f64, scale: f64 },
+    Log1p,
+    Arcsinh { cofactor: f64 },
+    Quantile { reference: Vec<f64> },
+}
+
+impl Step {
+    // Fits `method` to the values of every image. A feature with no spread maps to 0, like a
+    // constant column did before; robust scaling falls back to the standard deviation when over
+    // half the values tie. Quantile normalisation averages the images' quantiles; every other
+    // method pools the images' values.
+    fn fit(method: Method, images: &[Vec<f64>]) -> Step {
+        let values: Vec<f64> = images.concat();
+        let n = values.len().max(1) as f64;
+        let mut sorted = values.clone();
+        sorted.sort_by(f64::total_cmp);
+        match method {
+            Method::MinMax => Step::MinMax {
+                min: sorted.first().copied().unwrap_or(0.0),
+                max: sorted.last().copied().unwrap_or(0.0),
+            },
+            Method::ZScore | Method::Robust => {
+                let mean = values.iter().sum::<f64>() / n;
+                let std = (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+                if method == Method::ZScore {
+                    return Step::ZScore { mean, std };
+                }
+                let median = percentile(&sorted, 50.0);
+                let mut deviations: Vec<f64> = values.iter().map(|v| (v - median).abs()).collect();
+                deviations.sort_by(f64::total_cmp);
+                let mad = MAD_TO_STD * percentile(&deviations, 50.0);
+                Step::Robust { median, scale: if mad > 0.0 { mad } else { std } }
+            }
+            Method::Log1p => Step::Log1p,
+            Method::Arcsinh(cofactor) => Step::Arcsinh { cofactor },
+            Method::Quantile => {
+                let images: Vec<Vec<f64>> = images.iter()
+                    .filter(|values| !values.is_empty())
+                    .map(|values| {
+                        let mut sorted = values.clone();
+                        sorted.sort_by(f64::total_cmp);
+                        sorted
+                    })
+                    .collect();
+                let reference = (0..=QUANTILE_STEPS)
+                    .map(|q| {
+                        let p = 100.0 * q as f64 / QUANTILE_STEPS as f64;
+                        images.iter().map(|sorted| percentile(sorted, p)).sum::<f64>() / images.len().max(1) as f64
+                    })
+                    .collect();
+                Step::Quantile { reference }
+            }
+        }
+    }
+
+    // Applies the step to one image's values of the feature. Quantile normalisation places every
+    // value by its rank among the image's values, so it needs the whole image at once.
+    pub fn apply(&self, values: &[f64]) -> Vec<f64> {
+        let map = |f: &dyn Fn(f64) -> f64| values.iter().map(|&v| f(v)).collect();
+        match self {
+            Step::Clip { low, high } => map(&|v| v.clamp(*low, *high)),
+            Step::MinMax { min, max } => {
+                map(&|v| if (max - min).abs() < f64::EPSILON { 0.0 } else { (v - min) / (max - min) })
+            }
+            Step::ZScore { mean, std } => map(&|v| if *std > 0.0 { (v - mean) / std } else { 0.0 }),
+            Step::Robust { median, scale } => map(&|v| if *scale > 0.0 { (v - median) / scale } else { 0.0 }),
+            Step::Log1p => map(&|v| v.signum() * v.abs().ln_1p()),
+            Step::Arcsinh { cofactor } => map(&|v| (v / cofactor).asinh()),
+            Step::Quantile { reference } => {
+                let mut sorted = values.to_vec();
+                sorted.sort_by(f64::total_cmp);
+                map(&|v| reference_quantile(reference, quantile_position(&sorted, v)))
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Step::Clip { .. } => "clip",
+            Step::MinMax { .. } => "min-max",
+            Step::ZScore { .. } => "z-score",
+            Step::Robust { .. } => "robust",
+            Step::Log1p => "log1p",
+            Step::Arcsinh { .. } => "arcsinh",
+            Step::Quantile { .. } => "quantile",
+        }
+    }
+
+    fn parameters(&self) -> Vec<f64> {
+        match self {
+            Step::Clip { low, high } => vec![*low, *high],
+            Step::MinMax { min, max } => vec![*min, *max],
+            Step::ZScore { mean, std } => vec![*mean, *std],
+            Step::Robust { median, scale } => vec![*median, *scale],
+            Step::Log1p => Vec::new(),
+            Step::Arcsinh { cofactor } => vec![*cofactor],
+            Step::Quantile { reference } => reference.clone(),
+        }
+    }
+
+    fn from_parameters(name: &str, p: &[f64]) -> Result<Step, Box<dyn Error>> {
+        let expect = |count: usize| if p.len() == count { Ok(()) } else {
+            Err(format!("A {} step has {} parameters, not {}", name, count, p.len()))
+        };
+        Ok(match name {
+            "clip" => { expect(2)?; Step::Clip { low: p[0], high: p[1] } }
+            "min-max" => { expect(2)?; Step::MinMax { min: p[0], max: p[1] } }
+            "z-score" => { expect(2)?; Step::ZScore { mean: p[0], std: p[1] } }
+            "robust" => { expect(2)?; Step::Robust { median: p[0], scale: p[1] } }
+            "log1p" => { expect(0)?; Step::Log1p }
+            "arcsinh" => { expect(1)?; Step::Arcsinh { cofactor: p[0] } }
+            "quantile" if !p.is_empty() => Step::Quantile { reference: p.to_vec() },
+            _ => return Err(format!("Unknown normalisation step '{}'", name).into()),
+        })
+    }
+}
+
+// Where `v` falls among increasing `quantiles`, from 0 at the first to 1 at the last. Values equal
+// to a run of tied quantiles land in the middle of the run.
+fn quantile_position(quantiles: &[f64], v: f64) -> f64 {
+    let steps = (quantiles.len() - 1).max(1) as f64;
+    let below = quantiles.partition_point(|&q| q < v);
+    let at_or_below = quantiles.partition_point(|&q| q <= v);
+    if at_or_below > below {
+        return (below + at_or_below - 1) as f64 / 2.0 / steps;
+    }
+    if below == 0 {
+        return 0.0;
+    }
+    if below == quantiles.len() {
+        return 1.0;
+    }
+    let (lo, hi) = (quantiles[below - 1], quantiles[below]);
+    (below - 1) as f64 / steps + (v - lo) / (hi - lo) / steps
+}
+
+// The value at fraction `position` of evenly spaced `quantiles`, interpolated between them
+fn reference_quantile(quantiles: &[f64], position: f64) -> f64 {
+    let x = position.clamp(0.0, 1.0) * (quantiles.len() - 1) as f64;
+    let (i, t) = (x.floor() as usize, x.fract());
+    match quantiles.get(i + 1) {
+        Some(next) => quantiles[i] + t * (next - quantiles[i]),
+        None => quantiles[i],
+    }
+}
+
+// The fitted normalisation of one feature
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureNormalizer {
+    pub feature: String,
+    pub steps: Vec<Step>,
+}
+
+impl FeatureNormalizer {
+    // Fits each method in turn to the output of the ones before, given the feature's values in
+    // every image. With `clip` = (low, high) percentiles, values are first clipped to those
+    // percentiles of the fitted data.
+    pub fn fit(feature: &str, images: &[Vec<f64>], methods: &[Method], clip: Option<(f64, f64)>) -> FeatureNormalizer {
+        let mut normalizer = FeatureNormalizer { feature: feature.to_string(), steps: Vec::new() };
+        let mut images = images.to_vec();
+        if let Some((low, high)) = clip {
+            let mut sorted = images.concat();
+            sorted.sort_by(f64::total_cmp);
+            normalizer.steps.push(Step::Clip { low: percentile(&sorted, low), high: percentile(&sorted, high) });
+        }
+        for &method in methods {
+            if let Some(last) = normalizer.steps.last() {
+                images = images.iter().map(|values| last.apply(values)).collect();
+            }
+            normalizer.steps.push(Step::fit(method, &images));
+        }
+        normalizer
+    }
+
+    // Normalises one image's values of the feature
+    pub fn apply(&self, values: &[f64]) -> Vec<f64> {
+        self.steps.iter().fold(values.to_vec(), |values, step| step.apply(&values))
+    }
+}
+
+// Fits every feature to its methods in `spec`. `images[m][j]` is feature `names[j]` of the cells
+// of image m.
+pub fn fit_normalizers(names: &[String], images: &[Vec<Vec<f64>>], spec: &NormalizationSpec, clip: Option<(f64, f64)>) -> Vec<FeatureNormalizer> {
+    names.iter()
+        .enumerate()
+        .map(|(j, name)| {
+            let values: Vec<Vec<f64>> = images.iter().map(|columns| columns[j].clone()).collect();
+            FeatureNormalizer::fit(name, &values, spec.methods(name), clip)
+        })
+        .collect()
+}
+
+// Normalises the feature columns of one image by name, one row per cell. Every feature needs a
+// normaliser.
+pub fn normalize(names: &[String], columns: &[Vec<f64>], normalizers: &[FeatureNormalizer]) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+    let fitted: Vec<&FeatureNormalizer> = names.iter()
+        .map(|name| normalizers.iter().find(|n| &n.feature == name)
+            .ok_or_else(|| format!("The normalisation parameters have no feature {}", name)))
+        .collect::<Result<_, _>>()?;
+    let normalized: Vec<Vec<f64>> = fitted.iter().zip(columns).map(|(normalizer, column)| normalizer.apply(column)).collect();
+    let cells = columns.first().map_or(0, |c| c.len());
+    Ok((0..cells).map(|i| normalized.iter().map(|column| column[i]).collect()).collect())
+}
+
+// Writes one row per step: feature, its position in the chain, the method and its parameters
+// separated by spaces
+pub fn write_parameters(path: &str, normalizers: &[FeatureNormalizer]) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["feature", "step", "method", "parameters"])?;
+    for normalizer in normalizers {
+        for (i, step) in normalizer.steps.iter().enumerate() {
+            let parameters: Vec<String> = step.parameters().iter().map(|p| p.to_string()).collect();
+            writer.write_record([normalizer.feature.as_str(), &i.to_string(), step.name(), &parameters.join(" ")])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Reads parameters written by `write_parameters`
+pub fn read_parameters(path: &str) -> Result<Vec<FeatureNormalizer>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let mut normalizers: Vec<FeatureNormalizer> = Vec::new();
+    for (line, record) in reader.records().enumerate() {
+        let record = record?;
+        let context = |e: &dyn std::fmt::Display| format!("{} line {}: {}", path, line + 2, e);
+        let (feature, method) = (&record[0], &record[2]);
+        let parameters: Vec<f64> = record[3].split_whitespace()
+            .map(|p| p.parse::<f64>())
+            .collect::<Result<_, _>>()
+            .map_err(|e| context(&e))?;
+        let step = Step::from_parameters(method, &parameters).map_err(|e| context(&e))?;
+        match normalizers.last_mut() {
+            Some(last) if last.feature == feature => last.steps.push(step),
+            _ => normalizers.push(FeatureNormalizer { feature: feature.to_string(), steps: vec![step] }),
+        }
+    }
+    Ok(normalizers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantile_normalisation_maps_images_onto_one_distribution() {
+        let first: Vec<f64> = (0..50).map(|i| i as f64).collect();
+        let second: Vec<f64> = (0..50).rev().map(|i| 10.0 + 3.0 * i as f64).collect();
+        let normalizer = FeatureNormalizer::fit("area", &[first.clone(), second.clone()], &[Method::Quantile], None);
+
+        let (a, b) = (normalizer.apply(&first), normalizer.apply(&second));
+        // Cells of equal rank get equal values, halfway between the two images' values
+        for i in 0..50 {
+            assert!((a[i] - b[49 - i]).abs() < 1e-9);
+            assert!((a[i] - (i as f64 + 10.0 + 3.0 * i as f64) / 2.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn parameters_survive_a_round_trip() {
+        let spec: NormalizationSpec = "z-score,area=log1p+robust,channel_*=arcsinh:2+quantile".parse().unwrap();
+        let names = vec!["area".to_string(), "channel_0_mean".to_string(), "eccentricity".to_string()];
+        let image = |offset: f64| (0..3).map(|j| (0..20).map(|i| offset + (i * (j + 2)) as f64 * 0.7).collect()).collect();
+        let normalizers = fit_normalizers(&names, &[image(0.0), image(4.0)], &spec, Some((1.0, 99.0)));
+
+        let path = std::env::temp_dir().join(format!("normalization-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_parameters(path, &normalizers).unwrap();
+        let read = read_parameters(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(read.unwrap(), normalizers);
+    }
+}
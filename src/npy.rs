@@ -0,0 +1,536 @@
+/*
+Just enough of the .npy format (and of Python's pickle protocol) to pull integer label masks
+out of NumPy files: plain arrays saved with `np.save(path, masks)` and the pickled dictionaries
+Cellpose writes to `_seg.npy`.
+*/
+
+use std::error::Error;
+use std::fs;
+
+// A 2D integer array, row-major: `data[y * width + x]`
+#[derive(Debug, Clone)]
+pub struct NpyArray {
+    pub shape: Vec<usize>,
+    pub data: Vec<u32>,
+}
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+// Reads a label mask from a .npy file: either a plain integer array, or a pickled dict with a
+// `masks` entry (a Cellpose `_seg.npy`).
+pub fn read_npy_masks(path: &str) -> Result<NpyArray, Box<dyn Error>> {
+    let bytes = fs::read(path)?;
+    if !bytes.starts_with(MAGIC) || bytes.len() < 10 {
+        return Err(format!("{} is not a .npy file", path).into());
+    }
+
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 => {
+            let len = bytes.get(8..12).ok_or("Truncated .npy header")?;
+            (u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize, 12)
+        }
+        v => return Err(format!("Unsupported .npy version {}", v).into()),
+    };
+    let header = std::str::from_utf8(bytes.get(header_start..header_start + header_len).ok_or("Truncated .npy header")?)?;
+    let body = &bytes[header_start + header_len..];
+
+    let descr = header_value(header, "descr")
+        .and_then(|v| v.strip_prefix('\''))
+        .and_then(|v| v.split('\'').next())
+        .ok_or("No 'descr' in .npy header")?;
+    let fortran_order = header_value(header, "fortran_order").is_some_and(|v| v.starts_with("True"));
+    let shape = parse_shape(header_value(header, "shape").ok_or("No 'shape' in .npy header")?)?;
+
+    if descr.ends_with('O') {
+        // Object array: the payload is a pickle, for Cellpose a 0-d array holding a dict
+        let value = Unpickler::new(body).load()?;
+        let masks = find_key(&value, "masks").ok_or("No 'masks' entry in the pickled .npy")?;
+        match masks {
+            Value::Array(array) => array.to_u32(),
+            _ => Err("'masks' is not an array".into()),
+        }
+    } else {
+        let array = RawArray { dtype: descr.to_string(), shape, fortran_order, data: body.to_vec() };
+        array.to_u32()
+    }
+}
+
+// Returns the text following `'key':` in a .npy header dict, up to the end of the header.
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{}'", key))? + key.len() + 2;
+    let rest = header[start..].trim_start().strip_prefix(':')?;
+    Some(rest.trim_start())
+}
+
+fn parse_shape(value: &str) -> Result<Vec<usize>, Box<dyn Error>> {
+    let end = value.find(')').ok_or("Malformed shape in .npy header")?;
+    value[1..end]
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| Ok(s.parse::<usize>()?))
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct RawArray {
+    dtype: String,  // NumPy type string such as "<u2", "|u1" or "i4"
+    shape: Vec<usize>,
+    fortran_order: bool,
+    data: Vec<u8>,
+}
+
+impl RawArray {
+    fn to_u32(&self) -> Result<NpyArray, Box<dyn Error>> {
+        let (big_endian, code) = match self.dtype.chars().next() {
+            Some('>') => (true, &self.dtype[1..]),
+            Some('<') | Some('|') | Some('=') => (false, &self.dtype[1..]),
+            _ => (false, self.dtype.as_str()),
+        };
+        let size: usize = code.get(1..).unwrap_or("").parse().map_err(|_| format!("Unsupported dtype '{}'", self.dtype))?;
+        if !(1..=8).contains(&size) {
+            return Err(format!("Unsupported dtype '{}'", self.dtype).into());
+        }
+        let count: usize = self.shape.iter().product();
+        if self.data.len() < count * size {
+            return Err("Array data is shorter than its shape".into());
+        }
+
+        let mut data = Vec::with_capacity(count);
+        for chunk in self.data.chunks_exact(size).take(count) {
+            let mut buf = [0u8; 8];
+            if big_endian {
+                buf[8 - size..].copy_from_slice(chunk);
+                buf.reverse();
+            } else {
+                buf[..size].copy_from_slice(chunk);
+            }
+            let raw = u64::from_le_bytes(buf);
+            let value = match code.as_bytes()[0] {
+                b'u' | b'b' => raw,
+                b'i' => {
+                    // Sign-extend; negative labels are not cells
+                    let shift = 64 - 8 * size as u32;
+                    let signed = ((raw << shift) as i64) >> shift;
+                    signed.max(0) as u64
+                }
+                _ => return Err(format!("Label masks must be integers, got dtype '{}'", self.dtype).into()),
+            };
+            data.push(u32::try_from(value).map_err(|_| "Label value does not fit in 32 bits")?);
+        }
+
+        let mut shape = self.shape.clone();
+        if self.fortran_order && shape.len() == 2 {
+            // Column-major on disk; transpose into row-major
+            let (rows, cols) = (shape[0], shape[1]);
+            let mut transposed = vec![0; count];
+            for c in 0..cols {
+                for r in 0..rows {
+                    transposed[r * cols + c] = data[c * rows + r];
+                }
+            }
+            data = transposed;
+        }
+        // Cellpose 3D masks are (z, y, x); squeeze singleton leading axes
+        while shape.len() > 2 && shape[0] == 1 {
+            shape.remove(0);
+        }
+        if shape.len() != 2 {
+            return Err(format!("Expected a 2D label mask, got shape {:?}", self.shape).into());
+        }
+
+        Ok(NpyArray { shape, data })
+    }
+}
+
+// The subset of Python values a pickled NumPy dict can contain.
+#[derive(Debug, Clone)]
+enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Bytes(Vec<u8>),
+    Tuple(Vec<Value>),
+    List(Vec<Value>),
+    Dict(Vec<(Value, Value)>),
+    Global(String, String),
+    Dtype(String),
+    Array(RawArray),
+    // Anything reduced from a callable we don't model (e.g. a Python object)
+    Object,
+    // An object array: its items, once `BUILD` fills them in
+    ObjectArray(Vec<Value>),
+    Mark,
+}
+
+impl Value {
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+// Depth-first search for a dict entry with string key `key`.
+fn find_key<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Dict(items) => items.iter()
+            .find(|(k, _)| k.as_str() == Some(key))
+            .map(|(_, v)| v)
+            .or_else(|| items.iter().find_map(|(_, v)| find_key(v, key))),
+        Value::Tuple(items) | Value::List(items) | Value::ObjectArray(items) => items.iter().find_map(|v| find_key(v, key)),
+        _ => None,
+    }
+}
+
+struct Unpickler<'a> {
+    data: &'a [u8],
+    pos: usize,
+    stack: Vec<Value>,
+    memo: Vec<Option<Value>>,
+    // (memo index, stack depth) of memoised objects still on the stack. Pickle memoises an
+    // object before BUILD, APPEND or SETITEM(S) fill it in; the memo copy is refreshed after each.
+    links: Vec<(usize, usize)>,
+}
+
+impl<'a> Unpickler<'a> {
+    fn new(data: &'a [u8]) -> Unpickler<'a> {
+        Unpickler { data, pos: 0, stack: Vec::new(), memo: Vec::new(), links: Vec::new() }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let bytes = self.data.get(self.pos..self.pos + n).ok_or("Truncated pickle")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Box<dyn Error>> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        let b = self.take(8)?;
+        Ok(u64::from_le_bytes(b.try_into()?))
+    }
+
+    fn line(&mut self) -> Result<String, Box<dyn Error>> {
+        let end = self.data[self.pos..].iter().position(|&b| b == b'\n').ok_or("Truncated pickle")?;
+        let line = String::from_utf8_lossy(&self.data[self.pos..self.pos + end]).into_owned();
+        self.pos += end + 1;
+        Ok(line)
+    }
+
+    fn pop(&mut self) -> Result<Value, Box<dyn Error>> {
+        let value = self.stack.pop().ok_or("Pickle stack underflow")?;
+        self.unlink_popped();
+        Ok(value)
+    }
+
+    fn pop_mark(&mut self) -> Result<Vec<Value>, Box<dyn Error>> {
+        let mark = self.stack.iter().rposition(|v| matches!(v, Value::Mark)).ok_or("Pickle MARK not found")?;
+        let items = self.stack.split_off(mark + 1);
+        self.stack.pop();
+        self.unlink_popped();
+        Ok(items)
+    }
+
+    fn unlink_popped(&mut self) {
+        let depth = self.stack.len();
+        self.links.retain(|&(_, d)| d <= depth);
+    }
+
+    // Updates the memo copies of the object on top of the stack after it changed in place
+    fn refresh_memo(&mut self) {
+        let depth = self.stack.len();
+        if let Some(top) = self.stack.last() {
+            for &(index, _) in self.links.iter().filter(|&&(_, d)| d == depth) {
+                self.memo[index] = Some(top.clone());
+            }
+        }
+    }
+
+    fn top(&mut self) -> Result<&mut Value, Box<dyn Error>> {
+        self.stack.last_mut().ok_or_else(|| "Pickle stack underflow".into())
+    }
+
+    fn memo_put(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
+        let value = self.stack.last().ok_or("Pickle stack underflow")?.clone();
+        if self.memo.len() <= index {
+            self.memo.resize(index + 1, None);
+        }
+        self.memo[index] = Some(value);
+        self.links.retain(|&(i, _)| i != index);
+        self.links.push((index, self.stack.len()));
+        Ok(())
+    }
+
+    fn memo_get(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
+        let value = self.memo.get(index).cloned().flatten().ok_or("Pickle memo key not found")?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn load(mut self) -> Result<Value, Box<dyn Error>> {
+        loop {
+            let op = self.u8()?;
+            match op {
+                0x80 => { self.u8()?; }                                   // PROTO
+                0x95 => { self.u64()?; }                                  // FRAME
+                b'.' => return self.pop(),                                // STOP
+                b'(' => self.stack.push(Value::Mark),                     // MARK
+                b'N' => self.stack.push(Value::None),
+                0x88 => self.stack.push(Value::Bool(true)),
+                0x89 => self.stack.push(Value::Bool(false)),
+                b'J' => { let v = self.u32()? as i32; self.stack.push(Value::Int(v as i64)); }
+                b'K' => { let v = self.u8()?; self.stack.push(Value::Int(v as i64)); }
+                b'M' => { let v = self.u16()?; self.stack.push(Value::Int(v as i64)); }
+                0x8a => {                                                 // LONG1
+                    let n = self.u8()? as usize;
+                    let bytes = self.take(n)?;
+                    let mut buf = if bytes.last().is_some_and(|&b| b & 0x80 != 0) { [0xff; 8] } else { [0; 8] };
+                    buf[..n.min(8)].copy_from_slice(&bytes[..n.min(8)]);
+                    self.stack.push(Value::Int(i64::from_le_bytes(buf)));
+                }
+                b'G' => { self.take(8)?; self.stack.push(Value::Object); }  // BINFLOAT, never needed
+                b'X' => { let n = self.u32()? as usize; let s = self.take(n)?; self.stack.push(Value::Str(String::from_utf8_lossy(s).into_owned())); }
+                0x8c => { let n = self.u8()? as usize; let s = self.take(n)?; self.stack.push(Value::Str(String::from_utf8_lossy(s).into_owned())); }
+                0x8d => { let n = self.u64()? as usize; let s = self.take(n)?; self.stack.push(Value::Str(String::from_utf8_lossy(s).into_owned())); }
+                b'B' | b'T' => { let n = self.u32()? as usize; let b = self.take(n)?; self.stack.push(Value::Bytes(b.to_vec())); }
+                b'C' | b'U' => { let n = self.u8()? as usize; let b = self.take(n)?; self.stack.push(Value::Bytes(b.to_vec())); }
+                0x8e => { let n = self.u64()? as usize; let b = self.take(n)?; self.stack.push(Value::Bytes(b.to_vec())); }
+                b')' => self.stack.push(Value::Tuple(Vec::new())),
+                0x85 => { let a = self.pop()?; self.stack.push(Value::Tuple(vec![a])); }
+                0x86 => { let b = self.pop()?; let a = self.pop()?; self.stack.push(Value::Tuple(vec![a, b])); }
+                0x87 => { let c = self.pop()?; let b = self.pop()?; let a = self.pop()?; self.stack.push(Value::Tuple(vec![a, b, c])); }
+                b't' => { let items = self.pop_mark()?; self.stack.push(Value::Tuple(items)); }
+                b']' => self.stack.push(Value::List(Vec::new())),
+                b'l' => { let items = self.pop_mark()?; self.stack.push(Value::List(items)); }
+                b'}' => self.stack.push(Value::Dict(Vec::new())),
+                b'd' => {
+                    let items = self.pop_mark()?;
+                    let pairs = items.chunks(2).filter(|c| c.len() == 2).map(|c| (c[0].clone(), c[1].clone())).collect();
+                    self.stack.push(Value::Dict(pairs));
+                }
+                b'a' => {                                                 // APPEND
+                    let v = self.pop()?;
+                    if let Value::List(list) = self.top()? { list.push(v); }
+                    self.refresh_memo();
+                }
+                b'e' => {                                                 // APPENDS
+                    let items = self.pop_mark()?;
+                    if let Value::List(list) = self.top()? { list.extend(items); }
+                    self.refresh_memo();
+                }
+                b's' => {                                                 // SETITEM
+                    let v = self.pop()?;
+                    let k = self.pop()?;
+                    if let Value::Dict(dict) = self.top()? { dict.push((k, v)); }
+                    self.refresh_memo();
+                }
+                b'u' => {                                                 // SETITEMS
+                    let items = self.pop_mark()?;
+                    if let Value::Dict(dict) = self.top()? {
+                        dict.extend(items.chunks(2).filter(|c| c.len() == 2).map(|c| (c[0].clone(), c[1].clone())));
+                    }
+                    self.refresh_memo();
+                }
+                b'c' => {                                                 // GLOBAL
+                    let module = self.line()?;
+                    let name = self.line()?;
+                    self.stack.push(Value::Global(module, name));
+                }
+                0x93 => {                                                 // STACK_GLOBAL
+                    let name = self.pop()?;
+                    let module = self.pop()?;
+                    let (Some(module), Some(name)) = (module.as_str(), name.as_str()) else {
+                        return Err("STACK_GLOBAL expects two strings".into());
+                    };
+                    self.stack.push(Value::Global(module.to_string(), name.to_string()));
+                }
+                b'R' | 0x81 => {                                          // REDUCE, NEWOBJ
+                    let args = self.pop()?;
+                    let callable = self.pop()?;
+                    self.stack.push(reduce(callable, args));
+                }
+                b'b' => {                                                 // BUILD
+                    let state = self.pop()?;
+                    let target = self.top()?;
+                    build(target, state)?;
+                    self.refresh_memo();
+                }
+                b'q' => { let i = self.u8()? as usize; self.memo_put(i)?; }
+                b'r' => { let i = self.u32()? as usize; self.memo_put(i)?; }
+                0x94 => { let i = self.memo.len(); self.memo_put(i)?; }   // MEMOIZE
+                b'h' => { let i = self.u8()? as usize; self.memo_get(i)?; }
+                b'j' => { let i = self.u32()? as usize; self.memo_get(i)?; }
+                b'0' => { self.pop()?; }                                  // POP
+                b'2' => { let v = self.top()?.clone(); self.stack.push(v); }  // DUP
+                _ => return Err(format!("Unsupported pickle opcode 0x{:02x} at byte {}", op, self.pos - 1).into()),
+            }
+        }
+    }
+}
+
+fn reduce(callable: Value, args: Value) -> Value {
+    let Value::Global(module, name) = callable else {
+        return Value::Object;
+    };
+    let args = match args {
+        Value::Tuple(items) => items,
+        other => vec![other],
+    };
+    match (module.as_str(), name.as_str()) {
+        // numpy.dtype('u2', False, True); byte order is set by a later BUILD
+        (_, "dtype") if module.starts_with("numpy") => {
+            let code = args.first().and_then(|a| a.as_str()).unwrap_or("O").to_string();
+            Value::Dtype(code)
+        }
+        // numpy.core.multiarray._reconstruct(ndarray, (0,), b'b'); the contents come from BUILD
+        (_, "_reconstruct") if module.starts_with("numpy") => Value::ObjectArray(Vec::new()),
+        _ => Value::Object,
+    }
+}
+
+fn build(target: &mut Value, state: Value) -> Result<(), Box<dyn Error>> {
+    match target {
+        Value::Dtype(code) => {
+            // State is (version, byteorder, ...)
+            if let Value::Tuple(items) = state
+                && let Some(Value::Str(order)) = items.get(1)
+                && order != "|"
+                && !code.starts_with(['<', '>', '|'])
+            {
+                *code = format!("{}{}", order, code);
+            }
+        }
+        Value::ObjectArray(_) => {
+            // State is (version, shape, dtype, is_fortran, data), or the same without `version`
+            let Value::Tuple(mut items) = state else {
+                return Err("Unexpected ndarray state".into());
+            };
+            if matches!(items.first(), Some(Value::Int(_))) {
+                items.remove(0);
+            }
+            if items.len() < 4 {
+                return Err("Unexpected ndarray state".into());
+            }
+            let data = items.pop().unwrap_or(Value::None);
+            let fortran_order = matches!(items.pop(), Some(Value::Bool(true)) | Some(Value::Int(1)));
+            let dtype = items.pop();
+            let shape = match items.pop() {
+                Some(Value::Tuple(dims)) => dims.iter()
+                    .map(|d| match d { Value::Int(n) => Ok(*n as usize), _ => Err("Non-integer ndarray dimension") })
+                    .collect::<Result<Vec<_>, _>>()?,
+                _ => return Err("Unexpected ndarray shape".into()),
+            };
+            *target = match (dtype, data) {
+                (Some(Value::Dtype(code)), Value::Bytes(bytes)) if code.trim_start_matches(['<', '>', '|', '=']) != "O" => {
+                    Value::Array(RawArray { dtype: code, shape, fortran_order, data: bytes })
+                }
+                (_, Value::List(items)) => Value::ObjectArray(items),
+                _ => Value::Object,
+            };
+        }
+        Value::Dict(dict) => {
+            if let Value::Dict(items) = state {
+                dict.extend(items);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes a version 1.0 .npy file to the temp directory and returns its path
+    fn write_npy(name: &str, descr: &str, shape: &str, body: &[u8]) -> String {
+        let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+        while !(MAGIC.len() + 4 + header.len() + 1).is_multiple_of(64) {
+            header.push(' ');
+        }
+        header.push('\n');
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([1, 0]);
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        bytes.extend(body);
+        let path = std::env::temp_dir().join(format!("decode_{}_{}.npy", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn global(module: &str, name: &str) -> Vec<u8> {
+        [b"c", module.as_bytes(), b"\n", name.as_bytes(), b"\n"].concat()
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        [&[0x8c, s.len() as u8][..], s.as_bytes()].concat()
+    }
+
+    // numpy.core.multiarray._reconstruct(ndarray, (0,), b'b'), ready for BUILD
+    fn reconstruct() -> Vec<u8> {
+        [global("numpy.core.multiarray", "_reconstruct"), global("numpy", "ndarray"), b"K\x00\x85C\x01b\x87R".to_vec()].concat()
+    }
+
+    #[test]
+    fn reads_a_plain_uint16_array() {
+        let values: [u16; 6] = [0, 1, 2, 300, 0, 65535];
+        let body: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let path = write_npy("plain", "<u2", "(2, 3)", &body);
+        let array = read_npy_masks(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(array.shape, vec![2, 3]);
+        assert_eq!(array.data, vec![0, 1, 2, 300, 0, 65535]);
+    }
+
+    #[test]
+    fn reads_masks_from_a_pickled_seg_dict() {
+        // {'outlines': >u2 array, 'masks': >u2 array}; the masks reuse the memoised dtype, which
+        // only gets its big-endian byte order from BUILD after it was memoised
+        let big_endian_array = |dtype: Vec<u8>, values: &[u16]| -> Vec<u8> {
+            let data: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+            [reconstruct(), b"(K\x01K\x02K\x02\x86".to_vec(), dtype, b"\x89B".to_vec(), (data.len() as u32).to_le_bytes().to_vec(), data, b"tb".to_vec()].concat()
+        };
+        let dtype = [global("numpy", "dtype"), string("u2"), b"\x89\x88\x87R\x94(K\x03".to_vec(), string(">"), b"NNNJ\xff\xff\xff\xffJ\xff\xff\xff\xffK\x00tb".to_vec()].concat();
+        let seg = [
+            b"}(".to_vec(),
+            string("outlines"), big_endian_array(dtype, &[0, 0, 9, 0]),
+            string("masks"), big_endian_array(b"h\x00".to_vec(), &[0, 258, 258, 7]),
+            b"u".to_vec(),
+        ].concat();
+        let object_dtype = [global("numpy", "dtype"), string("O8"), b"\x89\x88\x87R(K\x03".to_vec(), string("|"), b"NNNJ\xff\xff\xff\xffJ\xff\xff\xff\xffK?tb".to_vec()].concat();
+        let pickle = [b"\x80\x02".to_vec(), reconstruct(), b"(K\x01)".to_vec(), object_dtype, b"\x89]".to_vec(), seg, b"atb.".to_vec()].concat();
+
+        let path = write_npy("seg", "|O", "()", &pickle);
+        let array = read_npy_masks(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(array.shape, vec![2, 2]);
+        assert_eq!(array.data, vec![0, 258, 258, 7]);
+    }
+
+    #[test]
+    fn empty_label_masks_are_an_error() {
+        for (name, shape) in [("no_columns", "(3, 0)"), ("no_rows", "(0, 3)")] {
+            let path = write_npy(name, "<u2", shape, &[]);
+            let mask = crate::segmentation::read_npy_label_mask(&path);
+            fs::remove_file(&path).ok();
+            assert!(mask.is_err(), "{} was read", shape);
+        }
+    }
+}
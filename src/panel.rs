@@ -0,0 +1,60 @@
+/*
+Marker panels: which marker each image channel shows, so features are named after DAPI or Ki67
+rather than a channel position.
+*/
+
+use crate::multichannel::MultiChannelImage;
+
+use std::error::Error;
+
+#[derive(Debug, Clone, Default)]
+pub struct MarkerPanel {
+    pub markers: Vec<(usize, String)>,  // (channel index from 0, marker name)
+}
+
+impl MarkerPanel {
+    // Reads a CSV with `channel` and `marker` columns; other columns, like a fluorophore or
+    // antibody clone, are ignored.
+    pub fn read(path: &str) -> Result<MarkerPanel, Box<dyn Error>> {
+        let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(path)?;
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("{} has no '{}' column", path, name));
+        let (channel, marker) = (column("channel")?, column("marker")?);
+
+        let mut markers = Vec::new();
+        for (line, record) in reader.records().enumerate() {
+            let record = record?;
+            let index: usize = record[channel].parse()
+                .map_err(|_| format!("{} line {}: channel '{}' is not an index", path, line + 2, &record[channel]))?;
+            markers.push((index, record[marker].to_string()));
+        }
+        Ok(MarkerPanel { markers })
+    }
+
+    // Marker names for channels 0, 1, 2, ... in order
+    pub fn from_names(names: &[String]) -> MarkerPanel {
+        MarkerPanel { markers: names.iter().cloned().enumerate().collect() }
+    }
+
+    // Renames the image's channels after their markers. Channels the panel leaves out keep their
+    // names; the result must still name every channel uniquely.
+    pub fn apply(&self, image: &mut MultiChannelImage) -> Result<(), Box<dyn Error>> {
+        let mut names = image.channel_names.clone();
+        for (index, marker) in &self.markers {
+            if marker.is_empty() {
+                return Err(format!("Channel {} has an empty marker name", index).into());
+            }
+            let name = names.get_mut(*index)
+                .ok_or_else(|| format!("The panel names channel {} but the image has {} channels", index, image.channel_names.len()))?;
+            *name = marker.clone();
+        }
+        for (i, name) in names.iter().enumerate() {
+            if names[..i].contains(name) {
+                return Err(format!("Two channels would be called {}", name).into());
+            }
+        }
+        image.channel_names = names;
+        Ok(())
+    }
+}
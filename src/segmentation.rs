@@ -0,0 +1,199 @@
+/*
+Loading segmentations. Cellpose `_outlines.txt` files, integer label masks (8/16-bit PNG or
+TIFF, one cell ID per pixel) and Cellpose `_seg.npy` files all end up as the same thing:
+a list of cells, each with a stable ID and an outline.
+*/
+
+use crate::extract_features::{read_outlines, Outline};
+use crate::npy;
+
+use image::{DynamicImage, ImageReader};
+
+use std::error::Error;
+
+#[derive(Debug, Clone, Default)]
+pub struct Segmentation {
+    // Outline line number for `_outlines.txt`, the label value for masks
+    pub cell_ids: Vec<usize>,
+    pub outlines: Vec<Outline>,
+}
+
+// Integer label image: `labels[y][x]` is the ID of the cell covering (x, y), 0 for background.
+pub type LabelMask = Vec<Vec<u32>>;
+
+// Picks a loader from the file name: `.npy` for Cellpose/NumPy masks, `.txt` for Cellpose
+// outlines, anything else is read as a label-mask image.
+pub fn load_segmentation(path: &str) -> Result<Segmentation, Box<dyn Error>> {
+    let lower = path.to_lowercase();
+    if lower.ends_with(".npy") {
+        Ok(label_mask_to_segmentation(&read_npy_label_mask(path)?))
+    } else if lower.ends_with(".txt") {
+        let outlines = read_outlines(path)?;
+        // Keep the line numbers as IDs even if some lines are empty
+        let (cell_ids, outlines) = outlines.into_iter()
+            .enumerate()
+            .filter(|(_, outline)| !outline.is_empty())
+            .map(|(i, outline)| (i + 1, outline))
+            .unzip();
+        Ok(Segmentation { cell_ids, outlines })
+    } else {
+        Ok(label_mask_to_segmentation(&read_label_mask_image(path)?))
+    }
+}
+
+// Reads a single-channel 8- or 16-bit integer image as a label mask. Pixel values are used
+// as-is (no rescaling between bit depths).
+pub fn read_label_mask_image(path: &str) -> Result<LabelMask, Box<dyn Error>> {
+    let img = ImageReader::open(path)?.with_guessed_format()?.decode()?;
+    let (width, height) = (img.width() as usize, img.height() as usize);
+
+    let values: Vec<u32> = match img {
+        DynamicImage::ImageLuma8(buffer) => buffer.into_raw().into_iter().map(u32::from).collect(),
+        DynamicImage::ImageLuma16(buffer) => buffer.into_raw().into_iter().map(u32::from).collect(),
+        other => return Err(format!("{} is not a single-channel integer label mask ({:?})", path, other.color()).into()),
+    };
+
+    Ok(values.chunks(width).take(height).map(|row| row.to_vec()).collect())
+}
+
+// Reads any image as a binary mask: true wherever the (grayscale) pixel is nonzero.
+pub fn read_binary_mask(path: &str) -> Result<Vec<Vec<bool>>, Box<dyn Error>> {
+    let img = ImageReader::open(path)?.with_guessed_format()?.decode()?.to_luma16();
+    let width = img.width() as usize;
+    Ok(img.into_raw().chunks(width).map(|row| row.iter().map(|&v| v > 0).collect()).collect())
+}
+
+// Gives every 8-connected group of true pixels its own label, 1, 2, ... in raster order.
+pub fn label_components(binary: &[Vec<bool>]) -> LabelMask {
+    let height = binary.len();
+    let width = binary.first().map_or(0, |row| row.len());
+    let mut labels: LabelMask = vec![vec![0; width]; height];
+    let mut next = 0;
+
+    for y in 0..height {
+        for x in 0..width {
+            if !binary[y][x] || labels[y][x] != 0 {
+                continue;
+            }
+            next += 1;
+            labels[y][x] = next;
+            let mut stack = vec![(x, y)];
+            while let Some((cx, cy)) = stack.pop() {
+                for ny in cy.saturating_sub(1)..=(cy + 1).min(height - 1) {
+                    for nx in cx.saturating_sub(1)..=(cx + 1).min(width - 1) {
+                        if binary[ny][nx] && labels[ny][nx] == 0 {
+                            labels[ny][nx] = next;
+                            stack.push((nx, ny));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    labels
+}
+
+// Reads the `masks` array of a Cellpose `_seg.npy`, or a plain integer array saved with `np.save`.
+pub fn read_npy_label_mask(path: &str) -> Result<LabelMask, Box<dyn Error>> {
+    let array = npy::read_npy_masks(path)?;
+    let [height, width] = array.shape[..] else {
+        return Err(format!("{} is not a 2D label mask (shape {:?})", path, array.shape).into());
+    };
+    if height == 0 || width == 0 {
+        return Err(format!("{} is an empty label mask (shape {:?})", path, array.shape).into());
+    }
+    if array.data.len() != height * width {
+        return Err(format!("{} holds {} labels, not {} x {}", path, array.data.len(), height, width).into());
+    }
+    Ok(array.data.chunks(width).map(|row| row.to_vec()).collect())
+}
+
+// Converts a label mask into one outline per label, in increasing label order. Each outline
+// is the cell's boundary pixels traced clockwise, like a Cellpose outline. Cells made of several
+// disconnected pieces are outlined by the piece that comes first in raster order.
+pub fn label_mask_to_segmentation(mask: &LabelMask) -> Segmentation {
+    let height = mask.len();
+    let width = mask.first().map_or(0, |row| row.len());
+
+    // First pixel of every label in raster order is its top-left boundary pixel
+    let mut starts: Vec<(u32, (i32, i32))> = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    for (y, row) in mask.iter().enumerate() {
+        for (x, &label) in row.iter().enumerate() {
+            if label != 0 && seen.insert(label) {
+                starts.push((label, (x as i32, y as i32)));
+            }
+        }
+    }
+    starts.sort();
+
+    let mut segmentation = Segmentation::default();
+    for (label, start) in starts {
+        segmentation.cell_ids.push(label as usize);
+        segmentation.outlines.push(trace_boundary(mask, width, height, label, start));
+    }
+    segmentation
+}
+
+// Moore-neighbour boundary tracing from the top-left pixel of a region, stopping when the
+// trace leaves the start pixel the same way it did the first time (Jacob's criterion).
+fn trace_boundary(mask: &LabelMask, width: usize, height: usize, label: u32, start: (i32, i32)) -> Outline {
+    // Clockwise neighbours in image coordinates (y down), starting west
+    const NEIGHBOURS: [(i32, i32); 8] = [(-1, 0), (-1, -1), (0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1)];
+    let inside = |(x, y): (i32, i32)| {
+        x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height && mask[y as usize][x as usize] == label
+    };
+    let step = |from: (i32, i32), search_from: usize| {
+        (0..8)
+            .map(|i| (search_from + i) % 8)
+            .map(|dir| (dir, (from.0 + NEIGHBOURS[dir].0, from.1 + NEIGHBOURS[dir].1)))
+            .find(|&(_, pixel)| inside(pixel))
+    };
+
+    let mut outline = vec![start];
+    // Nothing lies west of or above the start pixel, so start searching west
+    let Some(first) = step(start, 0) else {
+        return outline; // Single isolated pixel
+    };
+    let (mut dir, mut current) = first;
+    while outline.len() <= 4 * width * height {
+        // Resume the search at the background pixel we just passed
+        let search_from = if dir % 2 == 0 { (dir + 7) % 8 } else { (dir + 6) % 8 };
+        let Some(next) = step(current, search_from) else { break };
+        if current == start {
+            // Only stop once we would repeat the very first move
+            if next == first {
+                break;
+            }
+        } else {
+            outline.push(current);
+        }
+        (dir, current) = next;
+    }
+
+    outline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outlines_a_concave_cell_and_a_single_pixel() {
+        let mask: LabelMask = vec![
+            vec![7, 0, 7, 0, 0],
+            vec![7, 0, 7, 0, 3],
+            vec![7, 7, 7, 0, 0],
+        ];
+        let segmentation = label_mask_to_segmentation(&mask);
+        assert_eq!(segmentation.cell_ids, vec![3, 7]);
+        assert_eq!(segmentation.outlines[0], vec![(4, 1)]);
+        // Down the left arm and diagonally up the right one, then back down it and along the
+        // bottom: every pixel of the one-pixel-wide U is on its boundary
+        assert_eq!(
+            segmentation.outlines[1],
+            vec![(0, 0), (0, 1), (1, 2), (2, 1), (2, 0), (2, 1), (2, 2), (1, 2), (0, 2), (0, 1)]
+        );
+    }
+}
@@ -0,0 +1,218 @@
+/*
+Spatial organisation of the clusters: Ripley's K and L functions and the pair correlation
+function of the cell centroids, and a permutation test of which clusters neighbour each other
+more (or less) often than chance.
+*/
+
+use crate::calibration::{Calibration, Quantity};
+use crate::neighbours::NeighbourGraph;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+
+use std::error::Error;
+use std::f64::consts::PI;
+
+#[derive(Debug, Clone)]
+pub struct RipleyCurve {
+    pub cluster: Option<usize>,  // None for all cells together
+    pub cells: usize,
+    pub radii: Vec<f64>,         // Pixels
+    pub k: Vec<f64>,             // Square pixels; pi r^2 under complete spatial randomness
+    pub l: Vec<f64>,             // sqrt(K / pi), which is r under complete spatial randomness
+    pub pcf: Vec<f64>,           // Pair correlation over the ring ending at each radius; 1 under CSR
+}
+
+// Ripley's K, L and the pair correlation function of `points` in the width x height image at
+// `steps` evenly spaced radii up to `max_radius`. Edges are corrected with the translation
+// estimator: a pair is weighted by the inverse of the fraction of the image where it could be
+// observed at that offset. Returns None for fewer than two points.
+pub fn ripley(points: &[(f64, f64)], width: f64, height: f64, max_radius: f64, steps: usize) -> Option<RipleyCurve> {
+    let n = points.len();
+    if n < 2 || steps == 0 {
+        return None;
+    }
+    let area = width * height;
+    let radii: Vec<f64> = (1..=steps).map(|m| max_radius * m as f64 / steps as f64).collect();
+
+    // Weighted pair counts per ring (radii[m-1], radii[m]]
+    let mut rings = vec![0.0; steps];
+    for i in 0..n {
+        for j in 0..n {
+            if i == j {
+                continue;
+            }
+            let (dx, dy) = ((points[i].0 - points[j].0).abs(), (points[i].1 - points[j].1).abs());
+            let distance = (dx * dx + dy * dy).sqrt();
+            if distance > max_radius || dx >= width || dy >= height {
+                continue;
+            }
+            let ring = ((distance / max_radius * steps as f64).ceil() as usize).clamp(1, steps) - 1;
+            rings[ring] += area / ((width - dx) * (height - dy));
+        }
+    }
+
+    let scale = area / (n * (n - 1)) as f64;
+    let mut k = Vec::with_capacity(steps);
+    let mut cumulative = 0.0;
+    for ring in &rings {
+        cumulative += ring;
+        k.push(scale * cumulative);
+    }
+    let l = k.iter().map(|k| (k / PI).sqrt()).collect();
+    let pcf = (0..steps)
+        .map(|m| {
+            let (inner, outer) = (if m == 0 { 0.0 } else { radii[m - 1] }, radii[m]);
+            scale * rings[m] / (PI * (outer * outer - inner * inner))
+        })
+        .collect();
+
+    Some(RipleyCurve { cluster: None, cells: n, radii, k, l, pcf })
+}
+
+// Curves for all cells and then for each cluster with at least two cells.
+pub fn ripley_by_cluster(points: &[(f64, f64)], labels: &[usize], k: usize, width: f64, height: f64, max_radius: f64, steps: usize) -> Vec<RipleyCurve> {
+    let mut curves: Vec<RipleyCurve> = ripley(points, width, height, max_radius, steps).into_iter().collect();
+    for cluster in 0..k {
+        let members: Vec<(f64, f64)> = points.iter().zip(labels).filter(|&(_, &l)| l == cluster).map(|(&p, _)| p).collect();
+        if let Some(mut curve) = ripley(&members, width, height, max_radius, steps) {
+            curve.cluster = Some(cluster);
+            curves.push(curve);
+        }
+    }
+    curves
+}
+
+pub fn write_ripley_table(path: &str, curves: &[RipleyCurve], calibration: &Calibration) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_path(path)?;
+    let length = calibration.suffix(Quantity::Length);
+    writer.write_record([
+        "cluster".to_string(),
+        "cells".to_string(),
+        format!("r{}", length),
+        format!("k{}", calibration.suffix(Quantity::Area)),
+        format!("l{}", length),
+        format!("l_minus_r{}", length),
+        "pcf".to_string(),
+    ])?;
+    for curve in curves {
+        let cluster = curve.cluster.map_or("all".to_string(), |c| c.to_string());
+        for m in 0..curve.radii.len() {
+            let r = calibration.convert(Quantity::Length, curve.radii[m]);
+            let l = calibration.convert(Quantity::Length, curve.l[m]);
+            writer.write_record([
+                cluster.clone(),
+                curve.cells.to_string(),
+                r.to_string(),
+                calibration.convert(Quantity::Area, curve.k[m]).to_string(),
+                l.to_string(),
+                (l - r).to_string(),
+                curve.pcf[m].to_string(),
+            ])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct Enrichment {
+    pub observed: Vec<Vec<f64>>,  // observed[a][b] is the number of graph edges between clusters a and b
+    pub expected: Vec<Vec<f64>>,  // Mean count over label permutations
+    pub z_score: Vec<Vec<f64>>,   // Positive when a and b neighbour each other more than chance
+}
+
+// Neighbourhood enrichment: counts the neighbour graph's edges between every pair of clusters
+// and compares them with `permutations` random shufflings of the labels over the same graph.
+pub fn neighbourhood_enrichment(graph: &NeighbourGraph, labels: &[usize], k: usize, permutations: usize, rng: &mut StdRng) -> Enrichment {
+    let count = |labels: &[usize]| {
+        let mut counts = vec![vec![0.0; k]; k];
+        for &(a, b) in &graph.edges {
+            let (la, lb) = (labels[a], labels[b]);
+            counts[la][lb] += 1.0;
+            if la != lb {
+                counts[lb][la] += 1.0;
+            }
+        }
+        counts
+    };
+    let observed = count(labels);
+
+    let mut sum = vec![vec![0.0; k]; k];
+    let mut sum_sq = vec![vec![0.0; k]; k];
+    let mut shuffled = labels.to_vec();
+    for _ in 0..permutations {
+        shuffled.shuffle(rng);
+        let counts = count(&shuffled);
+        for a in 0..k {
+            for b in 0..k {
+                sum[a][b] += counts[a][b];
+                sum_sq[a][b] += counts[a][b] * counts[a][b];
+            }
+        }
+    }
+
+    let p = permutations.max(1) as f64;
+    let mut expected = vec![vec![0.0; k]; k];
+    let mut z_score = vec![vec![0.0; k]; k];
+    for a in 0..k {
+        for b in 0..k {
+            let mean = sum[a][b] / p;
+            let sd = (sum_sq[a][b] / p - mean * mean).max(0.0).sqrt();
+            expected[a][b] = mean;
+            // A count that never varies, e.g. for a cluster with one cell, carries no evidence
+            z_score[a][b] = if sd > 0.0 { (observed[a][b] - mean) / sd } else { 0.0 };
+        }
+    }
+
+    Enrichment { observed, expected, z_score }
+}
+
+// One row per ordered pair of clusters.
+pub fn write_enrichment_table(path: &str, enrichment: &Enrichment) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["cluster_a", "cluster_b", "observed_edges", "expected_edges", "z_score"])?;
+    for (a, row) in enrichment.z_score.iter().enumerate() {
+        for (b, z) in row.iter().enumerate() {
+            writer.write_record([
+                a.to_string(),
+                b.to_string(),
+                enrichment.observed[a][b].to_string(),
+                enrichment.expected[a][b].to_string(),
+                z.to_string(),
+            ])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn uniform_points_follow_complete_spatial_randomness() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let points: Vec<(f64, f64)> = (0..2000).map(|_| (rng.random_range(0.0..100.0), rng.random_range(0.0..100.0))).collect();
+        let labels: Vec<usize> = (0..points.len()).map(|i| i % 2).collect();
+
+        let curves = ripley_by_cluster(&points, &labels, 2, 100.0, 100.0, 10.0, 5);
+        assert_eq!(curves.iter().map(|c| c.cluster).collect::<Vec<_>>(), vec![None, Some(0), Some(1)]);
+        for (l, r) in curves[0].l.iter().zip(&curves[0].radii) {
+            assert!((l - r).abs() < 0.2, "L({}) - {} = {}", r, r, l - r);
+        }
+    }
+
+    #[test]
+    fn segregated_clusters_are_enriched_for_themselves() {
+        // A chain of cells, the first half in cluster 0 and the second in cluster 1
+        let graph = NeighbourGraph::from_edges(20, (0..19).map(|i| (i, i + 1)).collect());
+        let labels: Vec<usize> = (0..20).map(|i| i / 10).collect();
+        let enrichment = neighbourhood_enrichment(&graph, &labels, 2, 200, &mut StdRng::seed_from_u64(4));
+        assert_eq!(enrichment.observed, vec![vec![9.0, 1.0], vec![1.0, 9.0]]);
+        assert!(enrichment.z_score[0][0] > 0.0 && enrichment.z_score[1][1] > 0.0);
+        assert!(enrichment.z_score[0][1] < 0.0);
+    }
+}
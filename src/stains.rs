@@ -0,0 +1,327 @@
+/*
+Colour deconvolution of brightfield RGB images (Ruifrok & Johnston 2001). Pixels are converted
+to optical density, where stains add linearly, and unmixed into one density channel per stain,
+e.g. hematoxylin, eosin and DAB. Stain vectors are built in, read from a CSV or estimated from
+the image with Macenko's method.
+*/
+
+use crate::extract_features::percentile;
+use crate::multichannel::{MultiChannelImage, PixelType};
+
+use ndarray::{Array2, Array3, Axis};
+
+use std::error::Error;
+use std::str::FromStr;
+
+// Ruifrok & Johnston's optical density vectors of common stains, as RGB
+const HEMATOXYLIN: [f64; 3] = [0.65, 0.70, 0.29];
+const EOSIN: [f64; 3] = [0.07, 0.99, 0.11];
+const DAB: [f64; 3] = [0.27, 0.57, 0.78];
+
+// Macenko's defaults: pixels with less total density than this are background, and the stain
+// vectors are taken at these percentiles of the angles rather than the extremes
+const MACENKO_BETA: f64 = 0.15;
+const MACENKO_ALPHA: f64 = 1.0;
+
+// Where the stain vectors come from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stains {
+    HematoxylinEosin,
+    HematoxylinDab,
+    HematoxylinEosinDab,
+    Macenko,      // Hematoxylin and eosin vectors estimated from the image
+    File(String), // CSV with stain, r, g and b columns
+}
+
+impl FromStr for Stains {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "he" => Ok(Stains::HematoxylinEosin),
+            "h-dab" => Ok(Stains::HematoxylinDab),
+            "he-dab" => Ok(Stains::HematoxylinEosinDab),
+            "macenko" => Ok(Stains::Macenko),
+            _ if s.to_lowercase().ends_with(".csv") => Ok(Stains::File(s.to_string())),
+            _ => Err(format!("Unknown stains '{}', expected he, h-dab, he-dab, macenko or a CSV of stain vectors", s)),
+        }
+    }
+}
+
+// Unit optical density vectors (r, g, b) of two or three stains
+#[derive(Debug, Clone, Default)]
+pub struct StainMatrix {
+    pub names: Vec<String>,
+    pub vectors: Vec<[f64; 3]>,
+}
+
+impl StainMatrix {
+    // Scales every vector to unit length
+    pub fn new(stains: &[(&str, [f64; 3])]) -> Result<StainMatrix, Box<dyn Error>> {
+        if !(2..=3).contains(&stains.len()) {
+            return Err(format!("Expected 2 or 3 stains, got {}", stains.len()).into());
+        }
+        let mut matrix = StainMatrix::default();
+        for &(name, vector) in stains {
+            let length = norm(vector);
+            if length <= 0.0 || vector.iter().any(|v| !v.is_finite()) {
+                return Err(format!("Stain {} has no optical density vector", name).into());
+            }
+            matrix.names.push(name.to_string());
+            matrix.vectors.push(vector.map(|v| v / length));
+        }
+        Ok(matrix)
+    }
+
+    pub fn builtin(stains: &Stains) -> Option<StainMatrix> {
+        let vectors: &[(&str, [f64; 3])] = match stains {
+            Stains::HematoxylinEosin => &[("hematoxylin", HEMATOXYLIN), ("eosin", EOSIN)],
+            Stains::HematoxylinDab => &[("hematoxylin", HEMATOXYLIN), ("dab", DAB)],
+            Stains::HematoxylinEosinDab => &[("hematoxylin", HEMATOXYLIN), ("eosin", EOSIN), ("dab", DAB)],
+            _ => return None,
+        };
+        StainMatrix::new(vectors).ok()
+    }
+
+    // Reads a CSV with stain, r, g and b columns, one row per stain, e.g. `hematoxylin,0.65,0.70,0.29`
+    pub fn read(path: &str) -> Result<StainMatrix, Box<dyn Error>> {
+        let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(path)?;
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("{} has no '{}' column", path, name));
+        let columns = [column("stain")?, column("r")?, column("g")?, column("b")?];
+
+        let mut stains: Vec<(String, [f64; 3])> = Vec::new();
+        for (line, record) in reader.records().enumerate() {
+            let record = record?;
+            let mut vector = [0.0; 3];
+            for (v, &c) in vector.iter_mut().zip(&columns[1..]) {
+                *v = record[c].parse().map_err(|_| format!("{} line {}: '{}' is not a number", path, line + 2, &record[c]))?;
+            }
+            stains.push((record[columns[0]].to_string(), vector));
+        }
+        let stains: Vec<(&str, [f64; 3])> = stains.iter().map(|(name, vector)| (name.as_str(), *vector)).collect();
+        StainMatrix::new(&stains)
+    }
+}
+
+fn norm(v: [f64; 3]) -> f64 {
+    v.iter().map(|x| x * x).sum::<f64>().sqrt()
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+// The first three colour channels of an RGB image, leaving out alpha and derived channels
+pub fn colour_channels(image: &MultiChannelImage) -> Result<Vec<usize>, Box<dyn Error>> {
+    let measured = image.channel_names.len() - image.derived_channels;
+    let colour: Vec<usize> = (0..measured).filter(|&c| !image.is_alpha(c)).take(3).collect();
+    if colour.len() < 3 {
+        return Err(format!("Colour deconvolution needs an RGB image, this one has channels {}", image.channel_names.join(", ")).into());
+    }
+    Ok(colour)
+}
+
+// I0, the intensity of unstained glass: the largest value of the sample type, or the brightest
+// colour sample of a floating point image
+pub fn white_level(image: &MultiChannelImage) -> Result<f64, Box<dyn Error>> {
+    let white = match image.pixel_type {
+        PixelType::U8 => 255.0,
+        PixelType::U16 => 65535.0,
+        PixelType::F32 => colour_channels(image)?.iter()
+            .flat_map(|&c| image.channel(c).iter().map(|&v| v as f64).collect::<Vec<_>>())
+            .fold(0.0, f64::max),
+    };
+    if white <= 0.0 {
+        return Err("The image is black; it has no optical density".into());
+    }
+    Ok(white)
+}
+
+// Optical density -log10(I / I0) of the colour channels, (channel, y, x). Intensities are floored
+// at one count (1/65535 of white for floating point) so black stays finite.
+pub fn optical_density(image: &MultiChannelImage) -> Result<Array3<f64>, Box<dyn Error>> {
+    let colour = colour_channels(image)?;
+    let white = white_level(image)?;
+    let floor = if image.pixel_type == PixelType::F32 { white / 65535.0 } else { 1.0 };
+
+    let mut od = Array3::<f64>::zeros((3, image.height(), image.width()));
+    for (i, &c) in colour.iter().enumerate() {
+        od.index_axis_mut(Axis(0), i).assign(&image.channel(c).mapv(|v| -((v as f64).max(floor) / white).log10()));
+    }
+    Ok(od)
+}
+
+// Estimates hematoxylin and eosin vectors with Macenko's method: the stained pixels' densities
+// are projected onto the plane of their two main principal directions, and the stains are the
+// directions at the extreme angles (robust percentiles) within that plane. Hematoxylin is the
+// one with more red density.
+pub fn macenko(od: &Array3<f64>) -> Result<StainMatrix, Box<dyn Error>> {
+    let pixels: Vec<[f64; 3]> = od.lanes(Axis(0))
+        .into_iter()
+        .map(|lane| [lane[0], lane[1], lane[2]])
+        .filter(|&p| norm(p) >= MACENKO_BETA)
+        .collect();
+    if pixels.len() < 3 {
+        return Err("Too few stained pixels to estimate stain vectors".into());
+    }
+
+    let n = pixels.len() as f64;
+    let mean: [f64; 3] = std::array::from_fn(|i| pixels.iter().map(|p| p[i]).sum::<f64>() / n);
+    let mut covariance = [[0.0; 3]; 3];
+    for p in &pixels {
+        for i in 0..3 {
+            for j in 0..3 {
+                covariance[i][j] += (p[i] - mean[i]) * (p[j] - mean[j]) / (n - 1.0);
+            }
+        }
+    }
+
+    let (values, vectors) = symmetric_eigen(covariance);
+    let mut order = [0, 1, 2];
+    order.sort_by(|&a, &b| values[b].total_cmp(&values[a]));
+    // Densities are positive, so point both directions into the positive octant
+    let direction = |k: usize| {
+        let v: [f64; 3] = std::array::from_fn(|i| vectors[i][order[k]]);
+        if v.iter().sum::<f64>() < 0.0 { v.map(|x| -x) } else { v }
+    };
+    let (first, second) = (direction(0), direction(1));
+
+    let dot = |a: [f64; 3], b: [f64; 3]| a.iter().zip(&b).map(|(x, y)| x * y).sum::<f64>();
+    let mut angles: Vec<f64> = pixels.iter().map(|&p| dot(p, second).atan2(dot(p, first))).collect();
+    angles.sort_by(f64::total_cmp);
+    let at = |angle: f64| std::array::from_fn::<f64, 3, _>(|i| first[i] * angle.cos() + second[i] * angle.sin());
+    let low = at(percentile(&angles, MACENKO_ALPHA));
+    let high = at(percentile(&angles, 100.0 - MACENKO_ALPHA));
+
+    let (hematoxylin, eosin) = if low[0] > high[0] { (low, high) } else { (high, low) };
+    StainMatrix::new(&[("hematoxylin", hematoxylin), ("eosin", eosin)])
+}
+
+// Eigenvalues and eigenvectors (columns) of a symmetric 3x3 matrix by cyclic Jacobi rotations
+fn symmetric_eigen(mut a: [[f64; 3]; 3]) -> ([f64; 3], [[f64; 3]; 3]) {
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    for _ in 0..50 {
+        let off = a[0][1].powi(2) + a[0][2].powi(2) + a[1][2].powi(2);
+        if off < 1e-30 {
+            break;
+        }
+        for (p, q) in [(0, 1), (0, 2), (1, 2)] {
+            if a[p][q].abs() < 1e-300 {
+                continue;
+            }
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+            let (c, s) = (1.0 / (t * t + 1.0).sqrt(), t / (t * t + 1.0).sqrt());
+            // a <- J^T a J with the rotation J in the (p, q) plane
+            for row in a.iter_mut() {
+                let (akp, akq) = (row[p], row[q]);
+                row[p] = c * akp - s * akq;
+                row[q] = s * akp + c * akq;
+            }
+            let (row_p, row_q) = (a[p], a[q]);
+            a[p] = std::array::from_fn(|k| c * row_p[k] - s * row_q[k]);
+            a[q] = std::array::from_fn(|k| s * row_p[k] + c * row_q[k]);
+            for row in v.iter_mut() {
+                let (vkp, vkq) = (row[p], row[q]);
+                row[p] = c * vkp - s * vkq;
+                row[q] = s * vkp + c * vkq;
+            }
+        }
+    }
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+// Inverse of a 3x3 matrix, None if it is singular
+fn invert(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let determinant: f64 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+    if !determinant.is_finite() || determinant.abs() < 1e-12 {
+        return None;
+    }
+    Some(std::array::from_fn(|r| std::array::from_fn(|c| cofactor(c, r) / determinant)))
+}
+
+// Unmixes optical densities into one density per stain: od = sum of density * stain vector. With
+// two stains the third vector is their cross product and takes up the residual. Negative
+// densities, from noise or colours no stain explains, are clipped to 0.
+pub fn deconvolve(od: &Array3<f64>, matrix: &StainMatrix) -> Result<Vec<Array2<f64>>, Box<dyn Error>> {
+    let mut rows = [[0.0; 3]; 3];
+    for (row, vector) in rows.iter_mut().zip(&matrix.vectors) {
+        *row = *vector;
+    }
+    if matrix.vectors.len() == 2 {
+        let residual = cross(rows[0], rows[1]);
+        rows[2] = residual.map(|v| v / norm(residual));
+    }
+    let inverse = invert(rows).ok_or("The stain vectors are linearly dependent")?;
+
+    let (_, height, width) = od.dim();
+    Ok((0..matrix.vectors.len())
+        .map(|s| Array2::from_shape_fn((height, width), |(y, x)| {
+            (0..3).map(|i| od[[i, y, x]] * inverse[i][s]).sum::<f64>().max(0.0)
+        }))
+        .collect())
+}
+
+// Appends one density channel per stain, named after it, as derived channels. Densities aren't
+// counts, so texture features quantise them over their own range; the image's own channels keep
+// the range of their sample type.
+pub fn add_stain_channels(image: &mut MultiChannelImage, densities: &[Array2<f64>], matrix: &StainMatrix) -> Result<(), Box<dyn Error>> {
+    for name in &matrix.names {
+        if image.channel_names.contains(name) {
+            return Err(format!("The image already has a channel called {}", name).into());
+        }
+    }
+    let planes: Vec<Array3<f32>> = densities.iter()
+        .map(|d| d.mapv(|v| v as f32).insert_axis(Axis(0)))
+        .collect();
+    let mut views = vec![image.data.view()];
+    views.extend(planes.iter().map(|p| p.view()));
+    image.data = ndarray::concatenate(Axis(0), &views)?;
+    image.channel_names.extend(matrix.names.iter().cloned());
+    image.derived_channels += matrix.names.len();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deconvolution_recovers_pure_stains() {
+        let matrix = StainMatrix::builtin(&Stains::HematoxylinEosin).unwrap();
+        // One pixel of hematoxylin alone and one of eosin alone
+        let (h, e) = (matrix.vectors[0], matrix.vectors[1]);
+        let od = Array3::from_shape_fn((3, 1, 2), |(i, _, x)| if x == 0 { 0.8 * h[i] } else { 0.3 * e[i] });
+        let densities = deconvolve(&od, &matrix).unwrap();
+        assert!((densities[0][[0, 0]] - 0.8).abs() < 1e-9 && densities[1][[0, 0]].abs() < 1e-9);
+        assert!(densities[0][[0, 1]].abs() < 1e-9 && (densities[1][[0, 1]] - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn stain_channels_leave_the_colour_range_alone() {
+        let mut image = MultiChannelImage {
+            data: Array3::from_shape_fn((3, 2, 2), |(c, y, x)| (60 + 40 * c + 50 * x + 20 * y) as f32),
+            channel_names: ["red", "green", "blue"].map(String::from).to_vec(),
+            pixel_type: PixelType::U8,
+            derived_channels: 0,
+            alpha: None,
+        };
+        let matrix = StainMatrix::builtin(&Stains::HematoxylinEosin).unwrap();
+        let densities = deconvolve(&optical_density(&image).unwrap(), &matrix).unwrap();
+        add_stain_channels(&mut image, &densities, &matrix).unwrap();
+
+        let red = image.plane("red").unwrap();
+        assert_eq!(image.value_range("red", &red), (0.0, 256.0));
+        // Densities are quantised over their own range
+        let hematoxylin = image.plane("hematoxylin").unwrap();
+        let (lo, hi) = image.value_range("hematoxylin", &hematoxylin);
+        assert!(hi > lo && hematoxylin.iter().all(|&v| lo <= v && v <= hi) && hi < 10.0);
+    }
+}
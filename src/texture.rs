@@ -0,0 +1,404 @@
+/*
+Texture features. Gray-level co-occurrence matrices (GLCMs) are counted over the pixels of each
+cell and summarised by Haralick's 13 features, averaged over the requested angles. Local binary
+patterns and gradient statistics describe the same pixels without depending on absolute
+intensities.
+*/
+
+use crate::extract_features::percentile;
+
+use ndarray::Array2;
+
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Haralick {
+    pub angular_second_moment: f64,  // Energy: sum of squared probabilities
+    pub contrast: f64,
+    pub correlation: f64,
+    pub variance: f64,               // Sum of squares
+    pub homogeneity: f64,            // Inverse difference moment
+    pub sum_average: f64,
+    pub sum_variance: f64,
+    pub sum_entropy: f64,
+    pub entropy: f64,
+    pub difference_variance: f64,
+    pub difference_entropy: f64,
+    pub info_correlation_1: f64,     // Informational measures of correlation
+    pub info_correlation_2: f64,
+}
+
+impl Haralick {
+    pub const NAMES: [&'static str; 13] = [
+        "angular_second_moment", "contrast", "correlation", "variance", "homogeneity", "sum_average", "sum_variance",
+        "sum_entropy", "entropy", "difference_variance", "difference_entropy", "info_correlation_1", "info_correlation_2",
+    ];
+
+    // Values in the same order as NAMES
+    pub fn values(&self) -> [f64; 13] {
+        [
+            self.angular_second_moment, self.contrast, self.correlation, self.variance, self.homogeneity,
+            self.sum_average, self.sum_variance, self.sum_entropy, self.entropy, self.difference_variance,
+            self.difference_entropy, self.info_correlation_1, self.info_correlation_2,
+        ]
+    }
+}
+
+// Symmetric, normalised co-occurrence matrix of the quantised `levels` of one cell for pixel
+// pairs at offset (dx, dy). `levels[y][x]` is None outside the cell. None if no pair fits.
+pub fn glcm(levels: &[Vec<Option<usize>>], num_levels: usize, dx: i32, dy: i32) -> Option<Vec<Vec<f64>>> {
+    let mut matrix = vec![vec![0.0; num_levels]; num_levels];
+    let mut pairs = 0.0;
+    for (y, row) in levels.iter().enumerate() {
+        for (x, &level) in row.iter().enumerate() {
+            let Some(a) = level else { continue };
+            let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+            if nx < 0 || ny < 0 || ny as usize >= levels.len() || nx as usize >= row.len() {
+                continue;
+            }
+            if let Some(b) = levels[ny as usize][nx as usize] {
+                matrix[a][b] += 1.0;
+                matrix[b][a] += 1.0;
+                pairs += 2.0;
+            }
+        }
+    }
+    if pairs == 0.0 {
+        return None;
+    }
+    for row in matrix.iter_mut() {
+        for p in row.iter_mut() {
+            *p /= pairs;
+        }
+    }
+    Some(matrix)
+}
+
+// Haralick's features of a normalised co-occurrence matrix, with natural logarithms. Correlation
+// is undefined for a cell of one gray level and reported as 0.
+pub fn haralick(p: &[Vec<f64>]) -> Haralick {
+    let n = p.len();
+    let xlogx = |v: f64| if v > 0.0 { v * v.ln() } else { 0.0 };
+
+    // The matrix is symmetric, so the row and column marginals are the same
+    let px: Vec<f64> = p.iter().map(|row| row.iter().sum()).collect();
+    let mean: f64 = px.iter().enumerate().map(|(i, v)| i as f64 * v).sum();
+    let variance: f64 = px.iter().enumerate().map(|(i, v)| (i as f64 - mean).powi(2) * v).sum();
+
+    let mut sums = vec![0.0; 2 * n - 1];
+    let mut differences = vec![0.0; n];
+    let (mut asm, mut homogeneity, mut cross, mut entropy, mut hxy1, mut hxy2) = (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+    for i in 0..n {
+        for j in 0..n {
+            let v = p[i][j];
+            sums[i + j] += v;
+            differences[i.abs_diff(j)] += v;
+            asm += v * v;
+            homogeneity += v / (1.0 + (i as f64 - j as f64).powi(2));
+            cross += (i * j) as f64 * v;
+            entropy -= xlogx(v);
+            let marginal = px[i] * px[j];
+            if marginal > 0.0 {
+                hxy1 -= v * marginal.ln();
+                hxy2 -= marginal * marginal.ln();
+            }
+        }
+    }
+
+    let sum_average: f64 = sums.iter().enumerate().map(|(k, v)| k as f64 * v).sum();
+    let difference_mean: f64 = differences.iter().enumerate().map(|(k, v)| k as f64 * v).sum();
+    let hx = -px.iter().map(|&v| xlogx(v)).sum::<f64>();
+
+    Haralick {
+        angular_second_moment: asm,
+        contrast: differences.iter().enumerate().map(|(k, v)| (k * k) as f64 * v).sum(),
+        correlation: if variance > 0.0 { (cross - mean * mean) / variance } else { 0.0 },
+        variance,
+        homogeneity,
+        sum_average,
+        sum_variance: sums.iter().enumerate().map(|(k, v)| (k as f64 - sum_average).powi(2) * v).sum(),
+        sum_entropy: -sums.iter().map(|&v| xlogx(v)).sum::<f64>(),
+        entropy,
+        difference_variance: differences.iter().enumerate().map(|(k, v)| (k as f64 - difference_mean).powi(2) * v).sum(),
+        difference_entropy: -differences.iter().map(|&v| xlogx(v)).sum::<f64>(),
+        info_correlation_1: if hx > 0.0 { (entropy - hxy1) / hx } else { 0.0 },
+        info_correlation_2: (1.0 - (-2.0 * (hxy2 - entropy)).exp()).max(0.0).sqrt(),
+    }
+}
+
+// Quantised values of `pixels` on their bounding box, None off the cell. `range` (lo, hi) is
+// split into `num_levels` equal bins, so textures compare across cells; values at or above hi
+// fall in the top bin.
+pub fn quantize_cell(plane: &Array2<f64>, range: (f64, f64), pixels: &[(i32, i32)], num_levels: usize) -> Vec<Vec<Option<usize>>> {
+    let (height, width) = (plane.nrows() as i32, plane.ncols() as i32);
+    let inside: Vec<(i32, i32)> = pixels.iter().copied().filter(|&(x, y)| x >= 0 && y >= 0 && x < width && y < height).collect();
+    if inside.is_empty() {
+        return Vec::new();
+    }
+    let (min_x, min_y) = inside.iter().fold((i32::MAX, i32::MAX), |(mx, my), &(x, y)| (mx.min(x), my.min(y)));
+    let (max_x, max_y) = inside.iter().fold((i32::MIN, i32::MIN), |(mx, my), &(x, y)| (mx.max(x), my.max(y)));
+
+    let (lo, hi) = range;
+    let mut levels = vec![vec![None; (max_x - min_x + 1) as usize]; (max_y - min_y + 1) as usize];
+    for &(x, y) in &inside {
+        let value = (plane[[y as usize, x as usize]] - lo) / (hi - lo);
+        let level = ((value * num_levels as f64).floor().max(0.0) as usize).min(num_levels - 1);
+        levels[(y - min_y) as usize][(x - min_x) as usize] = Some(level);
+    }
+    levels
+}
+
+// Haralick features of one cell at one distance, averaged over `angles` (degrees, counter-
+// clockwise from the x axis as seen on screen). Angles at which no pixel pair fits are left out;
+// a cell too small for any gives all zeros.
+pub fn cell_texture(levels: &[Vec<Option<usize>>], num_levels: usize, distance: u32, angles: &[f64]) -> Haralick {
+    let features: Vec<[f64; 13]> = angles.iter()
+        .filter_map(|angle| {
+            let (sin, cos) = angle.to_radians().sin_cos();
+            // Image rows grow downwards
+            let (dx, dy) = ((distance as f64 * cos).round() as i32, -(distance as f64 * sin).round() as i32);
+            glcm(levels, num_levels, dx, dy).map(|matrix| haralick(&matrix).values())
+        })
+        .collect();
+    if features.is_empty() {
+        return Haralick::default();
+    }
+
+    let mut mean = [0.0; 13];
+    for values in &features {
+        for (m, v) in mean.iter_mut().zip(values) {
+            *m += v / features.len() as f64;
+        }
+    }
+    let [angular_second_moment, contrast, correlation, variance, homogeneity, sum_average, sum_variance,
+        sum_entropy, entropy, difference_variance, difference_entropy, info_correlation_1, info_correlation_2] = mean;
+    Haralick {
+        angular_second_moment, contrast, correlation, variance, homogeneity, sum_average, sum_variance,
+        sum_entropy, entropy, difference_variance, difference_entropy, info_correlation_1, info_correlation_2,
+    }
+}
+
+// Bilinear interpolation of `plane` at (x, y); None outside the pixel centres.
+fn sample(plane: &Array2<f64>, x: f64, y: f64) -> Option<f64> {
+    let (height, width) = plane.dim();
+    if x < 0.0 || y < 0.0 || x > (width - 1) as f64 || y > (height - 1) as f64 {
+        return None;
+    }
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+    let top = plane[[y0, x0]] * (1.0 - fx) + plane[[y0, x1]] * fx;
+    let bottom = plane[[y1, x0]] * (1.0 - fx) + plane[[y1, x1]] * fx;
+    Some(top * (1.0 - fy) + bottom * fy)
+}
+
+// Histogram of rotation-invariant uniform local binary patterns (Ojala et al.) over the cell's
+// pixels, as fractions of the pixels it counts. `points` neighbours are sampled on a circle of
+// `radius` pixels; bin b < points + 1 holds the uniform patterns with b neighbours at least as
+// bright as the centre, and the last bin every non-uniform pattern. Pixels whose circle leaves
+// the image are skipped.
+pub fn lbp_histogram(plane: &Array2<f64>, pixels: &[(i32, i32)], points: usize, radius: f64) -> Vec<f64> {
+    let offsets: Vec<(f64, f64)> = (0..points)
+        .map(|p| {
+            let (sin, cos) = (2.0 * PI * p as f64 / points as f64).sin_cos();
+            (radius * cos, -radius * sin)
+        })
+        .collect();
+
+    let mut histogram = vec![0.0; points + 2];
+    let mut counted = 0.0;
+    for &(x, y) in pixels {
+        let Some(centre) = sample(plane, x as f64, y as f64) else { continue };
+        let bits: Option<Vec<bool>> = offsets.iter()
+            .map(|&(dx, dy)| sample(plane, x as f64 + dx, y as f64 + dy).map(|v| v >= centre))
+            .collect();
+        let Some(bits) = bits else { continue };
+
+        let transitions = (0..points).filter(|&p| bits[p] != bits[(p + 1) % points]).count();
+        let bin = if transitions <= 2 { bits.iter().filter(|&&b| b).count() } else { points + 1 };
+        histogram[bin] += 1.0;
+        counted += 1.0;
+    }
+    if counted > 0.0 {
+        for h in histogram.iter_mut() {
+            *h /= counted;
+        }
+    }
+    histogram
+}
+
+// Derivative kernel for image gradients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GradientOperator {
+    Sobel,
+    Scharr,  // Closer to rotation invariant than Sobel
+}
+
+impl FromStr for GradientOperator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sobel" => Ok(GradientOperator::Sobel),
+            "scharr" => Ok(GradientOperator::Scharr),
+            _ => Err(format!("Unknown gradient operator '{}', expected sobel or scharr", s)),
+        }
+    }
+}
+
+// Horizontal and vertical derivatives of `plane`, replicating the edge pixels. Both kernels are
+// normalised so a unit intensity ramp has a gradient of 1.
+pub fn gradients(plane: &Array2<f64>, operator: GradientOperator) -> (Array2<f64>, Array2<f64>) {
+    let (height, width) = plane.dim();
+    // Smoothing weights across the derivative direction, and their sum times 2
+    let (outer, centre, norm) = match operator {
+        GradientOperator::Sobel => (1.0, 2.0, 8.0),
+        GradientOperator::Scharr => (3.0, 10.0, 32.0),
+    };
+    let at = |x: isize, y: isize| plane[[y.clamp(0, height as isize - 1) as usize, x.clamp(0, width as isize - 1) as usize]];
+
+    let mut gx = Array2::zeros((height, width));
+    let mut gy = Array2::zeros((height, width));
+    for y in 0..height as isize {
+        for x in 0..width as isize {
+            let column = |dx: isize| outer * at(x + dx, y - 1) + centre * at(x + dx, y) + outer * at(x + dx, y + 1);
+            let row = |dy: isize| outer * at(x - 1, y + dy) + centre * at(x, y + dy) + outer * at(x + 1, y + dy);
+            gx[[y as usize, x as usize]] = (column(1) - column(-1)) / norm;
+            gy[[y as usize, x as usize]] = (row(1) - row(-1)) / norm;
+        }
+    }
+    (gx, gy)
+}
+
+// Distribution of the gradient magnitude over one cell.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GradientStats {
+    pub mean: f64,
+    pub std: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+impl GradientStats {
+    pub const NAMES: [&'static str; 5] = ["mean", "std", "median", "p95", "max"];
+
+    // Values in the same order as NAMES
+    pub fn values(&self) -> [f64; 5] {
+        [self.mean, self.std, self.median, self.p95, self.max]
+    }
+}
+
+// Gradient magnitude statistics and a histogram of oriented gradients over the cell's pixels.
+// The histogram has `bins` unsigned orientations over [0, 180) degrees, each pixel voting with its
+// magnitude split between the two nearest bins, and is normalised to sum to 1 (all zeros for a
+// flat cell).
+pub fn gradient_features(gx: &Array2<f64>, gy: &Array2<f64>, pixels: &[(i32, i32)], bins: usize) -> (GradientStats, Vec<f64>) {
+    let (height, width) = (gx.nrows() as i32, gx.ncols() as i32);
+
+    let mut magnitudes = Vec::with_capacity(pixels.len());
+    let mut histogram = vec![0.0; bins];
+    for &(x, y) in pixels {
+        if x < 0 || y < 0 || x >= width || y >= height {
+            continue;
+        }
+        let (dx, dy) = (gx[[y as usize, x as usize]], gy[[y as usize, x as usize]]);
+        let magnitude = (dx * dx + dy * dy).sqrt();
+        magnitudes.push(magnitude);
+
+        if bins > 0 && magnitude > 0.0 {
+            // Rows grow downwards, so flip y for counter-clockwise angles
+            let angle = (-dy).atan2(dx).rem_euclid(PI);
+            let position = angle / PI * bins as f64 - 0.5;
+            let lower = position.floor();
+            let weight = position - lower;
+            histogram[(lower as isize).rem_euclid(bins as isize) as usize] += magnitude * (1.0 - weight);
+            histogram[(lower as isize + 1).rem_euclid(bins as isize) as usize] += magnitude * weight;
+        }
+    }
+
+    let total: f64 = histogram.iter().sum();
+    if total > 0.0 {
+        for h in histogram.iter_mut() {
+            *h /= total;
+        }
+    }
+    if magnitudes.is_empty() {
+        return (GradientStats::default(), histogram);
+    }
+
+    let n = magnitudes.len() as f64;
+    let mean = magnitudes.iter().sum::<f64>() / n;
+    let std = (magnitudes.iter().map(|m| (m - mean).powi(2)).sum::<f64>() / n).sqrt();
+    magnitudes.sort_by(f64::total_cmp);
+    let stats = GradientStats {
+        mean,
+        std,
+        median: percentile(&magnitudes, 50.0),
+        p95: percentile(&magnitudes, 95.0),
+        max: magnitudes[magnitudes.len() - 1],
+    };
+    (stats, histogram)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkerboard_has_known_haralick_features() {
+        let levels: Vec<Vec<Option<usize>>> = (0..6).map(|y| (0..6).map(|x| Some((x + y) % 2)).collect()).collect();
+        let matrix = glcm(&levels, 2, 1, 0).unwrap();
+        assert_eq!(matrix, vec![vec![0.0, 0.5], vec![0.5, 0.0]]);
+
+        let texture = cell_texture(&levels, 2, 1, &[0.0]);
+        assert!((texture.contrast - 1.0).abs() < 1e-12);
+        assert!((texture.homogeneity - 0.5).abs() < 1e-12);
+        assert!((texture.angular_second_moment - 0.5).abs() < 1e-12);
+        assert!((texture.correlation + 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn constant_patch_has_no_contrast() {
+        let levels = vec![vec![Some(3); 5]; 5];
+        let texture = cell_texture(&levels, 8, 1, &[0.0, 45.0, 90.0, 135.0]);
+        assert_eq!(texture.contrast, 0.0);
+        assert_eq!(texture.correlation, 0.0);
+        assert_eq!(texture.angular_second_moment, 1.0);
+        assert!(texture.values().iter().all(|v| v.is_finite()));
+    }
+
+    #[test]
+    fn flat_patch_has_one_uniform_pattern() {
+        let plane = Array2::from_elem((7, 7), 40.0);
+        let pixels: Vec<(i32, i32)> = (2..5).flat_map(|y| (2..5).map(move |x| (x, y))).collect();
+        // Every neighbour ties with the centre, which counts as at least as bright
+        let histogram = lbp_histogram(&plane, &pixels, 8, 1.0);
+        let mut expected = vec![0.0; 10];
+        expected[8] = 1.0;
+        assert_eq!(histogram, expected);
+    }
+
+    #[test]
+    fn ramps_give_their_slope_and_orientation() {
+        let horizontal = Array2::from_shape_fn((6, 8), |(_, x)| 3.0 * x as f64);
+        let vertical = Array2::from_shape_fn((8, 6), |(y, _)| 3.0 * y as f64);
+        // Away from the image edge, where the clamped border halves the slope
+        let pixels: Vec<(i32, i32)> = (1..5).flat_map(|y| (1..5).map(move |x| (x, y))).collect();
+        for operator in [GradientOperator::Sobel, GradientOperator::Scharr] {
+            let (gx, gy) = gradients(&horizontal, operator);
+            let (stats, histogram) = gradient_features(&gx, &gy, &pixels, 9);
+            assert!((stats.mean - 3.0).abs() < 1e-12 && stats.std.abs() < 1e-12);
+            // 0° is the edge between the last bin and the first, so they split the weight
+            assert!((histogram[0] - 0.5).abs() < 1e-12 && (histogram[8] - 0.5).abs() < 1e-12);
+
+            // A gradient pointing down the image is at -90°, the same orientation as 90°: the
+            // centre of bin 4
+            let (gx, gy) = gradients(&vertical, operator);
+            let (stats, histogram) = gradient_features(&gx, &gy, &pixels, 9);
+            assert!((stats.max - 3.0).abs() < 1e-12);
+            assert!((histogram[4] - 1.0).abs() < 1e-12);
+        }
+    }
+}
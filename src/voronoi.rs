@@ -0,0 +1,458 @@
+/*
+Exact Voronoi tessellation of the cell centroids. A Delaunay triangulation (Bowyer-Watson)
+gives every site its candidate neighbours; the site's Voronoi region is then the image
+rectangle clipped by the perpendicular bisector of each neighbour, intersected with the tissue
+outline when one is given.
+*/
+
+use crate::extract_features::Outline;
+
+// A point in pixel coordinates
+type Point2 = (f64, f64);
+
+// Largest distance, in pixels, between a traced tissue outline and its simplified polygon
+const TISSUE_TOLERANCE: f64 = 1.0;
+// Lengths and cross products below this are treated as zero
+const EPSILON: f64 = 1e-9;
+
+// What produced an edge of a clipped Voronoi region
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edge {
+    Boundary,         // The image rectangle or the tissue outline
+    Neighbour(usize), // The bisector with another site
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VoronoiRegion {
+    pub area: f64,                 // Square pixels
+    pub perimeter: f64,            // Pixels
+    pub vertices: usize,
+    pub touches_boundary: bool,    // Whether the region is cut by the image edge or tissue outline
+    pub neighbours: Vec<usize>,    // Sites whose regions share an edge with this one
+}
+
+// Delaunay triangulation of `points` as triples of point indices. Duplicate points are only
+// inserted once. Alongside the triangles, returns every edge between two input points that
+// appeared in the final triangulation including the triangles still attached to the
+// super-triangle, which is a superset of the Delaunay edges even for collinear input.
+pub fn delaunay(points: &[Point2]) -> (Vec<[usize; 3]>, Vec<(usize, usize)>) {
+    let n = points.len();
+    if n < 2 {
+        return (Vec::new(), Vec::new());
+    }
+
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+    for &(x, y) in points {
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x);
+        max_y = max_y.max(y);
+    }
+    let span = (max_x - min_x).max(max_y - min_y).max(1.0);
+    let (cx, cy) = ((min_x + max_x) / 2.0, (min_y + max_y) / 2.0);
+
+    // Vertices n, n+1 and n+2 form a triangle far outside every point
+    let mut vertices: Vec<Point2> = points.to_vec();
+    vertices.push((cx - 100.0 * span, cy - 100.0 * span));
+    vertices.push((cx + 100.0 * span, cy - 100.0 * span));
+    vertices.push((cx, cy + 100.0 * span));
+    let mut triangles: Vec<[usize; 3]> = vec![[n, n + 1, n + 2]];
+
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| points[a].0.total_cmp(&points[b].0).then(points[a].1.total_cmp(&points[b].1)));
+    order.dedup_by(|a, b| points[*a] == points[*b]);
+
+    for &p in &order {
+        let point = vertices[p];
+        let (bad, good): (Vec<[usize; 3]>, Vec<[usize; 3]>) = triangles
+            .into_iter()
+            .partition(|t| in_circumcircle(vertices[t[0]], vertices[t[1]], vertices[t[2]], point));
+        triangles = good;
+
+        // The boundary of the cavity is every edge that belongs to exactly one bad triangle
+        let mut edges: Vec<(usize, usize)> = Vec::new();
+        for t in &bad {
+            for (a, b) in [(t[0], t[1]), (t[1], t[2]), (t[2], t[0])] {
+                let key = (a.min(b), a.max(b));
+                if let Some(i) = edges.iter().position(|&e| e == key) {
+                    edges.swap_remove(i);
+                } else {
+                    edges.push(key);
+                }
+            }
+        }
+        for (a, b) in edges {
+            triangles.push([a, b, p]);
+        }
+    }
+
+    let mut edges: Vec<(usize, usize)> = Vec::new();
+    for t in &triangles {
+        for (a, b) in [(t[0], t[1]), (t[1], t[2]), (t[2], t[0])] {
+            if a < n && b < n {
+                edges.push((a.min(b), a.max(b)));
+            }
+        }
+    }
+    edges.sort();
+    edges.dedup();
+
+    triangles.retain(|t| t.iter().all(|&v| v < n));
+    (triangles, edges)
+}
+
+fn in_circumcircle(a: Point2, b: Point2, c: Point2, p: Point2) -> bool {
+    let (ax, ay) = (a.0 - p.0, a.1 - p.1);
+    let (bx, by) = (b.0 - p.0, b.1 - p.1);
+    let (cx, cy) = (c.0 - p.0, c.1 - p.1);
+    let det = (ax * ax + ay * ay) * (bx * cy - cx * by)
+        - (bx * bx + by * by) * (ax * cy - cx * ay)
+        + (cx * cx + cy * cy) * (ax * by - bx * ay);
+    // The sign depends on the triangle's orientation
+    let orientation = (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0);
+    if orientation > 0.0 { det > 0.0 } else { det < 0.0 }
+}
+
+// Voronoi regions of `sites` clipped to the width x height image, and to the tissue outlines
+// when given. Sites with identical coordinates share one region.
+//
+// Every tissue outline is the outer boundary of one connected piece of tissue, simplified to
+// within TISSUE_TOLERANCE pixels so its staircase of pixel steps doesn't count as vertices; holes
+// in the tissue are ignored.
+pub fn voronoi_regions(sites: &[Point2], width: usize, height: usize, tissue: Option<&[Outline]>) -> Vec<VoronoiRegion> {
+    let (_, edges) = delaunay(sites);
+    let mut candidates: Vec<Vec<usize>> = vec![Vec::new(); sites.len()];
+    for &(a, b) in &edges {
+        candidates[a].push(b);
+        candidates[b].push(a);
+    }
+    // Sites dropped from the triangulation as duplicates borrow the candidates of their twin
+    for i in 0..sites.len() {
+        if candidates[i].is_empty()
+            && let Some(twin) = (0..sites.len()).find(|&j| j != i && sites[j] == sites[i] && !candidates[j].is_empty())
+        {
+            candidates[i] = candidates[twin].clone();
+        }
+    }
+
+    // Pixel centers sit at integer coordinates, so the image covers [-0.5, width - 0.5]
+    let rectangle = vec![
+        ((-0.5, -0.5), Edge::Boundary),
+        ((width as f64 - 0.5, -0.5), Edge::Boundary),
+        ((width as f64 - 0.5, height as f64 - 0.5), Edge::Boundary),
+        ((-0.5, height as f64 - 0.5), Edge::Boundary),
+    ];
+    let tissue: Option<Vec<Vec<Point2>>> = tissue.map(|outlines| {
+        outlines.iter()
+            .map(|outline| simplify(&outline.iter().map(|&(x, y)| (x as f64, y as f64)).collect::<Vec<_>>(), TISSUE_TOLERANCE))
+            .filter(|polygon| polygon.len() >= 3)
+            .collect()
+    });
+
+    sites.iter()
+        .enumerate()
+        .map(|(i, &site)| {
+            // The Voronoi cell is convex, so clipping the rectangle by half-planes is exact
+            let mut cell = rectangle.clone();
+            for &j in &candidates[i] {
+                if sites[j] != site {
+                    cell = clip_to_bisector(&cell, site, sites[j], j);
+                }
+            }
+            let cell = drop_degenerate(&cell);
+            match &tissue {
+                None => convex_region(&cell),
+                Some(outlines) => {
+                    // Clipping a concave outline leaves zero-area bridges along the bisectors:
+                    // harmless for the area, but the boundary is measured separately
+                    let mut area = 0.0;
+                    for outline in outlines {
+                        let mut piece: Vec<(Point2, Edge)> = outline.iter().map(|&p| (p, Edge::Boundary)).collect();
+                        for &j in &candidates[i] {
+                            if sites[j] != site {
+                                piece = clip_to_bisector(&piece, site, sites[j], j);
+                            }
+                        }
+                        area += shoelace(&piece);
+                    }
+                    tissue_region(&cell, outlines, area)
+                }
+            }
+        })
+        .collect()
+}
+
+// Measures a convex Voronoi cell that isn't cut by tissue.
+fn convex_region(cell: &[(Point2, Edge)]) -> VoronoiRegion {
+    let mut region = VoronoiRegion { area: shoelace(cell), vertices: cell.len(), ..Default::default() };
+    for k in 0..cell.len() {
+        let (a, edge) = cell[k];
+        region.perimeter += distance(a, cell[(k + 1) % cell.len()].0);
+        match edge {
+            Edge::Boundary => region.touches_boundary = true,
+            Edge::Neighbour(j) => region.neighbours.push(j),
+        }
+    }
+    region.neighbours.sort();
+    region.neighbours.dedup();
+    region
+}
+
+// Measures the intersection of a convex Voronoi cell with the tissue from its boundary: the
+// parts of the tissue outlines inside the cell and the parts of the cell's edges inside the
+// tissue. Its corners are the outline vertices inside the cell, the cell vertices inside the
+// tissue and the points where the two boundaries cross.
+fn tissue_region(cell: &[(Point2, Edge)], outlines: &[Vec<Point2>], area: f64) -> VoronoiRegion {
+    let mut region = VoronoiRegion::default();
+    if cell.len() < 3 || area <= 0.0 {
+        return region;
+    }
+    region.area = area;
+    let orientation = signed_area(cell).signum();
+    let inside_cell = |p: Point2| (0..cell.len()).all(|k| orientation * cross(cell[k].0, cell[(k + 1) % cell.len()].0, p) > EPSILON);
+
+    for outline in outlines {
+        for k in 0..outline.len() {
+            let (a, b) = (outline[k], outline[(k + 1) % outline.len()]);
+            if let Some((t0, t1)) = clip_segment_to_convex(a, b, cell, orientation) {
+                region.perimeter += (t1 - t0) * distance(a, b);
+                region.touches_boundary = true;
+            }
+            if inside_cell(a) {
+                region.vertices += 1;
+            }
+        }
+    }
+
+    // Walk around the cell, splitting every edge where it crosses the tissue outlines, and count
+    // the switches between inside and outside the tissue
+    let mut states: Vec<bool> = Vec::new();
+    let mut neighbours: Vec<usize> = Vec::new();
+    for k in 0..cell.len() {
+        let (a, edge) = cell[k];
+        let b = cell[(k + 1) % cell.len()].0;
+        if inside_tissue(a, outlines) {
+            region.vertices += 1;
+        }
+        let mut cuts = vec![0.0, 1.0];
+        for outline in outlines {
+            for m in 0..outline.len() {
+                if let Some(t) = crossing(a, b, outline[m], outline[(m + 1) % outline.len()]) {
+                    cuts.push(t);
+                }
+            }
+        }
+        cuts.sort_by(f64::total_cmp);
+        for span in cuts.windows(2) {
+            if span[1] - span[0] <= EPSILON {
+                continue;
+            }
+            let t = (span[0] + span[1]) / 2.0;
+            let inside = inside_tissue((a.0 + t * (b.0 - a.0), a.1 + t * (b.1 - a.1)), outlines);
+            states.push(inside);
+            if inside {
+                region.perimeter += (span[1] - span[0]) * distance(a, b);
+                match edge {
+                    Edge::Boundary => region.touches_boundary = true,
+                    Edge::Neighbour(j) => neighbours.push(j),
+                }
+            }
+        }
+    }
+    region.vertices += (0..states.len()).filter(|&k| states[k] != states[(k + 1) % states.len()]).count();
+
+    neighbours.sort();
+    neighbours.dedup();
+    region.neighbours = neighbours;
+    region
+}
+
+// Sutherland-Hodgman step: keeps the part of `polygon` closer to `site` than to `other`. Edges
+// created along the bisector are labelled with `other`.
+fn clip_to_bisector(polygon: &[(Point2, Edge)], site: Point2, other: Point2, other_index: usize) -> Vec<(Point2, Edge)> {
+    let mid = ((site.0 + other.0) / 2.0, (site.1 + other.1) / 2.0);
+    let normal = (other.0 - site.0, other.1 - site.1);
+    // Positive on `other`'s side
+    let side = |p: Point2| (p.0 - mid.0) * normal.0 + (p.1 - mid.1) * normal.1;
+
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+    for k in 0..polygon.len() {
+        let (a, edge) = polygon[k];
+        let b = polygon[(k + 1) % polygon.len()].0;
+        let (sa, sb) = (side(a), side(b));
+        if sa <= 0.0 {
+            clipped.push((a, edge));
+            if sb > 0.0 {
+                // Leaving: the rest of this edge is cut away, and the bisector takes over
+                let t = sa / (sa - sb);
+                clipped.push(((a.0 + t * (b.0 - a.0), a.1 + t * (b.1 - a.1)), Edge::Neighbour(other_index)));
+            }
+        } else if sb <= 0.0 {
+            // Entering: the original edge resumes at the crossing
+            let t = sa / (sa - sb);
+            clipped.push(((a.0 + t * (b.0 - a.0), a.1 + t * (b.1 - a.1)), edge));
+        }
+    }
+    clipped
+}
+
+fn shoelace(polygon: &[(Point2, Edge)]) -> f64 {
+    signed_area(polygon).abs()
+}
+
+fn signed_area(polygon: &[(Point2, Edge)]) -> f64 {
+    let n = polygon.len();
+    let mut area = 0.0;
+    for k in 0..n {
+        let (a, b) = (polygon[k].0, polygon[(k + 1) % n].0);
+        area += a.0 * b.1 - b.0 * a.1;
+    }
+    0.5 * area
+}
+
+fn distance(a: Point2, b: Point2) -> f64 {
+    ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt()
+}
+
+// Twice the signed area of the triangle a, b, p: positive if p is to the left of a -> b
+fn cross(a: Point2, b: Point2, p: Point2) -> f64 {
+    (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0)
+}
+
+// Removes zero-length edges and vertices in the middle of a straight line, which clipping leaves
+// where a bisector passes through a vertex. A merged edge keeps the label of its first part.
+fn drop_degenerate(polygon: &[(Point2, Edge)]) -> Vec<(Point2, Edge)> {
+    let mut polygon = polygon.to_vec();
+    let mut k = 0;
+    while polygon.len() >= 3 && k < polygon.len() {
+        let n = polygon.len();
+        let (previous, point, next) = (polygon[(k + n - 1) % n].0, polygon[k].0, polygon[(k + 1) % n].0);
+        let collinear = cross(previous, point, next).abs() <= EPSILON * distance(previous, point).max(1.0) * distance(point, next).max(1.0);
+        if distance(point, next) <= EPSILON || collinear {
+            polygon.remove(k);
+            k = k.saturating_sub(1);
+        } else {
+            k += 1;
+        }
+    }
+    if polygon.len() < 3 { Vec::new() } else { polygon }
+}
+
+// Douglas-Peucker simplification of a closed polygon: keeps the fewest vertices such that every
+// dropped one lies within `tolerance` of the result.
+fn simplify(polygon: &[Point2], tolerance: f64) -> Vec<Point2> {
+    if polygon.len() < 4 {
+        return polygon.to_vec();
+    }
+    // Split the ring at its first vertex and the vertex farthest from it
+    let far = (1..polygon.len()).max_by(|&a, &b| distance(polygon[0], polygon[a]).total_cmp(&distance(polygon[0], polygon[b]))).unwrap_or(0);
+    let mut keep = vec![false; polygon.len()];
+    keep[0] = true;
+    keep[far] = true;
+    let ring: Vec<Point2> = polygon.iter().chain(std::iter::once(&polygon[0])).copied().collect();
+    simplify_chain(&ring, 0, far, tolerance, &mut keep);
+    let mut closing = vec![false; ring.len()];
+    simplify_chain(&ring, far, polygon.len(), tolerance, &mut closing);
+    (0..polygon.len()).filter(|&k| keep[k] || closing[k]).map(|k| polygon[k]).collect()
+}
+
+fn simplify_chain(points: &[Point2], first: usize, last: usize, tolerance: f64, keep: &mut [bool]) {
+    let (a, b) = (points[first], points[last]);
+    let length = distance(a, b);
+    let offset = |p: Point2| if length > EPSILON { cross(a, b, p).abs() / length } else { distance(a, p) };
+    let Some(farthest) = (first + 1..last).max_by(|&x, &y| offset(points[x]).total_cmp(&offset(points[y]))) else { return };
+    if offset(points[farthest]) > tolerance {
+        keep[farthest] = true;
+        simplify_chain(points, first, farthest, tolerance, keep);
+        simplify_chain(points, farthest, last, tolerance, keep);
+    }
+}
+
+// The part of segment a -> b inside a convex polygon, as a parameter range of positive length
+fn clip_segment_to_convex(a: Point2, b: Point2, polygon: &[(Point2, Edge)], orientation: f64) -> Option<(f64, f64)> {
+    let (mut t0, mut t1) = (0.0_f64, 1.0_f64);
+    for k in 0..polygon.len() {
+        let (p, q) = (polygon[k].0, polygon[(k + 1) % polygon.len()].0);
+        // Inside is where orientation * cross(p, q, x) > 0, linear in the segment parameter
+        let (start, end) = (orientation * cross(p, q, a), orientation * cross(p, q, b));
+        if start <= 0.0 && end <= 0.0 {
+            return None;
+        }
+        if start < 0.0 {
+            t0 = t0.max(start / (start - end));
+        } else if end < 0.0 {
+            t1 = t1.min(start / (start - end));
+        }
+    }
+    ((t1 - t0) * distance(a, b) > EPSILON).then_some((t0, t1))
+}
+
+// Parameter along a -> b where it properly crosses segment p -> q
+fn crossing(a: Point2, b: Point2, p: Point2, q: Point2) -> Option<f64> {
+    let denominator = (b.0 - a.0) * (q.1 - p.1) - (b.1 - a.1) * (q.0 - p.0);
+    if denominator.abs() <= EPSILON {
+        return None;
+    }
+    let t = ((p.0 - a.0) * (q.1 - p.1) - (p.1 - a.1) * (q.0 - p.0)) / denominator;
+    let u = ((p.0 - a.0) * (b.1 - a.1) - (p.1 - a.1) * (b.0 - a.0)) / denominator;
+    ((0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u)).then_some(t)
+}
+
+// Even-odd test against all tissue outlines
+fn inside_tissue(p: Point2, outlines: &[Vec<Point2>]) -> bool {
+    let mut inside = false;
+    for outline in outlines {
+        for k in 0..outline.len() {
+            let (a, b) = (outline[k], outline[(k + 1) % outline.len()]);
+            if (a.1 > p.1) != (b.1 > p.1) && p.0 < a.0 + (p.1 - a.1) / (b.1 - a.1) * (b.0 - a.0) {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A U-shaped piece of tissue traced pixel by pixel, open towards +y
+    fn u_outline() -> Outline {
+        let corners = [(0, 0), (30, 0), (30, 30), (20, 30), (20, 10), (10, 10), (10, 30), (0, 30)];
+        let mut outline = Vec::new();
+        for k in 0..corners.len() {
+            let ((x0, y0), (x1, y1)): ((i32, i32), (i32, i32)) = (corners[k], corners[(k + 1) % corners.len()]);
+            let steps = (x1 - x0).abs().max((y1 - y0).abs());
+            for s in 0..steps {
+                outline.push((x0 + s * (x1 - x0).signum(), y0 + s * (y1 - y0).signum()));
+            }
+        }
+        outline
+    }
+
+    #[test]
+    fn concave_tissue_is_cut_without_bridges() {
+        let sites = [(15.0, 5.0), (15.0, 25.0)];
+        let regions = voronoi_regions(&sites, 31, 31, Some(&[u_outline()]));
+
+        // Above the bisector y = 15: the base of the U and the stubs of both arms
+        assert!((regions[0].area - 400.0).abs() < 1e-6);
+        assert!((regions[0].perimeter - 100.0).abs() < 1e-6);
+        assert_eq!(regions[0].vertices, 8);
+        // Below it: the two separate arm ends, with nothing counted along the gap between them
+        assert!((regions[1].area - 300.0).abs() < 1e-6);
+        assert!((regions[1].perimeter - 100.0).abs() < 1e-6);
+        assert_eq!(regions[1].vertices, 8);
+        assert_eq!(regions[0].neighbours, vec![1]);
+        assert_eq!(regions[1].neighbours, vec![0]);
+        assert!(regions.iter().all(|r| r.touches_boundary));
+    }
+
+    #[test]
+    fn regions_tile_the_image_without_tissue() {
+        let sites = [(3.5, 4.0), (20.0, 2.0), (35.0, 27.0), (12.0, 18.0), (28.0, 11.0), (6.0, 26.0), (37.0, 3.0)];
+        let regions = voronoi_regions(&sites, 40, 30, None);
+        let total: f64 = regions.iter().map(|r| r.area).sum();
+        assert!((total - 40.0 * 30.0).abs() < 1e-6);
+    }
+}